@@ -3,14 +3,18 @@ extern crate glutin;
 extern crate vel0city;
 extern crate nalgebra as na;
 extern crate clock_ticks;
-extern crate image;
 
 use std::borrow::ToOwned;
+use std::sync::mpsc::Receiver;
 use glium::DisplayBuild;
 use glium::Surface;
 
-use vel0city::assets;
 use vel0city::graphics::hud;
+use vel0city::level::MapCycle;
+use vel0city::map::{q3_import, shader, technique};
+use vel0city::map::lightmaps::LightingSettings;
+use vel0city::map::textures::{ImportReport, TextureCache};
+use vel0city::watch::Watcher;
 use na::{
     Diag,
     Rotation,
@@ -26,15 +30,19 @@ pub struct Client {
     hudmanager: vel0city::graphics::hud::HudManager,
     hudelements: Vec<hud::Element>,
     scene: Option<vel0city::graphics::Scene>,
+    textures: TextureCache,
 }
 impl Client {
     fn new(display: &glium::Display) -> Client {
         let input = vel0city::input::Input::new();
         let hudmanager = hud::HudManager::new(display);
 
-        let tex = assets::load_bin_asset("textures/arrow.png").unwrap();
-        let tex = image::load(std::io::Cursor::new(tex), image::PNG).unwrap();
-        let tex = glium::Texture2d::new(display, tex);
+        let mut textures = TextureCache::new();
+        let mut report = ImportReport::new();
+        let tex = vel0city::map::textures::load_texture(&mut textures, display, "textures/arrow.png", &mut report);
+        for warning in report.warnings.iter() {
+            println!("Warning: {}", warning);
+        }
 
         fn id(context: &hud::Context) -> Option<na::Mat4<f32>> {
             let ang = std::f32::consts::PI - (context.player_vel.x.atan2(context.player_vel.z) - context.eyeang.y);
@@ -59,10 +67,61 @@ impl Client {
                 }
             }],
             scene: None,
+            textures: textures,
         }
     }
 }
 
+const MAP_NAME: &'static str = "maps/test.bsp";
+const CEL_SHADERS: (&'static str, &'static str) = ("shaders/post/vertex.glsl", "shaders/post/cel_fragment.glsl");
+const LIGHT_SHADERS: (&'static str, &'static str) = ("shaders/light/vertex.glsl", "shaders/light/dlight_fragment.glsl");
+/// How often to check whether any assets changed, in seconds.
+const RELOAD_INTERVAL: f64 = 0.5;
+
+/// A fresh game on a map, its uploaded graphics, and the assets it was made from.
+struct Level {
+    game: vel0city::Game,
+    graphics: vel0city::map::GraphicsMap,
+    dependencies: Vec<String>,
+}
+
+fn load_level(display: &glium::Display, name: &str, textures: &mut TextureCache) -> Result<Level, String> {
+    let level = try!(vel0city::level::load(name));
+    let (graphics, report) = try!(q3_import::upload_graphics(level.graphics, display, &level.materials, textures, &LightingSettings::from_env())
+                                  .map_err(|e| format!("{}: {}", name, e)));
+    for warning in report.warnings.iter() {
+        println!("Warning: {}", warning);
+    }
+
+    let mut dependencies = report.dependencies;
+    dependencies.push(name.to_string());
+    dependencies.push("scripts/shaderlist.txt".to_string());
+    dependencies.extend(shader::script_paths().into_iter());
+    Ok(Level {
+        game: level.game,
+        graphics: graphics,
+        dependencies: dependencies,
+    })
+}
+
+/// Empties `changes`.
+fn drain_changes(changes: &Receiver<String>) -> Vec<String> {
+    let mut changed = vec![];
+    while let Ok(name) = changes.try_recv() {
+        println!("{} changed", name);
+        changed.push(name);
+    }
+    changed
+}
+
+/// Replaces `program` if the shaders compile, and keeps the old one if they don't.
+fn reload_program(display: &glium::Display, program: &mut glium::Program, shaders: (&str, &str)) {
+    match vel0city::graphics::load_program(display, shaders.0, shaders.1) {
+        Ok(new_program) => *program = new_program,
+        Err(e) => println!("{}", e),
+    }
+}
+
 #[cfg(not(test))]
 fn main() {
     let display = glutin::WindowBuilder::new()
@@ -76,31 +135,24 @@ fn main() {
 
     let proj = na::Persp3::new(x as f32 / y as f32, 90.0, 1.5, 4096.0).to_mat();
 
-    let asset = assets::load_bin_asset("maps/test.bsp").unwrap();
-    let mut game = vel0city::Game {
-        movesettings: std::default::Default::default(),
-        players: vec![vel0city::player::Player {
-            pos: na::Pnt3::new(0.0, 0.0, 0.),
-            eyeheight: 0.0,
-            eyeang: na::zero(), 
-            viewpunch: na::zero(), 
-            viewpunch_vel: na::zero(), 
-            halfextents: vel0city::player::PLAYER_HALFEXTENTS,
-            vel: na::zero(),
-            flags: vel0city::player::PlayerFlags::empty(),
-            landtime: 0.0,
-            holdjumptime: 0.0,
-        }],
-        map: vel0city::map::q3_import::import(&asset).unwrap(),
-        timescale: 1.0,
-        time: 0.0,
-    };
+    let args: Vec<String> = std::env::args().collect();
+    let mut cycle = MapCycle::starting_at(vel0city::level::list_maps(), args.get(1).map(|s| &s[..]).unwrap_or(MAP_NAME));
+    let mut map_name = cycle.current().unwrap().to_string();
 
-    let mapmodel = vel0city::map::q3_import::import_graphics_model(&asset, &display).unwrap();
-    let ents = vel0city::map::q3_import::import_entities(&asset).unwrap();
-    println!("{}", ents);
+    let level = load_level(&display, &map_name, &mut client.textures).unwrap();
+    println!("{} textures, {} KiB", client.textures.len(), client.textures.memory() / 1024);
+    let mut watcher = Watcher::new();
+    let mut level_changes = watcher.subscribe(level.dependencies);
+    let map_program_changes = watcher.subscribe(q3_import::MAP_PROGRAMS.iter()
+        .flat_map(|&(vertex, fragment)| vec![vertex.to_string(), fragment.to_string()].into_iter())
+        .collect());
+    let post_changes = watcher.subscribe(vec![CEL_SHADERS.0.to_string(), CEL_SHADERS.1.to_string(),
+                                              LIGHT_SHADERS.0.to_string(), LIGHT_SHADERS.1.to_string()]);
+
+    let mut game = level.game;
+    client.input.ang = game.players[0].eyeang;
     client.scene = Some(vel0city::graphics::Scene {
-        map: mapmodel,
+        map: level.graphics,
         lights: vec![ vel0city::graphics::Light { position: na::zero(), intensity: 0.0, radius: 0.5, color: na::Vec3::new(0.0, 1.0, 1.0) }] 
     });
     
@@ -113,25 +165,15 @@ fn main() {
     //client.input.cursorpos = (winsize.0 as i32 / 2, winsize.1 as i32 / 2);
 
     let psystem = vel0city::graphics::passes::PassSystem::new(&display);
-    let cel_program = glium::Program::from_source(
-        &display,
-        &assets::load_str_asset("shaders/post/vertex.glsl").unwrap(),
-        &assets::load_str_asset("shaders/post/cel_fragment.glsl").unwrap(),
-        None
-        ).unwrap();
-    let cel_technique = vel0city::graphics::passes::Technique {
+    let cel_program = vel0city::graphics::load_program(&display, CEL_SHADERS.0, CEL_SHADERS.1).unwrap();
+    let mut cel_technique = vel0city::graphics::passes::Technique {
         shader: cel_program,
         drawparams: glium::DrawParameters {
             ..::std::default::Default::default()
         }
     };
-    let light_program = glium::Program::from_source(
-        &display,
-        &assets::load_str_asset("shaders/light/vertex.glsl").unwrap(),
-        &assets::load_str_asset("shaders/light/dlight_fragment.glsl").unwrap(),
-        None
-        ).unwrap();
-    let light_technique = vel0city::graphics::passes::Technique {
+    let light_program = vel0city::graphics::load_program(&display, LIGHT_SHADERS.0, LIGHT_SHADERS.1).unwrap();
+    let mut light_technique = vel0city::graphics::passes::Technique {
         shader: light_program,
         drawparams: glium::DrawParameters {
             blending_function: Some(glium::BlendingFunction::Addition {
@@ -148,6 +190,7 @@ fn main() {
     let mut lasttime = clock_ticks::precise_time_s();
     let mut accumtime = 0.0;
     let mut smoothtime = 0.0;
+    let mut lastpoll = lasttime;
     while !display.is_closed() {
         let curtime = clock_ticks::precise_time_s();
         let frametime = curtime - lasttime;
@@ -172,6 +215,42 @@ fn main() {
             client.input.handle_event(&win, &ev);
         }
 
+        if curtime - lastpoll >= RELOAD_INTERVAL {
+            lastpoll = curtime;
+            watcher.poll();
+            if !drain_changes(&post_changes).is_empty() {
+                reload_program(&display, &mut cel_technique.shader, CEL_SHADERS);
+                reload_program(&display, &mut light_technique.shader, LIGHT_SHADERS);
+            }
+            if !drain_changes(&map_program_changes).is_empty() {
+                match (q3_import::load_map_programs(&display), client.scene.as_mut()) {
+                    (Ok(programs), Some(scene)) => scene.map.shaders = programs,
+                    (Err(e), _) => println!("{}", e),
+                    _ => (),
+                }
+            }
+            // The player stays where they are, even if that's inside a wall now.
+            let changed = drain_changes(&level_changes);
+            if !changed.is_empty() {
+                for name in changed.iter() {
+                    client.textures.invalidate(technique::strip_extension(name));
+                }
+                match load_level(&display, &map_name, &mut client.textures) {
+                    Ok(level) => {
+                        level_changes = watcher.subscribe(level.dependencies);
+                        game.map = level.game.map;
+                        game.spawn = level.game.spawn;
+                        if let Some(ref mut scene) = client.scene {
+                            scene.map = level.graphics;
+                        }
+                        let freed = client.textures.free_unused();
+                        println!("Reloaded {}, freeing {} KiB of textures", map_name, freed / 1024);
+                    },
+                    Err(e) => println!("{}", e),
+                }
+            }
+        }
+
 
         if accumtime >= tick {
             while accumtime >= tick {
@@ -185,6 +264,32 @@ fn main() {
                 client.input.ang = game.players[0].eyeang;
             }
         }
+
+        if game.goal_reached() {
+            let next = cycle.advance().map(|name| name.to_string());
+            let loaded = match next {
+                Some(name) => load_level(&display, &name, &mut client.textures).map(|level| (name, level)),
+                None => Err("No maps to go to".to_string()),
+            };
+            match loaded {
+                Ok((name, level)) => {
+                    // Dropping the old receiver unsubscribes from the old map's assets.
+                    level_changes = watcher.subscribe(level.dependencies);
+                    game = level.game;
+                    client.input.ang = game.players[0].eyeang;
+                    if let Some(ref mut scene) = client.scene {
+                        scene.map = level.graphics;
+                    }
+                    let freed = client.textures.free_unused();
+                    println!("Finished {}, on to {}, freeing {} KiB of textures", map_name, name, freed / 1024);
+                    map_name = name;
+                },
+                Err(e) => {
+                    println!("{}", e);
+                    game.players[0].flags.insert(vel0city::player::PLAYER_MUST_DIE);
+                },
+            }
+        }
         let pv = game.players[0].vel;
 
         let ang = game.players[0].eyeang + game.players[0].viewpunch;
@@ -194,11 +299,13 @@ fn main() {
             );
 
         let l = na::Iso3::new_with_rotmat(na::zero(), rot.to_rot()).inv().unwrap().to_homogeneous();
-        let v = na::Iso3::new((game.players[0].pos.to_vec() + na::Vec3 { y: vel0city::player::PLAYER_HALFEXTENTS.y * -0.6, ..na::zero() }) * -1.0, na::zero()).to_homogeneous();
+        let eye = game.players[0].pos.to_vec() + na::Vec3 { y: vel0city::player::PLAYER_HALFEXTENTS.y * -0.6, ..na::zero() };
+        let v = na::Iso3::new(eye * -1.0, na::zero()).to_homogeneous();
         //l.inv();
         let view = vel0city::graphics::View {
             cam: l * v,
             w2s: proj * l * v,
+            time: game.time,
         };
 
         let mut target = display.draw();
@@ -208,9 +315,12 @@ fn main() {
             scene.lights[0].intensity = na::clamp(na::norm(&na::Vec2::new(pv.x, pv.z)) / 5.0, 2.0, 50.0);
 
 
-            vel0city::graphics::draw_scene(&mut pass_data.get_framebuffer_for_prepass(&display),
+            let visible_faces = vel0city::map::vis::visible_faces(&game.map.bsp, scene.map.faces.len(), &eye.to_pnt(), &view.w2s);
+            vel0city::graphics::draw_scene(&display,
+                                           &mut pass_data.get_framebuffer_for_prepass(&display),
                                            &scene,
-                                           &view);
+                                           &view,
+                                           &visible_faces);
             psystem.light_passes(&display, &mut pass_data, &scene.lights, &view, &light_technique);
 
             psystem.postprocess(&pass_data, &mut target, &cel_technique);
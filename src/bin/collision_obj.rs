@@ -0,0 +1,37 @@
+//! Writes the collision hull of a map out as a Wavefront OBJ.
+//!
+//! Usage: collision_obj [map asset] [output.obj]
+
+extern crate vel0city;
+
+use std::fs::File;
+use std::io::BufWriter;
+use std::path::Path;
+
+use vel0city::assets;
+use vel0city::map::obj_export;
+
+fn main() {
+    let args: Vec<String> = std::env::args().collect();
+    let mapname = args.get(1).map(|s| &s[..]).unwrap_or("maps/test.bsp");
+    let objpath = Path::new(args.get(2).map(|s| &s[..]).unwrap_or("collision.obj"));
+    let mtlpath = objpath.with_extension("mtl");
+
+    let asset = assets::load_bin_asset(mapname).unwrap();
+    let map = match vel0city::map::q3_import::import(&asset) {
+        Ok(map) => map,
+        Err(e) => {
+            println!("Couldn't load {}: {}", mapname, e);
+            return;
+        }
+    };
+
+    let mtlname = mtlpath.file_name().unwrap().to_string_lossy().into_owned();
+    let mut obj = BufWriter::new(File::create(objpath).unwrap());
+    obj_export::write_obj(&map, Some(&mtlname), &mut obj).unwrap();
+
+    let mut mtl = BufWriter::new(File::create(&mtlpath).unwrap());
+    obj_export::write_mtl(&map, &mut mtl).unwrap();
+
+    println!("Wrote {} and {}", objpath.display(), mtlpath.display());
+}
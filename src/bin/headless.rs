@@ -0,0 +1,68 @@
+//! Runs maps without a window: loads a map into a game, lets the player fall and
+//! slide around for a while, and moves on through the map cycle whenever the goal
+//! is reached. Handy for checking that maps load and spawns are sane.
+//!
+//! Usage: headless --list
+//!        headless [map asset] [seconds]
+
+extern crate vel0city;
+extern crate nalgebra as na;
+
+use vel0city::level::{self, MapCycle};
+use vel0city::player::movement::{self, MoveInput};
+
+const TICK: f32 = 1.0 / 200.0;
+
+fn main() {
+    let args: Vec<String> = std::env::args().collect();
+    if args.get(1).map(|s| &s[..]) == Some("--list") {
+        for name in level::list_maps() {
+            println!("{}", name);
+        }
+        return;
+    }
+    let first = args.get(1).map(|s| &s[..]).unwrap_or("maps/test.bsp");
+    let seconds: f32 = args.get(2).and_then(|s| s.parse().ok()).unwrap_or(10.0);
+
+    let mut cycle = MapCycle::starting_at(level::list_maps(), first);
+    let mut loaded = match level::load(first) {
+        Ok(loaded) => loaded,
+        Err(e) => {
+            println!("{}", e);
+            return;
+        }
+    };
+    println!("{}: {} spawns, starting at {:?}", loaded.name, loaded.spawns.len(), loaded.game.spawn.pos);
+
+    let mut ticks = (seconds / TICK) as u32;
+    while ticks > 0 {
+        ticks -= 1;
+        let input = MoveInput {
+            wishvel: na::zero(),
+            eyeang: loaded.game.players[0].eyeang,
+            jump: false,
+            reset: false,
+        };
+        loaded.game.time += TICK;
+        movement::move_player(&mut loaded.game, 0, &input, TICK);
+
+        if loaded.game.goal_reached() {
+            println!("{}: reached the goal after {}s", loaded.name, loaded.game.time);
+            let next = match cycle.advance() {
+                Some(next) => next.to_string(),
+                None => return,
+            };
+            loaded = match level::load(&next) {
+                Ok(loaded) => loaded,
+                Err(e) => {
+                    println!("{}", e);
+                    return;
+                }
+            };
+            println!("{}: {} spawns, starting at {:?}", loaded.name, loaded.spawns.len(), loaded.game.spawn.pos);
+        }
+    }
+
+    let pl = &loaded.game.players[0];
+    println!("{}: after {}s the player is at {:?} moving at {:?}", loaded.name, loaded.game.time, pl.pos, pl.vel);
+}
@@ -0,0 +1,61 @@
+//! Times loading a map's collision and graphics data, either by importing the BSP
+//! or from the map cache, and reports the process's peak memory use. Run it once
+//! per method, since the peak only goes up. The first `cache` run has to import
+//! the map to write the cache, so it's the second one that counts.
+//!
+//! Usage: map_load [map asset] [import|cache]
+
+extern crate vel0city;
+extern crate clock_ticks;
+
+use std::fs::File;
+use std::io::Read;
+
+use vel0city::assets;
+use vel0city::map::{lightmaps, map_cache, q3_import, shader};
+
+/// The peak resident set size, from Linux's `/proc`.
+fn peak_memory() -> Option<String> {
+    let mut status = String::new();
+    if File::open("/proc/self/status").and_then(|mut f| f.read_to_string(&mut status)).is_err() {
+        return None;
+    }
+    status.lines()
+        .find(|line| line.starts_with("VmHWM:"))
+        .map(|line| line["VmHWM:".len()..].trim().to_string())
+}
+
+fn main() {
+    let args: Vec<String> = std::env::args().collect();
+    let mapname = args.get(1).map(|s| &s[..]).unwrap_or("maps/test.bsp");
+    let method = args.get(2).map(|s| &s[..]).unwrap_or("cache");
+
+    let asset = assets::load_bin_asset(mapname).unwrap();
+    let materials = shader::load_materials();
+
+    let start = clock_ticks::precise_time_s();
+    let loaded = match method {
+        "import" => q3_import::import_with_materials(&asset, &materials)
+            .and_then(|map| q3_import::import_graphics_data(&asset).map(|graphics| (map, graphics)))
+            .map(|(map, mut graphics)| {
+                // The cache holds packed atlases, so pack here too to time the same work.
+                lightmaps::pack_atlases(&mut graphics, lightmaps::DEFAULT_ATLAS_SIZE);
+                (map, graphics)
+            }),
+        "cache" => map_cache::load(mapname, &asset, &materials),
+        _ => {
+            println!("Unknown method {}, expected import or cache", method);
+            return;
+        }
+    };
+    let elapsed = clock_ticks::precise_time_s() - start;
+
+    match loaded {
+        Ok((map, graphics)) => {
+            println!("Loaded {} with {} in {:.1}ms: {} brushes, {} vertices, {} lightmaps",
+                     mapname, method, elapsed * 1000.0, map.bsp.brushes.len(), graphics.vertices.len(), graphics.lightmaps.len());
+            println!("Peak memory: {}", peak_memory().unwrap_or("unknown".to_string()));
+        },
+        Err(e) => println!("Couldn't load {}: {}", mapname, e),
+    }
+}
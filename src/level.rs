@@ -0,0 +1,159 @@
+//! Finding maps, loading them into fresh games and going from one to the next.
+//! Nothing here needs a display, so it works headless too.
+
+use std::default::Default;
+use na;
+use map::{self, map_cache, q3_import, GraphicsMapData};
+use map::entities::{self, Spawn};
+use map::shader::{self, Material};
+use vel0city_base::{assets, vfs};
+use player;
+use Game;
+
+/// Where map assets are.
+pub const MAP_DIR: &'static str = "maps";
+
+/// Every map in the asset mounts, sorted.
+pub fn list_maps() -> Vec<String> {
+    vfs::list(MAP_DIR, "bsp")
+}
+
+/// A map loaded into a fresh game, along with what it takes to draw it.
+pub struct Level {
+    pub name: String,
+    pub game: Game,
+    /// All the places players can start. The game starts at the first.
+    pub spawns: Vec<Spawn>,
+    /// Ready for `q3_import::upload_graphics`.
+    pub graphics: GraphicsMapData,
+    pub materials: Vec<Material>,
+}
+
+/// Loads the map asset `name`: its collision, graphics, entities and spawns.
+pub fn load(name: &str) -> Result<Level, String> {
+    let asset = try!(assets::load_bin_asset(name).map_err(|e| format!("Couldn't load {}: {}", name, e)));
+    let materials = shader::load_materials();
+    let (map, graphics) = try!(map_cache::load(name, &asset, &materials).map_err(|e| format!("{}: {}", name, e)));
+    let entity_lump = try!(q3_import::import_entities(&asset).map_err(|e| format!("{}: {}", name, e)));
+    let spawns = entities::find_spawns(&entities::parse(&entity_lump));
+    let spawn = spawns.first().cloned().unwrap_or(Spawn::origin());
+
+    Ok(Level {
+        name: name.to_string(),
+        game: new_game(map, spawn),
+        spawns: spawns,
+        graphics: graphics,
+        materials: materials,
+    })
+}
+
+/// A game on `map` with one player standing at `spawn`.
+pub fn new_game(map: map::Map, spawn: Spawn) -> Game {
+    Game {
+        map: map,
+        players: vec![player::Player {
+            pos: spawn.pos,
+            eyeheight: 0.0,
+            eyeang: na::Vec3::new(0.0, spawn.yaw, 0.0),
+            viewpunch: na::zero(),
+            viewpunch_vel: na::zero(),
+            halfextents: player::PLAYER_HALFEXTENTS,
+            vel: na::zero(),
+            flags: player::PlayerFlags::empty(),
+            landtime: 0.0,
+            holdjumptime: 0.0,
+        }],
+        spawn: spawn,
+        movesettings: Default::default(),
+        timescale: 1.0,
+        time: 0.0,
+    }
+}
+
+/// The maps to play, in order, going back to the first after the last.
+pub struct MapCycle {
+    pub maps: Vec<String>,
+    current: usize,
+}
+
+impl MapCycle {
+    pub fn new(maps: Vec<String>) -> MapCycle {
+        MapCycle { maps: maps, current: 0 }
+    }
+
+    /// Starts at `first`, which is added to the cycle if it isn't in it. Asset
+    /// names are case-insensitive, so `first` matches maps with any case.
+    pub fn starting_at(mut maps: Vec<String>, first: &str) -> MapCycle {
+        let first_lower = first.to_lowercase();
+        let current = match maps.iter().position(|map| map.to_lowercase() == first_lower) {
+            Some(idx) => idx,
+            None => {
+                maps.insert(0, first.to_string());
+                0
+            },
+        };
+        MapCycle { maps: maps, current: current }
+    }
+
+    pub fn current(&self) -> Option<&str> {
+        self.maps.get(self.current).map(|map| &map[..])
+    }
+
+    /// Moves on to the next map and returns it.
+    pub fn advance(&mut self) -> Option<&str> {
+        if self.maps.is_empty() {
+            return None;
+        }
+        self.current = (self.current + 1) % self.maps.len();
+        self.current()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use na;
+    use map::entities::Spawn;
+    use map::builder::MapBuilder;
+    use super::{new_game, MapCycle};
+
+    fn maps(names: &[&str]) -> Vec<String> {
+        names.iter().map(|name| name.to_string()).collect()
+    }
+
+    #[test]
+    fn cycle_wraps() {
+        let mut cycle = MapCycle::new(maps(&["maps/a.bsp", "maps/b.bsp"]));
+        assert_eq!(cycle.current(), Some("maps/a.bsp"));
+        assert_eq!(cycle.advance(), Some("maps/b.bsp"));
+        assert_eq!(cycle.advance(), Some("maps/a.bsp"));
+
+        let mut empty = MapCycle::new(vec![]);
+        assert_eq!(empty.current(), None);
+        assert_eq!(empty.advance(), None);
+    }
+
+    #[test]
+    fn cycle_starts_anywhere() {
+        let mut cycle = MapCycle::starting_at(maps(&["maps/a.bsp", "maps/b.bsp"]), "maps/b.bsp");
+        assert_eq!(cycle.current(), Some("maps/b.bsp"));
+        assert_eq!(cycle.advance(), Some("maps/a.bsp"));
+
+        // The cycle keeps its own spelling rather than adding the map again.
+        let cycle = MapCycle::starting_at(maps(&["maps/a.bsp", "maps/Test.bsp"]), "MAPS/test.BSP");
+        assert_eq!(cycle.maps, maps(&["maps/a.bsp", "maps/Test.bsp"]));
+        assert_eq!(cycle.current(), Some("maps/Test.bsp"));
+
+        let cycle = MapCycle::starting_at(maps(&["maps/a.bsp"]), "maps/test.bsp");
+        assert_eq!(cycle.maps, maps(&["maps/test.bsp", "maps/a.bsp"]));
+        assert_eq!(cycle.current(), Some("maps/test.bsp"));
+    }
+
+    #[test]
+    fn games_start_at_the_spawn() {
+        let spawn = Spawn { pos: na::Pnt3::new(1.0, 2.0, 3.0), yaw: 0.5 };
+        let game = new_game(MapBuilder::new().build(), spawn);
+        assert_eq!(game.players[0].pos, spawn.pos);
+        assert_eq!(game.players[0].eyeang.y, 0.5);
+        assert!(!game.goal_reached());
+    }
+}
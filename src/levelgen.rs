@@ -0,0 +1,417 @@
+//! Endless rooftop levels in the spirit of Canabalt.
+//!
+//! The level is cut into chunks along the run, which goes towards -Z. Chunks are
+//! generated ahead of the player and thrown away behind them, and the whole thing is
+//! compiled into an ordinary `Map` with `MapBuilder`, so collision and entities work
+//! exactly like they do on imported maps.
+//!
+//! Each chunk only depends on the seed, its index and the speed the player had when
+//! it was generated, so replaying a run on the same seed rebuilds the same level.
+
+use std::collections::VecDeque;
+use na;
+use map::{EntityKind, Map};
+use map::bsp::{Brush, Plane};
+use map::builder::{
+    MapBuilder,
+    box_brush,
+    brush_from_planes,
+    CONTENTS_SOLID
+};
+use player::PLAYER_HALFEXTENTS;
+use settings::MoveSettings;
+use vel0city_base::rng::Rng;
+
+/// Everything below a rooftop is solid down to here.
+const BUILDING_BOTTOM: f32 = 64.0;
+/// Top of the out-of-bounds volume that catches players who miss a jump.
+const KILL_TOP: f32 = -16.0;
+/// How far to the sides the out-of-bounds volume reaches.
+const KILL_HALFWIDTH: f32 = 4096.0;
+
+const MIN_HEIGHT: f32 = 64.0;
+const MAX_HEIGHT: f32 = 512.0;
+const MIN_GAP: f32 = 64.0;
+
+/// How high a player can get above where they jumped from.
+pub fn jump_height(movesettings: &MoveSettings) -> f32 {
+    movesettings.jumpspeed * movesettings.jumpspeed / (2.0 * movesettings.gravity)
+}
+
+/// How long a jump takes to come back down to the height it started at.
+pub fn air_time(movesettings: &MoveSettings) -> f32 {
+    2.0 * movesettings.jumpspeed / movesettings.gravity
+}
+
+/// How far a player moving at `speed` gets with a jump onto a rooftop of the same height.
+pub fn jump_reach(movesettings: &MoveSettings, speed: f32) -> f32 {
+    speed * air_time(movesettings)
+}
+
+/// How hard to make things for a player moving at `speed`, from 0 at a normal run
+/// to 1 at the speed cap. Always 0 if there's no room between the two.
+pub fn difficulty(movesettings: &MoveSettings, speed: f32) -> f32 {
+    let speed_range = movesettings.maxspeed - movesettings.movespeed;
+    if speed_range > 0.0 {
+        (speed - movesettings.movespeed) / speed_range
+    } else {
+        0.0
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum PieceKind {
+    Platform,
+    Gap,
+    /// A slope from the height of the previous piece up to `height`.
+    Ramp,
+    /// A low wall across a platform that has to be jumped over.
+    Wall,
+}
+
+/// One stretch of a chunk. `start` and `end` are distances along the run.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Piece {
+    pub kind: PieceKind,
+    pub start: f32,
+    pub end: f32,
+    /// Height of the rooftop, upwards from the street. For gaps, the height of the
+    /// rooftop the player has to land on.
+    pub height: f32,
+}
+
+pub struct Chunk {
+    pub index: u32,
+    pub start: f32,
+    pub end: f32,
+    /// Height of the rooftop the chunk starts from.
+    pub start_height: f32,
+    /// The speed the difficulty was based on.
+    pub speed: f32,
+    pub pieces: Vec<Piece>,
+    pub brushes: Vec<Brush>,
+    pub kill_brush: Brush,
+}
+
+pub struct RunnerSettings {
+    /// Roughly how long a chunk is. Chunks end on a rooftop, so they can run a bit over.
+    pub chunk_length: f32,
+    /// How much of the level to keep ahead of the player.
+    pub keep_ahead: f32,
+    /// How much of the level to keep behind the player.
+    pub keep_behind: f32,
+    /// Half the width of the rooftops.
+    pub halfwidth: f32,
+}
+impl ::std::default::Default for RunnerSettings {
+    fn default() -> RunnerSettings {
+        RunnerSettings {
+            chunk_length: 2048.0,
+            keep_ahead: 4096.0,
+            keep_behind: 1024.0,
+            halfwidth: 256.0,
+        }
+    }
+}
+
+pub struct Generator {
+    seed: u64,
+    settings: RunnerSettings,
+    movesettings: MoveSettings,
+    chunks: VecDeque<Chunk>,
+    next_index: u32,
+    next_start: f32,
+    next_height: f32,
+}
+
+impl Generator {
+    pub fn new(seed: u64, settings: RunnerSettings, movesettings: MoveSettings) -> Generator {
+        let mut generator = Generator {
+            seed: seed,
+            settings: settings,
+            movesettings: movesettings,
+            chunks: VecDeque::new(),
+            next_index: 0,
+            next_start: 0.0,
+            next_height: 128.0,
+        };
+        let speed = generator.movesettings.movespeed;
+        generator.stream(0.0, speed);
+        generator
+    }
+
+    pub fn chunks(&self) -> &VecDeque<Chunk> {
+        &self.chunks
+    }
+
+    /// Where players start a run: a little way onto the first chunk we still have.
+    pub fn spawn_point(&self) -> na::Pnt3<f32> {
+        let (start, height) = self.chunks.front().map_or((self.next_start, self.next_height), |c| (c.start, c.start_height));
+        na::Pnt3::new(0.0, -height - PLAYER_HALFEXTENTS.y - 1.0, -start - 64.0)
+    }
+
+    /// Generates chunks ahead of `pos` and drops the ones far behind it.
+    /// Returns true if anything changed, in which case the map needs to be rebuilt.
+    pub fn update(&mut self, pos: &na::Pnt3<f32>, vel: &na::Vec3<f32>) -> bool {
+        let speed = na::norm(&na::Vec2::new(vel.x, vel.z));
+        self.stream(-pos.z, speed)
+    }
+
+    fn stream(&mut self, distance: f32, speed: f32) -> bool {
+        let mut changed = false;
+        while self.next_start < distance + self.settings.keep_ahead {
+            let chunk = self.generate_chunk(speed);
+            self.chunks.push_back(chunk);
+            changed = true;
+        }
+        while self.chunks.front().map_or(false, |c| c.end < distance - self.settings.keep_behind) {
+            self.chunks.pop_front();
+            changed = true;
+        }
+        changed
+    }
+
+    /// Compiles the chunks we currently have into a map.
+    pub fn build_map(&self) -> Map {
+        let mut builder = MapBuilder::new();
+        for chunk in &self.chunks {
+            for brush in &chunk.brushes {
+                builder.add_brush(brush.clone());
+            }
+        }
+        builder.add_entity(EntityKind::OutOfBounds, self.chunks.iter().map(|c| c.kill_brush.clone()).collect());
+        builder.build()
+    }
+
+    fn generate_chunk(&mut self, speed: f32) -> Chunk {
+        let index = self.next_index;
+        let mut rng = Rng::new(self.seed ^ (index as u64 + 1).wrapping_mul(0x9e3779b97f4a7c15));
+
+        // Never plan for someone slower than a normal run.
+        let speed = speed.max(self.movesettings.movespeed).min(self.movesettings.maxspeed);
+        let difficulty = difficulty(&self.movesettings, speed);
+        let reach = jump_reach(&self.movesettings, speed);
+        let jump_height = jump_height(&self.movesettings);
+
+        let start = self.next_start;
+        let start_height = self.next_height;
+        let target_end = start + self.settings.chunk_length;
+        let mut height = self.next_height;
+        let mut pieces = vec![];
+        let mut d = start;
+
+        if index == 0 {
+            // Give players a long flat run-up to get going.
+            pieces.push(Piece { kind: PieceKind::Platform, start: d, end: target_end, height: height });
+            d = target_end;
+        }
+
+        while d < target_end {
+            let roll = rng.next_f32();
+            if roll < 0.4 {
+                let len = rng.range(256.0, 768.0);
+                pieces.push(Piece { kind: PieceKind::Platform, start: d, end: d + len, height: height });
+                d += len;
+            } else if roll < 0.75 {
+                // Faster players get wider gaps, but never wider than they can clear,
+                // and never onto rooftops higher than they can jump.
+                let newheight = na::clamp(height + rng.range(-128.0, jump_height * 0.5), MIN_HEIGHT, MAX_HEIGHT);
+                let maxgap = reach * (0.35 + 0.45 * difficulty);
+                let gap = (maxgap * rng.range(0.7, 1.0)).max(MIN_GAP);
+                pieces.push(Piece { kind: PieceKind::Gap, start: d, end: d + gap, height: newheight });
+                d += gap;
+                height = newheight;
+
+                // There has to be something to land on.
+                let len = rng.range(384.0, 768.0);
+                pieces.push(Piece { kind: PieceKind::Platform, start: d, end: d + len, height: height });
+                d += len;
+            } else if roll < 0.9 {
+                let len = rng.range(256.0, 512.0);
+                let newheight = (height + len * rng.range(0.1, 0.4)).min(MAX_HEIGHT);
+                pieces.push(Piece { kind: PieceKind::Ramp, start: d, end: d + len, height: newheight });
+                d += len;
+                height = newheight;
+            } else {
+                let len = rng.range(384.0, 640.0);
+                pieces.push(Piece { kind: PieceKind::Wall, start: d, end: d + len, height: height });
+                d += len;
+            }
+        }
+
+        let mut brushes = vec![];
+        let mut prevheight = start_height;
+        for piece in &pieces {
+            match piece.kind {
+                PieceKind::Platform => {
+                    brushes.push(self.building(piece.start, piece.end, piece.height));
+                },
+                PieceKind::Gap => {},
+                PieceKind::Ramp => {
+                    brushes.push(self.ramp(piece.start, piece.end, prevheight, piece.height));
+                },
+                PieceKind::Wall => {
+                    brushes.push(self.building(piece.start, piece.end, piece.height));
+                    let mid = (piece.start + piece.end) * 0.5;
+                    let wallheight = jump_height * 0.6;
+                    brushes.push(box_brush(
+                            na::Pnt3::new(-self.settings.halfwidth, -piece.height - wallheight, -mid - 8.0),
+                            na::Pnt3::new(self.settings.halfwidth, -piece.height, -mid + 8.0),
+                            CONTENTS_SOLID));
+                },
+            }
+            prevheight = piece.height;
+        }
+
+        let kill_brush = box_brush(
+            na::Pnt3::new(-KILL_HALFWIDTH, KILL_TOP, -d),
+            na::Pnt3::new(KILL_HALFWIDTH, BUILDING_BOTTOM, -start),
+            CONTENTS_SOLID);
+
+        self.next_index += 1;
+        self.next_start = d;
+        self.next_height = height;
+
+        Chunk {
+            index: index,
+            start: start,
+            end: d,
+            start_height: start_height,
+            speed: speed,
+            pieces: pieces,
+            brushes: brushes,
+            kill_brush: kill_brush,
+        }
+    }
+
+    /// A building spanning `start` to `end` along the run, with its roof at `height`.
+    fn building(&self, start: f32, end: f32, height: f32) -> Brush {
+        box_brush(
+            na::Pnt3::new(-self.settings.halfwidth, -height, -end),
+            na::Pnt3::new(self.settings.halfwidth, BUILDING_BOTTOM, -start),
+            CONTENTS_SOLID)
+    }
+
+    /// Like `building`, but the roof slopes from `startheight` up to `endheight`.
+    fn ramp(&self, start: f32, end: f32, startheight: f32, endheight: f32) -> Brush {
+        let slope = (endheight - startheight) / (end - start);
+        // The roof is every point with y >= -startheight - slope * (distance - start),
+        // and distance is -z.
+        let roof = na::Vec3::new(0.0, -1.0, slope);
+        let len = na::norm(&roof);
+        let halfwidth = self.settings.halfwidth;
+        brush_from_planes(&[
+            Plane { norm: na::Vec3::new(1.0, 0.0, 0.0), dist: halfwidth },
+            Plane { norm: na::Vec3::new(-1.0, 0.0, 0.0), dist: halfwidth },
+            Plane { norm: na::Vec3::new(0.0, 1.0, 0.0), dist: BUILDING_BOTTOM },
+            Plane { norm: na::Vec3::new(0.0, 0.0, 1.0), dist: -start },
+            Plane { norm: na::Vec3::new(0.0, 0.0, -1.0), dist: end },
+            Plane { norm: roof / len, dist: (startheight - slope * start) / len },
+        ], CONTENTS_SOLID)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use na;
+    use map::cast::{
+        Ray,
+        Shape
+    };
+    use settings::MoveSettings;
+    use super::{
+        Generator,
+        RunnerSettings,
+        PieceKind,
+        difficulty,
+        jump_reach,
+        jump_height
+    };
+
+    fn generator(seed: u64) -> Generator {
+        Generator::new(seed, ::std::default::Default::default(), ::std::default::Default::default())
+    }
+
+    fn planes(generator: &Generator) -> Vec<(na::Vec3<f32>, f32)> {
+        generator.chunks().iter()
+            .flat_map(|c| c.brushes.iter())
+            .flat_map(|b| b.sides.iter())
+            .map(|s| (s.plane.norm, s.plane.dist))
+            .collect()
+    }
+
+    #[test]
+    fn same_seed_same_level() {
+        assert_eq!(planes(&generator(1234)), planes(&generator(1234)));
+        assert!(planes(&generator(1234)) != planes(&generator(4321)));
+    }
+
+    #[test]
+    fn jumps_are_possible() {
+        let movesettings: MoveSettings = ::std::default::Default::default();
+        let mut generator = generator(99);
+        let mut pos = generator.spawn_point();
+        for _ in 0..50 {
+            pos.z -= 1000.0;
+            generator.update(&pos, &na::Vec3::new(0.0, 0.0, -600.0));
+        }
+
+        for chunk in generator.chunks() {
+            let reach = jump_reach(&movesettings, chunk.speed);
+            let mut prevheight = chunk.start_height;
+            for piece in &chunk.pieces {
+                if piece.kind == PieceKind::Gap {
+                    assert!(piece.end - piece.start <= reach);
+                    assert!(piece.height - prevheight <= jump_height(&movesettings));
+                }
+                prevheight = piece.height;
+            }
+        }
+    }
+
+    #[test]
+    fn difficulty_without_speed_range() {
+        let mut movesettings: MoveSettings = ::std::default::Default::default();
+        assert_eq!(difficulty(&movesettings, movesettings.movespeed), 0.0);
+        assert_eq!(difficulty(&movesettings, movesettings.maxspeed), 1.0);
+
+        movesettings.maxspeed = movesettings.movespeed;
+        assert_eq!(difficulty(&movesettings, movesettings.movespeed), 0.0);
+        assert_eq!(difficulty(&movesettings, movesettings.movespeed + 100.0), 0.0);
+    }
+
+    #[test]
+    fn chunks_stream() {
+        let mut generator = generator(7);
+        let settings: RunnerSettings = ::std::default::Default::default();
+        let mut pos = generator.spawn_point();
+        let vel = na::Vec3::new(0.0, 0.0, -400.0);
+
+        for _ in 0..20 {
+            pos.z -= 700.0;
+            generator.update(&pos, &vel);
+
+            let distance = -pos.z;
+            let first = generator.chunks().front().unwrap();
+            let last = generator.chunks().back().unwrap();
+            assert!(first.end >= distance - settings.keep_behind);
+            assert!(last.end >= distance + settings.keep_ahead);
+        }
+        assert!(generator.chunks().front().unwrap().index > 0);
+    }
+
+    #[test]
+    fn spawn_is_above_a_roof() {
+        let generator = generator(5);
+        let map = generator.build_map();
+        let spawn = generator.spawn_point();
+
+        let hit = map.cast_ray(&Ray {
+            orig: spawn,
+            dir: na::Vec3::new(0.0, 64.0, 0.0),
+            shape: Shape::Box(::player::PLAYER_HALFEXTENTS),
+        }).unwrap();
+        assert!(hit.entity.is_none());
+        assert!(hit.norm.y < -0.7);
+    }
+}
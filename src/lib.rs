@@ -17,10 +17,13 @@ extern crate vel0city_base;
 extern crate vel0city_graphics;
 
 pub use vel0city_base::assets as assets;
+pub use vel0city_base::watch as watch;
 pub use vel0city_map as map;
 pub use vel0city_graphics as graphics;
 
 pub mod input;
+pub mod level;
+pub mod levelgen;
 pub mod player;
 pub mod particle;
 pub mod settings;
@@ -28,14 +31,94 @@ pub mod settings;
 pub struct Game {
     pub map: map::Map,
     pub players: Vec<player::Player>,
+    /// Where players go when they die or reset.
+    pub spawn: map::entities::Spawn,
 
     pub movesettings: settings::MoveSettings,
     pub timescale: f32,
     pub time: f32,
 }
 
+impl Game {
+    /// Whether anyone has made it to the end of the map.
+    pub fn goal_reached(&self) -> bool {
+        self.players.iter().any(|pl| pl.flags.contains(player::PLAYER_REACHED_GOAL))
+    }
+}
+
 #[cfg(test)]
 pub mod test {
     use super::{map, Game, player};
+    use na;
+
+    /// A player standing still in the air above a flat floor whose top is at y = 0.
+    fn test_game() -> Game {
+        let mut builder = map::builder::MapBuilder::new();
+        builder.add_box(na::Pnt3::new(-512.0, 0.0, -512.0), na::Pnt3::new(512.0, 32.0, 512.0));
+
+        Game {
+            map: builder.build(),
+            players: vec![player::Player {
+                pos: na::Pnt3::new(0.0, -64.0, 0.0),
+                eyeheight: 0.0,
+                eyeang: na::zero(),
+                viewpunch: na::zero(),
+                viewpunch_vel: na::zero(),
+                halfextents: player::PLAYER_HALFEXTENTS,
+                vel: na::zero(),
+                flags: player::PlayerFlags::empty(),
+                landtime: 0.0,
+                holdjumptime: 0.0,
+            }],
+            spawn: map::entities::Spawn::origin(),
+            movesettings: ::std::default::Default::default(),
+            timescale: 1.0,
+            time: 0.0,
+        }
+    }
+
+    fn run(game: &mut Game, input: &player::movement::MoveInput, ticks: u32) {
+        let dt = 1.0 / 200.0;
+        for _ in 0..ticks {
+            game.time += dt;
+            player::movement::move_player(game, 0, input, dt);
+        }
+    }
+
+    fn idle() -> player::movement::MoveInput {
+        player::movement::MoveInput {
+            wishvel: na::zero(),
+            eyeang: na::zero(),
+            jump: false,
+            reset: false,
+        }
+    }
+
+    #[test]
+    fn player_lands_on_floor() {
+        let mut game = test_game();
+        run(&mut game, &idle(), 400);
+
+        let pl = &game.players[0];
+        assert!(pl.flags.contains(player::PLAYER_ONGROUND));
+        assert!(pl.pos.y < -player::PLAYER_HALFEXTENTS.y);
+        assert!(pl.pos.y > -player::PLAYER_HALFEXTENTS.y - 1.0);
+        assert!(na::approx_eq(&pl.vel.y, &0.0));
+    }
+
+    #[test]
+    fn player_runs_forward() {
+        let mut game = test_game();
+        run(&mut game, &idle(), 400);
+
+        let input = player::movement::MoveInput {
+            wishvel: na::Vec3::new(0.0, 0.0, -game.movesettings.movespeed),
+            ..idle()
+        };
+        run(&mut game, &input, 200);
 
+        let pl = &game.players[0];
+        assert!(pl.pos.z < -100.0);
+        assert!(pl.flags.contains(player::PLAYER_ONGROUND));
+    }
 }
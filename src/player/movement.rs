@@ -1,8 +1,10 @@
 use map::cast::{
     Ray,
+    Shape,
     CastResult
 };
 use map::{EntityKind, Map};
+use map::bsp::SURF_SLICK;
 use player::{
     Player,
     PlayerFlags,
@@ -10,6 +12,7 @@ use player::{
     PLAYER_HOLDING_JUMP,
     PLAYER_CAN_STEP,
     PLAYER_MUST_DIE,
+    PLAYER_REACHED_GOAL,
 };
 use na::{
     self,
@@ -42,7 +45,7 @@ fn simple_move(map: &Map, pl: &mut Player, dt: f32) {
         let moveray = Ray {
             orig: pl.pos,
             dir: v * dt,
-            halfextents: pl.halfextents
+            shape: Shape::Box(pl.halfextents)
         };
 
         let cast = map.cast_ray(&moveray);
@@ -53,7 +56,7 @@ fn simple_move(map: &Map, pl: &mut Player, dt: f32) {
                     pl.flags.insert(PLAYER_MUST_DIE);
                 }
                 if map.entities[entidx as usize].kind == EntityKind::Goal {
-                    panic!("A WINNER IS YOU!");
+                    pl.flags.insert(PLAYER_REACHED_GOAL);
                 }
             }
 
@@ -111,7 +114,7 @@ fn how_far(map: &Map, pl: &Player, movement: na::Vec3<f32>) -> (na::Vec3<f32>, O
     let trace = map.cast_ray(&Ray {
         orig: pl.pos,
         dir: movement,
-        halfextents: pl.halfextents
+        shape: Shape::Box(pl.halfextents)
     });
     if let Some(trace) = trace {
         (pl.pos.to_vec() + (movement * trace.toi), Some(trace.norm)) 
@@ -161,8 +164,8 @@ pub fn move_player(game: &mut Game, playeridx: u32, input: &MoveInput, dt: f32)
         pl.eyeang = input.eyeang;
 
         if pl.flags.contains(PLAYER_MUST_DIE) || input.reset {
-            pl.pos = na::Pnt3::new(0.0, 0.0, 0.0);
-            pl.eyeang = na::Vec3::new(0.0, 0.0, 0.0);
+            pl.pos = game.spawn.pos;
+            pl.eyeang = na::Vec3::new(0.0, game.spawn.yaw, 0.0);
             pl.vel = na::zero();
             pl.flags = PlayerFlags::empty(); 
             // FIXME: need a better way to handle this
@@ -179,7 +182,7 @@ pub fn move_player(game: &mut Game, playeridx: u32, input: &MoveInput, dt: f32)
         let downray = Ray {
             orig: pl.pos,
             dir: na::Vec3::new(0.0, 0.1, 0.0),
-            halfextents: pl.halfextents
+            shape: Shape::Box(pl.halfextents)
         };
 
         let cast = game.map.cast_ray(&downray);
@@ -193,6 +196,8 @@ pub fn move_player(game: &mut Game, playeridx: u32, input: &MoveInput, dt: f32)
         } else {
             (None, false)
         };
+        // Slick ground has no friction, and you can only steer on it like in the air.
+        let slick = hit_floor && cast.map_or(false, |c| c.flags & SURF_SLICK != 0);
 
         if hit_floor {
             if !pl.flags.contains(PLAYER_ONGROUND) {
@@ -220,12 +225,12 @@ pub fn move_player(game: &mut Game, playeridx: u32, input: &MoveInput, dt: f32)
             pl.flags.remove(PLAYER_HOLDING_JUMP);
         }
 
-        let accel = if pl.flags.contains(PLAYER_ONGROUND) && game.time > (pl.landtime + game.movesettings.slidetime) {
+        let accel = if pl.flags.contains(PLAYER_ONGROUND) && game.time > (pl.landtime + game.movesettings.slidetime) && !slick {
             game.movesettings.accel
         } else {
             game.movesettings.airaccel
         };
-        let friction = if pl.flags.contains(PLAYER_ONGROUND) && game.time > (pl.landtime + game.movesettings.slidetime) { 
+        let friction = if pl.flags.contains(PLAYER_ONGROUND) && game.time > (pl.landtime + game.movesettings.slidetime) && !slick { 
             game.movesettings.friction 
         } else {
             0.0
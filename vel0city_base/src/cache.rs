@@ -0,0 +1,138 @@
+//! Sharing loaded assets. Everything that asks for the same name gets a handle to
+//! the same copy, which sticks around until `free_unused` finds nobody holding it.
+
+use std::collections::HashMap;
+use std::rc::Rc;
+
+struct Entry<T> {
+    value: Rc<T>,
+    /// Roughly how many bytes the value takes up, as told by its loader.
+    size: usize,
+}
+
+pub struct Cache<T> {
+    entries: HashMap<String, Entry<T>>,
+}
+
+impl<T> Default for Cache<T> {
+    fn default() -> Cache<T> {
+        Cache { entries: HashMap::new() }
+    }
+}
+
+impl<T> Cache<T> {
+    pub fn new() -> Cache<T> {
+        Cache::default()
+    }
+
+    pub fn get(&self, name: &str) -> Option<Rc<T>> {
+        self.entries.get(name).map(|entry| entry.value.clone())
+    }
+
+    /// The cached copy of `name`, or what `load` gives along with its size. Nothing
+    /// is cached if `load` fails, so the next call tries again.
+    pub fn get_or_load<F, E>(&mut self, name: &str, load: F) -> Result<Rc<T>, E>
+        where F: FnOnce() -> Result<(T, usize), E> {
+        if let Some(value) = self.get(name) {
+            return Ok(value);
+        }
+        let (value, size) = try!(load());
+        let value = Rc::new(value);
+        self.entries.insert(name.to_string(), Entry { value: value.clone(), size: size });
+        Ok(value)
+    }
+
+    /// Like `get_or_load`, for loaders that always succeed.
+    pub fn get_or_insert_with<F>(&mut self, name: &str, load: F) -> Rc<T> where F: FnOnce() -> (T, usize) {
+        match self.get_or_load(name, || Ok::<_, ()>(load())) {
+            Ok(value) => value,
+            Err(()) => unreachable!(),
+        }
+    }
+
+    /// Forgets `name`, so the next `get_or_load` loads it again. Handles that are
+    /// already out keep the old copy.
+    pub fn invalidate(&mut self, name: &str) {
+        self.entries.remove(name);
+    }
+
+    /// Drops everything only the cache holds, like the last map's textures after
+    /// switching maps. Returns how many bytes that freed.
+    pub fn free_unused(&mut self) -> usize {
+        let unused: Vec<String> = self.entries.iter()
+            .filter(|&(_, entry)| Rc::strong_count(&entry.value) == 1)
+            .map(|(name, _)| name.clone())
+            .collect();
+        let mut freed = 0;
+        for name in unused {
+            if let Some(entry) = self.entries.remove(&name) {
+                freed += entry.size;
+            }
+        }
+        freed
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// The total size of everything cached, in bytes.
+    pub fn memory(&self) -> usize {
+        self.entries.values().fold(0, |total, entry| total + entry.size)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::Cache;
+
+    fn load(value: &str) -> Result<(String, usize), ()> {
+        Ok((value.to_string(), value.len()))
+    }
+
+    #[test]
+    fn shares_values() {
+        let mut cache = Cache::new();
+        let a = cache.get_or_load("a", || load("first")).unwrap();
+        let b = cache.get_or_insert_with("a", || ("second".to_string(), 6));
+        assert!(&*a as *const _ == &*b as *const _);
+        assert_eq!(*b, "first");
+        assert_eq!(cache.len(), 1);
+        assert_eq!(cache.memory(), 5);
+
+        assert_eq!(cache.get_or_load("b", || Err(())), Err(()));
+        assert!(cache.get("b").is_none());
+        assert_eq!(cache.len(), 1);
+    }
+
+    #[test]
+    fn frees_unused_values() {
+        let mut cache = Cache::new();
+        let kept = cache.get_or_load("kept", || load("kept")).unwrap();
+        cache.get_or_load("dropped", || load("dropped")).unwrap();
+        assert_eq!(cache.memory(), 11);
+
+        assert_eq!(cache.free_unused(), 7);
+        assert!(cache.get("dropped").is_none());
+        assert_eq!(cache.get("kept"), Some(kept.clone()));
+        assert_eq!(cache.memory(), 4);
+
+        drop(kept);
+        assert_eq!(cache.free_unused(), 4);
+        assert!(cache.is_empty());
+    }
+
+    #[test]
+    fn invalidated_values_reload() {
+        let mut cache = Cache::new();
+        let old = cache.get_or_load("a", || load("old")).unwrap();
+        cache.invalidate("a");
+        let new = cache.get_or_load("a", || load("new")).unwrap();
+        assert_eq!(*old, "old");
+        assert_eq!(*new, "new");
+    }
+}
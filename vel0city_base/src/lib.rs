@@ -1 +1,9 @@
+extern crate flate2;
+
 pub mod assets;
+pub mod cache;
+pub mod pk3;
+pub mod rng;
+pub mod testing;
+pub mod vfs;
+pub mod watch;
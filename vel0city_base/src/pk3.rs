@@ -0,0 +1,361 @@
+//! Reading Quake 3 `.pk3` files, which are zips. Only what pk3s use is supported:
+//! stored and deflated entries, without encryption or zip64.
+
+use flate2::read::DeflateDecoder;
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{self, Read, Seek, SeekFrom};
+use std::path::{Path, PathBuf};
+
+const END_OF_CENTRAL_DIR: u32 = 0x06054b50;
+const CENTRAL_DIR_ENTRY: u32 = 0x02014b50;
+const LOCAL_HEADER: u32 = 0x04034b50;
+
+const END_OF_CENTRAL_DIR_LEN: usize = 22;
+const CENTRAL_DIR_ENTRY_LEN: usize = 46;
+const LOCAL_HEADER_LEN: usize = 30;
+/// The end of the central directory can be followed by a comment this long at most.
+const MAX_COMMENT_LEN: usize = 0xffff;
+
+const METHOD_STORED: u16 = 0;
+const METHOD_DEFLATE: u16 = 8;
+
+#[derive(Clone, Debug)]
+struct Entry {
+    name: String,
+    method: u16,
+    compressed_size: u32,
+    size: u32,
+    local_header: u32,
+}
+
+/// An opened pk3. Only the directory is kept in memory; entries are read from the
+/// file when they're opened.
+#[derive(Debug)]
+pub struct Pk3 {
+    path: PathBuf,
+    entries: Vec<Entry>,
+    /// Lower-cased names to indices into `entries`, since Quake 3 ignores case in pk3s.
+    by_name: HashMap<String, usize>,
+}
+
+fn bad_data(what: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, what.to_string())
+}
+
+fn u16_at(data: &[u8], at: usize) -> u16 {
+    data[at] as u16 | (data[at + 1] as u16) << 8
+}
+
+fn u32_at(data: &[u8], at: usize) -> u32 {
+    u16_at(data, at) as u32 | (u16_at(data, at + 2) as u32) << 16
+}
+
+fn read_exact_at(file: &mut File, offset: u64, len: usize) -> io::Result<Vec<u8>> {
+    try!(file.seek(SeekFrom::Start(offset)));
+    let mut buf = vec![0; len];
+    try!(file.read_exact(&mut buf));
+    Ok(buf)
+}
+
+/// Finds the end of the central directory record, searching back from the end
+/// past any comment. Returns the central directory's offset, size and entry count.
+fn find_central_dir(tail: &[u8]) -> io::Result<(u32, u32, u16)> {
+    if tail.len() < END_OF_CENTRAL_DIR_LEN {
+        return Err(bad_data("Too short to be a zip"));
+    }
+    let mut at = tail.len() - END_OF_CENTRAL_DIR_LEN;
+    loop {
+        if u32_at(tail, at) == END_OF_CENTRAL_DIR {
+            return Ok((u32_at(tail, at + 16), u32_at(tail, at + 12), u16_at(tail, at + 10)));
+        }
+        if at == 0 {
+            return Err(bad_data("No end of central directory record"));
+        }
+        at -= 1;
+    }
+}
+
+fn parse_central_dir(data: &[u8], n_entries: u16) -> io::Result<Vec<Entry>> {
+    let mut entries = vec![];
+    let mut at = 0;
+    for _ in 0..n_entries {
+        if at + CENTRAL_DIR_ENTRY_LEN > data.len() || u32_at(data, at) != CENTRAL_DIR_ENTRY {
+            return Err(bad_data("Bad central directory entry"));
+        }
+        let flags = u16_at(data, at + 8);
+        let method = u16_at(data, at + 10);
+        let compressed_size = u32_at(data, at + 20);
+        let size = u32_at(data, at + 24);
+        let name_len = u16_at(data, at + 28) as usize;
+        let extra_len = u16_at(data, at + 30) as usize;
+        let comment_len = u16_at(data, at + 32) as usize;
+        let local_header = u32_at(data, at + 42);
+
+        let name_start = at + CENTRAL_DIR_ENTRY_LEN;
+        if name_start + name_len > data.len() {
+            return Err(bad_data("Bad central directory entry"));
+        }
+        let name = String::from_utf8_lossy(&data[name_start..name_start + name_len]).into_owned();
+        at = name_start + name_len + extra_len + comment_len;
+
+        if flags & 1 != 0 {
+            return Err(bad_data("Encrypted entries aren't supported"));
+        }
+        if compressed_size == 0xffffffff || size == 0xffffffff || local_header == 0xffffffff {
+            return Err(bad_data("Zip64 isn't supported"));
+        }
+        // Directories are only implied by the files in them.
+        if name.ends_with('/') {
+            continue;
+        }
+        entries.push(Entry {
+            name: name,
+            method: method,
+            compressed_size: compressed_size,
+            size: size,
+            local_header: local_header,
+        });
+    }
+    Ok(entries)
+}
+
+impl Pk3 {
+    /// Reads the directory of the pk3 at `path`.
+    pub fn open<P: AsRef<Path>>(path: P) -> io::Result<Pk3> {
+        let path = path.as_ref().to_path_buf();
+        let mut file = try!(File::open(&path));
+        let len = try!(file.seek(SeekFrom::End(0)));
+        let tail_len = ::std::cmp::min(len, (END_OF_CENTRAL_DIR_LEN + MAX_COMMENT_LEN) as u64);
+        let tail = try!(read_exact_at(&mut file, len - tail_len, tail_len as usize));
+
+        let (offset, size, n_entries) = try!(find_central_dir(&tail));
+        if offset as u64 + size as u64 > len {
+            return Err(bad_data("Central directory runs past the end of the file"));
+        }
+        let central_dir = try!(read_exact_at(&mut file, offset as u64, size as usize));
+        let entries = try!(parse_central_dir(&central_dir, n_entries));
+        // The sizes are only trusted once they fit in the file, since they say how
+        // much to allocate when reading.
+        for entry in &entries {
+            if entry.local_header as u64 + LOCAL_HEADER_LEN as u64 + entry.compressed_size as u64 > len {
+                return Err(bad_data("Entry runs past the end of the file"));
+            }
+        }
+
+        // Like Quake 3, the first of two entries with the same name wins.
+        let mut by_name = HashMap::new();
+        for (idx, entry) in entries.iter().enumerate() {
+            by_name.entry(entry.name.to_lowercase()).or_insert(idx);
+        }
+        Ok(Pk3 {
+            path: path,
+            entries: entries,
+            by_name: by_name,
+        })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    fn find(&self, name: &str) -> Option<&Entry> {
+        self.by_name.get(&name.to_lowercase()).map(|&idx| &self.entries[idx])
+    }
+
+    pub fn contains(&self, name: &str) -> bool {
+        self.find(name).is_some()
+    }
+
+    /// Names of the files directly inside `dir`, which is given without a trailing `/`.
+    pub fn list(&self, dir: &str) -> Vec<String> {
+        let dir = dir.to_lowercase();
+        self.entries.iter()
+            .filter(|entry| {
+                let name = entry.name.to_lowercase();
+                let parent = match name.rfind('/') {
+                    Some(slash) => &name[..slash],
+                    None => "",
+                };
+                parent == dir
+            })
+            .map(|entry| entry.name.clone())
+            .collect()
+    }
+
+    /// Reads and decompresses the whole of `name`.
+    pub fn read(&self, name: &str) -> io::Result<Vec<u8>> {
+        let entry = match self.find(name) {
+            Some(entry) => entry,
+            None => return Err(io::Error::new(io::ErrorKind::NotFound, format!("{} isn't in {:?}", name, self.path))),
+        };
+
+        let mut file = try!(File::open(&self.path));
+        let header = try!(read_exact_at(&mut file, entry.local_header as u64, LOCAL_HEADER_LEN));
+        if u32_at(&header, 0) != LOCAL_HEADER {
+            return Err(bad_data("Bad local file header"));
+        }
+        // The local header's own name and extra field can differ from the central directory's.
+        let data_start = entry.local_header as u64 + LOCAL_HEADER_LEN as u64 +
+            u16_at(&header, 26) as u64 + u16_at(&header, 28) as u64;
+        let compressed = try!(read_exact_at(&mut file, data_start, entry.compressed_size as usize));
+
+        let data = match entry.method {
+            METHOD_STORED => compressed,
+            METHOD_DEFLATE => {
+                // Reading one byte past the size is enough to catch entries that lie about it.
+                let mut data = vec![];
+                try!(DeflateDecoder::new(&compressed[..]).take(entry.size as u64 + 1).read_to_end(&mut data));
+                data
+            },
+            _ => return Err(bad_data("Unsupported compression method")),
+        };
+        if data.len() != entry.size as usize {
+            return Err(bad_data("Entry isn't the size the directory says"));
+        }
+        Ok(data)
+    }
+}
+
+#[cfg(test)]
+pub mod test {
+    use flate2::write::DeflateEncoder;
+    use flate2::Compression;
+    use std::fs::File;
+    use std::io::{Read, Write};
+    use std::path::Path;
+    use testing::temp_dir;
+    use super::Pk3;
+
+    fn put_u16(out: &mut Vec<u8>, v: u16) {
+        out.push(v as u8);
+        out.push((v >> 8) as u8);
+    }
+
+    fn put_u32(out: &mut Vec<u8>, v: u32) {
+        put_u16(out, v as u16);
+        put_u16(out, (v >> 16) as u16);
+    }
+
+    /// Writes a zip to `path`. Entries with `true` are deflated, the rest stored.
+    /// CRCs are left as zero, since nothing checks them.
+    pub fn write_zip(path: &Path, files: &[(&str, &[u8], bool)]) {
+        let mut out = vec![];
+        let mut central = vec![];
+        for &(name, contents, deflate) in files {
+            let data = if deflate {
+                let mut encoder = DeflateEncoder::new(vec![], Compression::default());
+                encoder.write_all(contents).unwrap();
+                encoder.finish().unwrap()
+            } else {
+                contents.to_vec()
+            };
+            let method = if deflate { 8 } else { 0 };
+            let offset = out.len() as u32;
+
+            put_u32(&mut out, 0x04034b50);
+            for &v in &[20, 0, method, 0, 0] {
+                put_u16(&mut out, v);
+            }
+            for &v in &[0, data.len() as u32, contents.len() as u32] {
+                put_u32(&mut out, v);
+            }
+            put_u16(&mut out, name.len() as u16);
+            // Some extra field bytes, to check they're skipped.
+            put_u16(&mut out, 4);
+            out.extend(name.bytes());
+            out.extend([0xca, 0xfe, 0, 0].iter().cloned());
+            out.extend(data.iter().cloned());
+
+            put_u32(&mut central, 0x02014b50);
+            for &v in &[20, 20, 0, method, 0, 0] {
+                put_u16(&mut central, v);
+            }
+            for &v in &[0, data.len() as u32, contents.len() as u32] {
+                put_u32(&mut central, v);
+            }
+            for &v in &[name.len() as u16, 0, 0, 0, 0] {
+                put_u16(&mut central, v);
+            }
+            put_u32(&mut central, 0);
+            put_u32(&mut central, offset);
+            central.extend(name.bytes());
+        }
+
+        let central_offset = out.len() as u32;
+        out.extend(central.iter().cloned());
+        put_u32(&mut out, 0x06054b50);
+        for &v in &[0, 0, files.len() as u16, files.len() as u16] {
+            put_u16(&mut out, v);
+        }
+        put_u32(&mut out, central.len() as u32);
+        put_u32(&mut out, central_offset);
+        let comment = b"a comment";
+        put_u16(&mut out, comment.len() as u16);
+        out.extend(comment.iter().cloned());
+
+        File::create(path).unwrap().write_all(&out).unwrap();
+    }
+
+    #[test]
+    fn reads_entries() {
+        let dir = temp_dir("pk3_reads_entries", &[]);
+        let path = dir.path().join("test.pk3");
+        let big: Vec<u8> = (0..10000).map(|i| (i % 7) as u8).collect();
+        write_zip(&path, &[
+            ("maps/test.bsp", b"IBSP stored", false),
+            ("textures/base/Floor.png", &big, true),
+        ]);
+        let pk3 = Pk3::open(&path).unwrap();
+
+        assert_eq!(pk3.read("maps/test.bsp").unwrap(), b"IBSP stored".to_vec());
+        assert_eq!(pk3.read("textures/base/floor.png").unwrap(), big);
+        assert!(pk3.contains("TEXTURES/BASE/FLOOR.PNG"));
+        assert!(!pk3.contains("textures/base/wall.png"));
+        assert!(pk3.read("textures/base/wall.png").is_err());
+    }
+
+    #[test]
+    fn lists_directories() {
+        let dir = temp_dir("pk3_lists_directories", &[]);
+        let path = dir.path().join("test.pk3");
+        write_zip(&path, &[
+            ("scripts/a.shader", b"", false),
+            ("scripts/sub/b.shader", b"", false),
+            ("Scripts/C.shader", b"", true),
+            ("top.txt", b"", false),
+        ]);
+        let pk3 = Pk3::open(&path).unwrap();
+        assert_eq!(pk3.list("scripts"), vec!["scripts/a.shader", "Scripts/C.shader"]);
+        assert_eq!(pk3.list(""), vec!["top.txt"]);
+    }
+
+    #[test]
+    fn rejects_entries_past_the_end() {
+        let dir = temp_dir("pk3_rejects_entries_past_the_end", &[]);
+        let path = dir.path().join("test.pk3");
+        write_zip(&path, &[("maps/test.bsp", b"IBSP stored", false)]);
+        assert!(Pk3::open(&path).is_ok());
+
+        // Make the central directory claim the entry is nearly 4 GiB.
+        let mut data = vec![];
+        File::open(&path).unwrap().read_to_end(&mut data).unwrap();
+        let eocd = data.len() - 22 - b"a comment".len();
+        let central_dir = super::u32_at(&data, eocd + 16) as usize;
+        for byte in &mut data[central_dir + 20..central_dir + 24] {
+            *byte = 0xfe;
+        }
+        File::create(&path).unwrap().write_all(&data).unwrap();
+        assert!(Pk3::open(&path).is_err());
+    }
+
+    #[test]
+    fn rejects_junk() {
+        let dir = temp_dir("pk3_rejects_junk", &[]);
+        let path = dir.path().join("test.pk3");
+        File::create(&path).unwrap().write_all(b"this is not a zip file, not even close").unwrap();
+        assert!(Pk3::open(&path).is_err());
+        File::create(&path).unwrap().write_all(b"").unwrap();
+        assert!(Pk3::open(&path).is_err());
+    }
+}
@@ -0,0 +1,68 @@
+//! A small xorshift64* generator, for generated levels and randomized tests. Both
+//! have to come out the same on every machine, so we don't want to depend on
+//! anything we don't control.
+
+pub struct Rng {
+    state: u64
+}
+impl Rng {
+    pub fn new(seed: u64) -> Rng {
+        // xorshift gets stuck at zero, so make sure we never start there.
+        Rng { state: seed ^ 0x9e3779b97f4a7c15 | 1 }
+    }
+
+    pub fn next_u64(&mut self) -> u64 {
+        self.state ^= self.state >> 12;
+        self.state ^= self.state << 25;
+        self.state ^= self.state >> 27;
+        self.state.wrapping_mul(2685821657736338717)
+    }
+
+    /// A number in [0, 1).
+    pub fn next_f32(&mut self) -> f32 {
+        (self.next_u64() >> 40) as f32 / (1u64 << 24) as f32
+    }
+
+    /// A number in [lo, hi).
+    pub fn range(&mut self, lo: f32, hi: f32) -> f32 {
+        lo + (hi - lo) * self.next_f32()
+    }
+
+    /// A number in [0, n), or 0 if `n` is.
+    pub fn below(&mut self, n: usize) -> usize {
+        if n == 0 {
+            0
+        } else {
+            (self.next_u64() % n as u64) as usize
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::Rng;
+
+    #[test]
+    fn same_seed_same_numbers() {
+        let numbers = |seed| {
+            let mut rng = Rng::new(seed);
+            (0..16).map(|_| rng.next_u64()).collect::<Vec<_>>()
+        };
+        assert_eq!(numbers(1), numbers(1));
+        assert!(numbers(1) != numbers(2));
+        // Seeds that would leave the state at zero still go somewhere.
+        assert!(numbers(0x9e3779b97f4a7c15).iter().any(|&n| n != 0));
+    }
+
+    #[test]
+    fn ranges() {
+        let mut rng = Rng::new(7);
+        for _ in 0..1000 {
+            let f = rng.range(-2.0, 3.0);
+            assert!(f >= -2.0);
+            assert!(f < 3.0);
+            assert!(rng.below(5) < 5);
+        }
+        assert_eq!(rng.below(0), 0);
+    }
+}
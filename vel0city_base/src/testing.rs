@@ -0,0 +1,50 @@
+//! Fixtures for tests, here and in the crates that use this one. None of it is
+//! behind `cfg(test)`, since that's only set for the crate being tested.
+
+use std::env;
+use std::fs::{self, File};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use vfs::{self, Mount, Vfs};
+
+/// A directory of files for one test, deleted again when it's dropped.
+pub struct TempDir {
+    path: PathBuf,
+}
+impl TempDir {
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Writes `contents` to `name` inside the directory, making any directories
+    /// in between.
+    pub fn write(&self, name: &str, contents: &[u8]) {
+        let path = self.path.join(name);
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        File::create(&path).unwrap().write_all(contents).unwrap();
+    }
+}
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.path);
+    }
+}
+
+/// A fresh directory called `vel0city_<test>` holding `files`. Tests run in
+/// parallel, so each one needs its own name.
+pub fn temp_dir(test: &str, files: &[(&str, &[u8])]) -> TempDir {
+    let dir = TempDir { path: env::temp_dir().join(format!("vel0city_{}", test)) };
+    let _ = fs::remove_dir_all(&dir.path);
+    fs::create_dir_all(&dir.path).unwrap();
+    for &(name, contents) in files {
+        dir.write(name, contents);
+    }
+    dir
+}
+
+/// Like `temp_dir`, and mounts the directory as this thread's only asset source.
+pub fn temp_mount(test: &str, files: &[(&str, &[u8])]) -> TempDir {
+    let dir = temp_dir(test, files);
+    vfs::with_vfs(|vfs| *vfs = Vfs::new(vec![Mount::Dir(dir.path.clone())]));
+    dir
+}
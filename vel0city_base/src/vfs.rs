@@ -0,0 +1,318 @@
+//! Where assets come from: an ordered list of places to look, so mods and map
+//! packs can override the base game's files by being mounted ahead of them.
+
+use std::cell::RefCell;
+use std::collections::HashSet;
+use std::env;
+use std::ffi::OsStr;
+use std::fs::{self, File};
+use std::io::{self, Cursor, Read};
+use std::path::{Component, Path, PathBuf};
+use pk3::Pk3;
+
+/// Environment variable with the directories to mount, highest priority first,
+/// separated like `PATH`. The `.pk3`s in each directory are mounted right after it.
+pub const SEARCH_PATH_VAR: &'static str = "VEL0CITY_ASSETS";
+/// What gets mounted when `SEARCH_PATH_VAR` isn't set.
+pub const DEFAULT_SEARCH_PATH: &'static str = "assets";
+
+/// Somewhere assets can be found.
+#[derive(Debug)]
+pub enum Mount {
+    Dir(PathBuf),
+    Archive(Pk3),
+}
+
+/// Tells versions of a loose file apart, by its length and a hash of its contents.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct Version {
+    pub len: u64,
+    pub hash: u64,
+}
+
+/// 64-bit FNV-1a.
+fn fnv(bytes: &[u8]) -> u64 {
+    bytes.iter().fold(0xcbf29ce484222325, |hash, &b| (hash ^ b as u64).wrapping_mul(0x100000001b3))
+}
+
+/// Reads one asset, wherever it came from.
+#[derive(Debug)]
+pub enum AssetReader {
+    File(File),
+    /// Archive entries are decompressed all at once.
+    Memory(Cursor<Vec<u8>>),
+}
+impl Read for AssetReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match *self {
+            AssetReader::File(ref mut f) => f.read(buf),
+            AssetReader::Memory(ref mut c) => c.read(buf),
+        }
+    }
+}
+
+/// Asset names are relative paths with `/` between the parts. Anything that could
+/// escape the mount is refused.
+fn is_safe_name(name: &str) -> bool {
+    let path = Path::new(name);
+    !name.is_empty() && !path.has_root() && !path.components().any(|c| c == Component::ParentDir)
+}
+
+fn not_found(name: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::NotFound, format!("{} isn't in any asset mount", name))
+}
+
+impl Mount {
+    fn path_of(&self, name: &str) -> Option<PathBuf> {
+        match *self {
+            Mount::Dir(ref root) if is_safe_name(name) => Some(root.join(name)),
+            _ => None,
+        }
+    }
+
+    pub fn exists(&self, name: &str) -> bool {
+        match *self {
+            Mount::Archive(ref pk3) => pk3.contains(name),
+            _ => match self.path_of(name) {
+                Some(path) => path.is_file(),
+                None => false,
+            },
+        }
+    }
+
+    pub fn open(&self, name: &str) -> io::Result<AssetReader> {
+        match *self {
+            Mount::Archive(ref pk3) => pk3.read(name).map(|data| AssetReader::Memory(Cursor::new(data))),
+            _ => match self.path_of(name) {
+                Some(path) => File::open(&path).map(AssetReader::File),
+                None => Err(not_found(name)),
+            },
+        }
+    }
+
+    /// Which version of `name` is there now, if it's a loose file. Archives are
+    /// indexed when they're mounted, so their entries never change.
+    pub fn version(&self, name: &str) -> Option<Version> {
+        let path = match self.path_of(name) {
+            Some(path) => path,
+            None => return None,
+        };
+        let len = match fs::metadata(&path) {
+            Ok(ref metadata) if metadata.is_file() => metadata.len(),
+            _ => return None,
+        };
+        let mut data = vec![];
+        match File::open(&path).and_then(|mut file| file.read_to_end(&mut data)) {
+            Ok(_) => Some(Version { len: len, hash: fnv(&data) }),
+            Err(_) => None,
+        }
+    }
+
+    /// Names of the files directly inside `dir`, which is given without a trailing `/`.
+    pub fn list(&self, dir: &str) -> Vec<String> {
+        let prefix = if dir.is_empty() { String::new() } else { format!("{}/", dir) };
+        let path = match *self {
+            Mount::Archive(ref pk3) => return pk3.list(dir),
+            Mount::Dir(ref root) if dir.is_empty() => root.clone(),
+            Mount::Dir(ref root) if is_safe_name(dir) => root.join(dir),
+            _ => return vec![],
+        };
+        let entries = match fs::read_dir(&path) {
+            Ok(entries) => entries,
+            Err(_) => return vec![],
+        };
+        entries.filter_map(|entry| entry.ok())
+            .filter(|entry| entry.path().is_file())
+            .filter_map(|entry| entry.file_name().to_str().map(|name| format!("{}{}", prefix, name)))
+            .collect()
+    }
+}
+
+/// The mounts to search, in order.
+#[derive(Debug)]
+pub struct Vfs {
+    pub mounts: Vec<Mount>,
+}
+
+impl Vfs {
+    pub fn new(search_order: Vec<Mount>) -> Vfs {
+        Vfs { mounts: search_order }
+    }
+
+    /// Mounts every directory in `search_path`, which is separated like `PATH`.
+    /// Each directory's loose files come first, then its pk3s with later names
+    /// overriding earlier ones, like in Quake 3.
+    pub fn from_search_path(search_path: &OsStr) -> Vfs {
+        let mut mounts = vec![];
+        for dir in env::split_paths(search_path) {
+            let mut pk3s: Vec<PathBuf> = match fs::read_dir(&dir) {
+                Ok(entries) => entries.filter_map(|entry| entry.ok())
+                    .map(|entry| entry.path())
+                    .filter(|path| path.extension().and_then(|ext| ext.to_str()).map(|ext| ext.to_lowercase()) == Some("pk3".to_string()))
+                    .collect(),
+                Err(_) => vec![],
+            };
+            pk3s.sort();
+            mounts.push(Mount::Dir(dir));
+            for path in pk3s.iter().rev() {
+                match Pk3::open(path) {
+                    Ok(pk3) => mounts.push(Mount::Archive(pk3)),
+                    Err(e) => println!("Couldn't mount {:?}: {}", path, e),
+                }
+            }
+        }
+        Vfs::new(mounts)
+    }
+
+    /// Uses `SEARCH_PATH_VAR`, or `DEFAULT_SEARCH_PATH` without it.
+    pub fn from_env() -> Vfs {
+        match env::var_os(SEARCH_PATH_VAR) {
+            Some(search_path) => Vfs::from_search_path(&search_path),
+            None => Vfs::from_search_path(OsStr::new(DEFAULT_SEARCH_PATH)),
+        }
+    }
+
+    pub fn exists(&self, name: &str) -> bool {
+        self.mounts.iter().any(|mount| mount.exists(name))
+    }
+
+    /// Opens `name` from the first mount that has it.
+    pub fn open(&self, name: &str) -> io::Result<AssetReader> {
+        match self.mounts.iter().find(|mount| mount.exists(name)) {
+            Some(mount) => mount.open(name),
+            None => Err(not_found(name)),
+        }
+    }
+
+    /// The version of the copy of `name` that `open` would use. See `Mount::version`.
+    pub fn version(&self, name: &str) -> Option<Version> {
+        match self.mounts.iter().find(|mount| mount.exists(name)) {
+            Some(mount) => mount.version(name),
+            None => None,
+        }
+    }
+
+    /// Every asset directly in `dir` whose name ends in `.ext`, from all the mounts,
+    /// sorted. An empty `ext` matches everything. Case is ignored like it is in pk3s,
+    /// so names that only differ in case are listed once, spelled like the first
+    /// mount that has them spells them.
+    pub fn list(&self, dir: &str, ext: &str) -> Vec<String> {
+        let dir = dir.trim_matches('/');
+        let suffix = format!(".{}", ext.to_lowercase());
+        let mut seen = HashSet::new();
+        let mut names = vec![];
+        for name in self.mounts.iter().flat_map(|mount| mount.list(dir).into_iter()) {
+            let lower = name.to_lowercase();
+            if (ext.is_empty() || lower.ends_with(&suffix[..])) && seen.insert(lower) {
+                names.push(name);
+            }
+        }
+        names.sort_by(|a, b| a.to_lowercase().cmp(&b.to_lowercase()));
+        names
+    }
+}
+
+thread_local!(static VFS: RefCell<Vfs> = RefCell::new(Vfs::from_env()));
+
+/// Runs `f` with this thread's VFS, to change what's mounted. Each thread starts
+/// out with `Vfs::from_env`.
+pub fn with_vfs<F, R>(f: F) -> R where F: FnOnce(&mut Vfs) -> R {
+    VFS.with(|vfs| f(&mut vfs.borrow_mut()))
+}
+
+pub fn exists(name: &str) -> bool {
+    with_vfs(|vfs| vfs.exists(name))
+}
+
+pub fn open(name: &str) -> io::Result<AssetReader> {
+    with_vfs(|vfs| vfs.open(name))
+}
+
+pub fn list(dir: &str, ext: &str) -> Vec<String> {
+    with_vfs(|vfs| vfs.list(dir, ext))
+}
+
+pub fn version(name: &str) -> Option<Version> {
+    with_vfs(|vfs| vfs.version(name))
+}
+
+#[cfg(test)]
+mod test {
+    use std::env;
+    use std::io::Read;
+    use std::path::PathBuf;
+    use pk3::test::write_zip;
+    use testing::temp_dir;
+    use super::{Mount, Vfs};
+
+    fn read(vfs: &Vfs, name: &str) -> String {
+        let mut s = String::new();
+        vfs.open(name).unwrap().read_to_string(&mut s).unwrap();
+        s
+    }
+
+    #[test]
+    fn earlier_mounts_win() {
+        let base = temp_dir("vfs_base", &[("a.txt", b"base a"), ("scripts/b.shader", b"base b")]);
+        let modded = temp_dir("vfs_mod", &[("a.txt", b"mod a"), ("scripts/c.shader", b"mod c")]);
+        let vfs = Vfs::new(vec![Mount::Dir(modded.path().to_path_buf()), Mount::Dir(base.path().to_path_buf())]);
+
+        assert_eq!(read(&vfs, "a.txt"), "mod a");
+        assert_eq!(read(&vfs, "scripts/b.shader"), "base b");
+        assert!(vfs.exists("scripts/c.shader"));
+        assert!(!vfs.exists("scripts/d.shader"));
+        assert!(vfs.open("scripts/d.shader").is_err());
+        assert_eq!(vfs.version("a.txt").unwrap().len, 5);
+        assert!(vfs.version("a.txt") != Mount::Dir(base.path().to_path_buf()).version("a.txt"));
+        assert_eq!(vfs.version("scripts/d.shader"), None);
+    }
+
+    #[test]
+    fn lists_across_mounts() {
+        let base = temp_dir("vfs_list_base", &[("scripts/a.shader", b""), ("scripts/b.txt", b""), ("scripts/sub/c.shader", b"")]);
+        let modded = temp_dir("vfs_list_mod", &[("scripts/a.shader", b""), ("scripts/d.shader", b"")]);
+        let vfs = Vfs::new(vec![Mount::Dir(modded.path().to_path_buf()), Mount::Dir(base.path().to_path_buf())]);
+
+        assert_eq!(vfs.list("scripts", "shader"), vec!["scripts/a.shader", "scripts/d.shader"]);
+        assert_eq!(vfs.list("scripts/", ""), vec!["scripts/a.shader", "scripts/b.txt", "scripts/d.shader"]);
+        assert!(vfs.list("nothing", "").is_empty());
+    }
+
+    #[test]
+    fn refuses_to_escape() {
+        let root = temp_dir("vfs_escape", &[("inside/a.txt", b"a")]);
+        let vfs = Vfs::new(vec![Mount::Dir(root.path().join("inside"))]);
+        assert!(vfs.exists("a.txt"));
+        assert!(!vfs.exists("../inside/a.txt"));
+        assert!(vfs.open("/etc/passwd").is_err());
+        assert!(vfs.list("..", "").is_empty());
+    }
+
+    #[test]
+    fn search_path() {
+        let paths = env::join_paths(["first", "second"].iter()).unwrap();
+        let vfs = Vfs::from_search_path(&paths);
+        let roots: Vec<PathBuf> = vfs.mounts.iter().filter_map(|mount| match *mount {
+            Mount::Dir(ref root) => Some(root.clone()),
+            _ => None,
+        }).collect();
+        assert_eq!(roots, vec![PathBuf::from("first"), PathBuf::from("second")]);
+    }
+
+    #[test]
+    fn pk3s_override_in_order() {
+        let root = temp_dir("vfs_pk3s", &[("maps/loose.bsp", b"loose"), ("textures/a.png", b"loose a")]);
+        write_zip(&root.path().join("pak0.pk3"), &[("maps/test.bsp", b"pak0 test", true), ("textures/a.png", b"pak0 a", false)]);
+        write_zip(&root.path().join("pak1.PK3"), &[("MAPS/Test.bsp", b"pak1 test", true)]);
+        let vfs = Vfs::from_search_path(root.path().as_os_str());
+
+        assert_eq!(vfs.mounts.len(), 3);
+        assert_eq!(read(&vfs, "maps/test.bsp"), "pak1 test");
+        assert_eq!(read(&vfs, "textures/a.png"), "loose a");
+        assert_eq!(read(&vfs, "maps/loose.bsp"), "loose");
+        assert!(vfs.version("maps/loose.bsp").is_some());
+        assert_eq!(vfs.version("maps/test.bsp"), None);
+        assert_eq!(vfs.list("maps", "bsp"), vec!["maps/loose.bsp", "MAPS/Test.bsp"]);
+        assert_eq!(vfs.list("maps", "BSP"), vec!["maps/loose.bsp", "MAPS/Test.bsp"]);
+    }
+}
@@ -0,0 +1,124 @@
+//! Noticing when assets change, so they can be reloaded while the game runs.
+//! This just rereads and hashes every watched file whenever it's polled, which is
+//! plenty for a few hundred files.
+
+use std::collections::HashMap;
+use std::sync::mpsc::{channel, Receiver, Sender};
+use vfs::{self, Version};
+
+struct Subscriber {
+    names: Vec<String>,
+    sender: Sender<String>,
+}
+
+#[derive(Default)]
+pub struct Watcher {
+    /// The version of each watched asset at the last poll, or `None` if it isn't a loose file.
+    versions: HashMap<String, Option<Version>>,
+    subscribers: Vec<Subscriber>,
+}
+
+impl Watcher {
+    pub fn new() -> Watcher {
+        Watcher::default()
+    }
+
+    /// Sends the name of each of `names` that changes to the receiver. Assets that
+    /// don't exist yet are watched too, so adding a file that takes priority over
+    /// an existing one counts as a change. Dropping the receiver unsubscribes.
+    pub fn subscribe(&mut self, names: Vec<String>) -> Receiver<String> {
+        for name in names.iter() {
+            if !self.versions.contains_key(name) {
+                self.versions.insert(name.clone(), vfs::version(name));
+            }
+        }
+        let (sender, receiver) = channel();
+        self.subscribers.push(Subscriber { names: names, sender: sender });
+        receiver
+    }
+
+    /// Checks every watched asset and tells subscribers about the ones that changed
+    /// since the last poll, which are also returned.
+    pub fn poll(&mut self) -> Vec<String> {
+        let mut changed = vec![];
+        for (name, version) in self.versions.iter_mut() {
+            let now = vfs::version(name);
+            if now != *version {
+                *version = now;
+                changed.push(name.clone());
+            }
+        }
+        changed.sort();
+
+        self.subscribers.retain(|subscriber| {
+            changed.iter()
+                .filter(|name| subscriber.names.contains(name))
+                .all(|name| subscriber.sender.send(name.clone()).is_ok())
+        });
+
+        let subscribers = &self.subscribers;
+        self.versions.retain(|name, _| subscribers.iter().any(|subscriber| subscriber.names.contains(name)));
+        changed
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::fs;
+    use testing::temp_mount;
+    use super::Watcher;
+
+    #[test]
+    fn notices_files_appearing_and_disappearing() {
+        let root = temp_mount("watch_appear", &[]);
+        root.write("a.glsl", b"a");
+        let mut watcher = Watcher::new();
+        let changes = watcher.subscribe(vec!["a.glsl".to_string(), "b.glsl".to_string()]);
+        assert!(watcher.poll().is_empty());
+
+        root.write("b.glsl", b"b");
+        assert_eq!(watcher.poll(), vec!["b.glsl"]);
+        assert_eq!(changes.try_recv().unwrap(), "b.glsl");
+        assert!(changes.try_recv().is_err());
+
+        fs::remove_file(root.path().join("a.glsl")).unwrap();
+        fs::remove_file(root.path().join("b.glsl")).unwrap();
+        assert_eq!(watcher.poll(), vec!["a.glsl", "b.glsl"]);
+        assert_eq!(changes.try_recv().unwrap(), "a.glsl");
+        assert_eq!(changes.try_recv().unwrap(), "b.glsl");
+        assert!(watcher.poll().is_empty());
+    }
+
+    #[test]
+    fn notices_edits_that_keep_the_length() {
+        let root = temp_mount("watch_edit", &[]);
+        root.write("a.glsl", b"old");
+        let mut watcher = Watcher::new();
+        let _changes = watcher.subscribe(vec!["a.glsl".to_string()]);
+
+        root.write("a.glsl", b"old");
+        assert!(watcher.poll().is_empty());
+        root.write("a.glsl", b"new");
+        assert_eq!(watcher.poll(), vec!["a.glsl"]);
+    }
+
+    #[test]
+    fn subscribers_only_hear_about_their_assets() {
+        let root = temp_mount("watch_subscribers", &[]);
+        let mut watcher = Watcher::new();
+        let shaders = watcher.subscribe(vec!["a.glsl".to_string()]);
+        let maps = watcher.subscribe(vec!["a.glsl".to_string(), "test.bsp".to_string()]);
+
+        root.write("test.bsp", b"map");
+        watcher.poll();
+        assert!(shaders.try_recv().is_err());
+        assert_eq!(maps.try_recv().unwrap(), "test.bsp");
+
+        drop(maps);
+        root.write("a.glsl", b"a");
+        watcher.poll();
+        assert_eq!(shaders.try_recv().unwrap(), "a.glsl");
+        assert_eq!(watcher.subscribers.len(), 1);
+        assert_eq!(watcher.versions.keys().collect::<Vec<_>>(), vec!["a.glsl"]);
+    }
+}
@@ -6,6 +6,7 @@ use na::{
     ToHomogeneous
 };
 use std::default::Default;
+use std::rc::Rc;
 
 pub struct HudManager {
     quad_verts: glium::VertexBuffer<QuadVertex>,
@@ -46,7 +47,7 @@ impl HudManager {
             match element.element_type {
                 ElementType::TransformedBlit { ref texture, f } => {
                     if let Some(customtransform) = f(context) {
-                        let samp = glium::uniforms::Sampler::new(texture)
+                        let samp = glium::uniforms::Sampler::new(&**texture)
                             .wrap_function(glium::uniforms::SamplerWrapFunction::Clamp)
                             .minify_filter(glium::uniforms::MinifySamplerFilter::LinearMipmapLinear)
                             .anisotropy(8);
@@ -87,7 +88,7 @@ pub struct Element {
 
 pub enum ElementType {
     TransformedBlit {
-        texture: glium::Texture2d,
+        texture: Rc<glium::Texture2d>,
         f: fn(&Context) -> Option<na::Mat4<f32>>,
     }
 }
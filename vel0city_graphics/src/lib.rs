@@ -8,6 +8,7 @@ extern crate vel0city_base;
 
 use glium::Surface;
 use map::GraphicsMap;
+use vel0city_base::assets;
 use std::sync::Arc;
 use std::default::Default;
 
@@ -32,6 +33,8 @@ pub struct Model {
 pub struct View {
     pub w2s: na::Mat4<f32>,
     pub cam: na::Mat4<f32>,
+    /// Game time, for animating materials.
+    pub time: f32,
 }
 
 pub struct Light {
@@ -46,46 +49,198 @@ pub struct Scene {
     pub lights: Vec<Light>,
 }
 
-pub fn draw_scene<S: glium::Surface>(surface: &mut S,
+/// Compiles a program from two shader assets, or says why it couldn't.
+pub fn load_program(display: &glium::Display, vertex: &str, fragment: &str) -> Result<glium::Program, String> {
+    let vertex_src = try!(assets::load_str_asset(vertex).map_err(|e| format!("Couldn't load {}: {}", vertex, e)));
+    let fragment_src = try!(assets::load_str_asset(fragment).map_err(|e| format!("Couldn't load {}: {}", fragment, e)));
+    glium::Program::from_source(display, &vertex_src, &fragment_src, None)
+        .map_err(|e| format!("Couldn't compile {} with {}: {:?}", vertex, fragment, e))
+}
+
+/// Draws the scene. Only the map faces in `visible_faces` are drawn, see
+/// `map::vis::visible_faces`.
+pub fn draw_scene<S: glium::Surface>(display: &glium::Display,
+                                     surface: &mut S,
                                      scene: &Scene,
-                                     view: &View) {
-    draw_map(surface, &scene.map, view);
+                                     view: &View,
+                                     visible_faces: &[u32]) {
+    draw_map(display, surface, &scene.map, view, visible_faces);
+}
+
+fn blend_factor(factor: map::shader::BlendFactor) -> glium::LinearBlendingFactor {
+    use map::shader::BlendFactor;
+    match factor {
+        BlendFactor::Zero => glium::LinearBlendingFactor::Zero,
+        BlendFactor::One => glium::LinearBlendingFactor::One,
+        BlendFactor::SrcColor => glium::LinearBlendingFactor::SourceColor,
+        BlendFactor::OneMinusSrcColor => glium::LinearBlendingFactor::OneMinusSourceColor,
+        BlendFactor::DstColor => glium::LinearBlendingFactor::DestinationColor,
+        BlendFactor::OneMinusDstColor => glium::LinearBlendingFactor::OneMinusDestinationColor,
+        BlendFactor::SrcAlpha => glium::LinearBlendingFactor::SourceAlpha,
+        BlendFactor::OneMinusSrcAlpha => glium::LinearBlendingFactor::OneMinusSourceAlpha,
+        BlendFactor::DstAlpha => glium::LinearBlendingFactor::DestinationAlpha,
+        BlendFactor::OneMinusDstAlpha => glium::LinearBlendingFactor::OneMinusDestinationAlpha,
+    }
+}
+
+/// Draws `count` indices of `indices` from `start`: a batch's range of the static
+/// index buffer if it's all visible, or its range of the frame's buffer if only some
+/// of it is.
+fn draw_batch<S, U>(surface: &mut S,
+                    map: &GraphicsMap,
+                    indices: &glium::IndexBuffer,
+                    (start, count): (usize, usize),
+                    program: &glium::Program,
+                    uniforms: &U,
+                    drawparams: &glium::DrawParameters) where S: glium::Surface, U: glium::uniforms::Uniforms {
+    surface.draw(&map.vertices,
+                 &indices.slice(start, count).unwrap(),
+                 program,
+                 uniforms,
+                 drawparams).unwrap();
 }
 
-fn draw_map<S: glium::Surface>(surface: &mut S, map: &GraphicsMap, view: &View) {
-    let drawparams_main = glium::DrawParameters {
-        depth_test: glium::DepthTest::IfLess,
-        depth_write: true,
-        backface_culling: glium::BackfaceCullingMode::CullCounterClockWise,
-        ..Default::default()
+fn draw_map<S: glium::Surface>(display: &glium::Display, surface: &mut S, map: &GraphicsMap, view: &View, visible_faces: &[u32]) {
+    let visible: Vec<_> = map::batch::compact_visible(&map.batches, &map.faces, &map.index_data, visible_faces)
+        .into_iter()
+        .filter(|visible_batch| {
+            let batch = &map.batches[visible_batch.batch];
+            map.texture_flags[batch.texture as usize] & map::bsp::SURF_NODRAW == 0
+        })
+        .collect();
+
+    // The indices of every partly visible batch go in one buffer for the whole
+    // frame, and each batch draws its own range of it.
+    let mut frame_data = vec![];
+    let mut frame_ranges = vec![];
+    for visible_batch in &visible {
+        frame_ranges.push(visible_batch.indices.as_ref().map(|indices| {
+            let start = frame_data.len();
+            frame_data.extend(indices.iter().cloned());
+            (start, indices.len())
+        }));
+    }
+    let frame_indices = if frame_data.is_empty() {
+        None
+    } else {
+        Some(glium::IndexBuffer::new(display, glium::index::TrianglesList(frame_data)))
     };
 
-    for face in &map.faces {
-        let color = &map.textures[face.texture as usize];
-        let colorsamp = glium::uniforms::Sampler::new(color)
-            .anisotropy(16)
-            .magnify_filter(glium::uniforms::MagnifySamplerFilter::Nearest)
-            .minify_filter(glium::uniforms::MinifySamplerFilter::LinearMipmapLinear);
-
-        if face.lightmap >= 0 {
-            let lightmap = &map.lightmaps[face.lightmap as usize];
-            let lmsamp = glium::uniforms::Sampler::new(lightmap)
-                .magnify_filter(glium::uniforms::MagnifySamplerFilter::Linear);
-
-            let uniforms = uniform! { 
-                w2s: *(view.w2s).as_array(),
-                cam: *(view.cam).as_array(),
-                model: *na::new_identity::<na::Mat4<_>>(4).as_array(), 
-                diffuse: colorsamp,
-                lightmap: lmsamp 
-            };
-            surface.draw(&map.vertices,
-                       &map.indices.slice(face.index_start as usize, face.index_count as usize).unwrap(),
-                       &map.shaders[0],
-                       &uniforms,
-                       &drawparams_main).unwrap();
+    for (visible_batch, frame_range) in visible.iter().zip(frame_ranges.into_iter()) {
+        let batch = &map.batches[visible_batch.batch];
+        let flags = map.texture_flags[batch.texture as usize];
+        let (indices, range) = match (frame_range, frame_indices.as_ref()) {
+            (None, _) => (&map.indices, (batch.index_start as usize, batch.index_count as usize)),
+            (Some(range), Some(frame_indices)) if range.1 > 0 => (frame_indices, range),
+            // The visible faces have no triangles.
+            (Some(_), _) => continue,
+        };
+
+        // Everything in a batch has the same texture, so it has the same material too.
+        let material = map.faces[batch.faces[0] as usize].material;
+        let technique = material.map(|m| &map.techniques[m as usize]);
+        let cull = match technique.map_or(map::shader::Cull::Front, |t| t.cull) {
+            map::shader::Cull::Front => glium::BackfaceCullingMode::CullCounterClockWise,
+            map::shader::Cull::Back => glium::BackfaceCullingMode::CullClockWise,
+            map::shader::Cull::None => glium::BackfaceCullingMode::CullingDisabled,
+        };
+
+        let lightmap = if batch.lightmap >= 0 {
+            &map.lightmaps[batch.lightmap as usize]
         } else {
-    println!("Skipping un-lightmapped face...");
+            &map.white_lightmap
+        };
+
+        match technique {
+            // Without its own program the stages can't be drawn properly, and plain
+            // texture and lightmap is the closest thing.
+            Some(technique) if flags & map::bsp::SURF_SKY == 0 && !technique.passes.is_empty()
+                               && map.shaders.loaded(map::SHADER_STAGE) => {
+                for pass in &technique.passes {
+                    let image = match pass.frame(view.time) {
+                        Some(frame) => &*map.stage_textures[frame as usize],
+                        None => match pass.image {
+                            map::technique::PassImage::Lightmap => lightmap,
+                            _ => &map.white_lightmap,
+                        },
+                    };
+                    let wrap = if pass.clamp {
+                        glium::uniforms::SamplerWrapFunction::Clamp
+                    } else {
+                        glium::uniforms::SamplerWrapFunction::Repeat
+                    };
+                    let imagesamp = glium::uniforms::Sampler::new(image)
+                        .wrap_function(wrap)
+                        .magnify_filter(glium::uniforms::MagnifySamplerFilter::Linear)
+                        .minify_filter(glium::uniforms::MinifySamplerFilter::LinearMipmapLinear);
+
+                    let lmsamp = glium::uniforms::Sampler::new(lightmap)
+                        .magnify_filter(glium::uniforms::MagnifySamplerFilter::Linear);
+
+                    let pass_uniforms = pass.uniforms(view.time);
+                    let lightmap_stage = match pass.image {
+                        map::technique::PassImage::Lightmap => 1,
+                        _ => 0,
+                    };
+                    let uniforms = uniform! {
+                        w2s: *(view.w2s).as_array(),
+                        cam: *(view.cam).as_array(),
+                        model: *na::new_identity::<na::Mat4<_>>(4).as_array(),
+                        diffuse: imagesamp,
+                        lightmap: lmsamp,
+                        tc_transform: pass_uniforms.tc_transform,
+                        stage_color: pass_uniforms.color,
+                        vertex_color: pass_uniforms.vertex_color,
+                        alpha_func: pass_uniforms.alpha_func,
+                        gbuffer: pass_uniforms.gbuffer,
+                        lightmap_stage: lightmap_stage
+                    };
+                    let drawparams = glium::DrawParameters {
+                        // Later passes land exactly on top of the first.
+                        depth_test: glium::DepthTest::IfLessOrEqual,
+                        depth_write: pass.depth_write,
+                        blending_function: pass.blend.map(|(src, dst)| glium::BlendingFunction::Addition {
+                            source: blend_factor(src),
+                            destination: blend_factor(dst),
+                        }),
+                        backface_culling: cull,
+                        ..Default::default()
+                    };
+                    draw_batch(surface, map, indices, range, &map.shaders[map::SHADER_STAGE], &uniforms, &drawparams);
+                }
+            },
+            _ => {
+                let program = if flags & map::bsp::SURF_SKY != 0 {
+                    &map.shaders[map::SHADER_SKY]
+                } else if batch.lightmap < 0 {
+                    &map.shaders[map::SHADER_VERTEXLIT]
+                } else {
+                    &map.shaders[map::SHADER_MAIN]
+                };
+
+                let color = &*map.textures[batch.texture as usize];
+                let colorsamp = glium::uniforms::Sampler::new(color)
+                    .anisotropy(16)
+                    .magnify_filter(glium::uniforms::MagnifySamplerFilter::Nearest)
+                    .minify_filter(glium::uniforms::MinifySamplerFilter::LinearMipmapLinear);
+                let lmsamp = glium::uniforms::Sampler::new(lightmap)
+                    .magnify_filter(glium::uniforms::MagnifySamplerFilter::Linear);
+
+                let uniforms = uniform! { 
+                    w2s: *(view.w2s).as_array(),
+                    cam: *(view.cam).as_array(),
+                    model: *na::new_identity::<na::Mat4<_>>(4).as_array(), 
+                    diffuse: colorsamp,
+                    lightmap: lmsamp 
+                };
+                let drawparams = glium::DrawParameters {
+                    depth_test: glium::DepthTest::IfLess,
+                    depth_write: true,
+                    backface_culling: cull,
+                    ..Default::default()
+                };
+                draw_batch(surface, map, indices, range, program, &uniforms, &drawparams);
+            },
         }
     }
 }
@@ -0,0 +1,73 @@
+#![feature(test)]
+
+extern crate test;
+extern crate nalgebra as na;
+extern crate vel0city_map;
+extern crate vel0city_base;
+
+use test::Bencher;
+use vel0city_base::assets;
+use vel0city_map::Map;
+use vel0city_map::cast::{
+    Ray,
+    Shape
+};
+
+const N_RAYS: usize = 4096;
+
+fn load_map() -> Option<Map> {
+    match assets::load_bin_asset("maps/test.bsp") {
+        Ok(data) => Some(vel0city_map::q3_import::import(&data).unwrap()),
+        Err(e) => {
+            println!("Can't load maps/test.bsp ({}), skipping benchmark", e);
+            None
+        }
+    }
+}
+
+/// A deterministic spread of player-sized traces around the origin.
+fn make_rays() -> Vec<Ray> {
+    let mut seed = 0x2545f491u32;
+    let mut next = || {
+        seed = seed.wrapping_mul(1664525).wrapping_add(1013904223);
+        (seed >> 8) as f32 / (1 << 24) as f32 * 2.0 - 1.0
+    };
+
+    (0..N_RAYS).map(|_| {
+        let orig = na::Pnt3::new(next() * 1024.0, next() * 256.0, next() * 1024.0);
+        let dir = na::Vec3::new(next() * 512.0, next() * 128.0, next() * 512.0);
+        Ray {
+            orig: orig,
+            dir: dir,
+            shape: Shape::Box(na::Vec3::new(8.0, 12.0, 8.0)),
+        }
+    }).collect()
+}
+
+#[bench]
+fn trace_sequential(b: &mut Bencher) {
+    if let Some(map) = load_map() {
+        let rays = make_rays();
+        b.iter(|| {
+            for ray in &rays {
+                test::black_box(map.cast_ray(ray));
+            }
+        });
+    }
+}
+
+#[bench]
+fn trace_batch(b: &mut Bencher) {
+    if let Some(map) = load_map() {
+        let rays = make_rays();
+        b.iter(|| test::black_box(map.cast_rays(&rays)));
+    }
+}
+
+#[bench]
+fn trace_parallel_4(b: &mut Bencher) {
+    if let Some(map) = load_map() {
+        let rays = make_rays();
+        b.iter(|| test::black_box(map.cast_rays_parallel(&rays, 4)));
+    }
+}
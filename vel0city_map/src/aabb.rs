@@ -0,0 +1,71 @@
+use na;
+use std::f32;
+use cast::Ray;
+
+/// An axis-aligned bounding box.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Aabb {
+    pub mins: na::Pnt3<f32>,
+    pub maxs: na::Pnt3<f32>,
+}
+impl Aabb {
+    pub fn new(mins: na::Pnt3<f32>, maxs: na::Pnt3<f32>) -> Aabb {
+        Aabb {
+            mins: mins,
+            maxs: maxs
+        }
+    }
+
+    /// A box that contains nothing at all. Merging anything into it gives back the other thing.
+    pub fn empty() -> Aabb {
+        Aabb {
+            mins: na::Pnt3::new(f32::INFINITY, f32::INFINITY, f32::INFINITY),
+            maxs: na::Pnt3::new(f32::NEG_INFINITY, f32::NEG_INFINITY, f32::NEG_INFINITY),
+        }
+    }
+
+    /// The box covered by sweeping the ray's shape from its origin to its end.
+    pub fn from_ray(ray: &Ray) -> Aabb {
+        let start = ray.orig;
+        let end = (ray.orig.to_vec() + ray.dir).to_pnt();
+        let halfextents = ray.shape.bounding_halfextents();
+        Aabb::new(
+            na::Pnt3::new(start.x.min(end.x), start.y.min(end.y), start.z.min(end.z)),
+            na::Pnt3::new(start.x.max(end.x), start.y.max(end.y), start.z.max(end.z)),
+            ).expand(&halfextents)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.mins.x > self.maxs.x || self.mins.y > self.maxs.y || self.mins.z > self.maxs.z
+    }
+
+    pub fn center(&self) -> na::Pnt3<f32> {
+        ((self.mins.to_vec() + self.maxs.to_vec()) * 0.5).to_pnt()
+    }
+
+    /// Grows the box so it contains `point`.
+    pub fn add_point(&mut self, point: &na::Pnt3<f32>) {
+        self.mins = na::Pnt3::new(self.mins.x.min(point.x), self.mins.y.min(point.y), self.mins.z.min(point.z));
+        self.maxs = na::Pnt3::new(self.maxs.x.max(point.x), self.maxs.y.max(point.y), self.maxs.z.max(point.z));
+    }
+
+    /// The smallest box containing both boxes.
+    pub fn merge(&self, other: &Aabb) -> Aabb {
+        Aabb::new(
+            na::Pnt3::new(self.mins.x.min(other.mins.x), self.mins.y.min(other.mins.y), self.mins.z.min(other.mins.z)),
+            na::Pnt3::new(self.maxs.x.max(other.maxs.x), self.maxs.y.max(other.maxs.y), self.maxs.z.max(other.maxs.z)),
+            )
+    }
+
+    /// Pushes every face of the box out by the matching component of `amount`.
+    pub fn expand(&self, amount: &na::Vec3<f32>) -> Aabb {
+        Aabb::new((self.mins.to_vec() - *amount).to_pnt(), (self.maxs.to_vec() + *amount).to_pnt())
+    }
+
+    /// Whether the boxes overlap. Boxes that only touch count as overlapping.
+    pub fn intersects(&self, other: &Aabb) -> bool {
+        self.mins.x <= other.maxs.x && self.maxs.x >= other.mins.x &&
+            self.mins.y <= other.maxs.y && self.maxs.y >= other.mins.y &&
+            self.mins.z <= other.maxs.z && self.maxs.z >= other.mins.z
+    }
+}
@@ -0,0 +1,152 @@
+//! Groups faces that are drawn the same way, so the renderer can draw them all at once.
+
+use MapFace;
+
+/// Faces sharing a texture and lightmap, whose indices are all next to each other.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Batch {
+    pub texture: i32,
+    pub lightmap: i32,
+    pub index_start: u32,
+    pub index_count: u32,
+    /// The faces in the batch, in the order their indices come in.
+    pub faces: Vec<u32>,
+}
+
+/// Rearranges `indices` so faces with the same texture and lightmap are next to each
+/// other, and points `faces` at where their indices ended up. Faces stay where they
+/// are in `faces`, so anything referring to them by index still works.
+pub fn batch_faces(faces: &mut [MapFace], indices: &mut Vec<u32>) -> Vec<Batch> {
+    let mut order: Vec<u32> = (0..faces.len() as u32).collect();
+    // The sort is stable, so faces in a batch keep their original order.
+    order.sort_by(|&a, &b| {
+        let (a, b) = (&faces[a as usize], &faces[b as usize]);
+        (a.texture, a.lightmap).cmp(&(b.texture, b.lightmap))
+    });
+
+    let mut batched = Vec::with_capacity(indices.len());
+    let mut batches: Vec<Batch> = vec![];
+    for faceidx in order {
+        let face = &mut faces[faceidx as usize];
+        let start = batched.len() as u32;
+        batched.extend(indices[face.index_start as usize..(face.index_start + face.index_count) as usize].iter().cloned());
+        face.index_start = start;
+
+        let same = batches.last().map_or(false, |batch| batch.texture == face.texture && batch.lightmap == face.lightmap);
+        if !same {
+            batches.push(Batch {
+                texture: face.texture,
+                lightmap: face.lightmap,
+                index_start: start,
+                index_count: 0,
+                faces: vec![],
+            });
+        }
+        let batch = batches.last_mut().unwrap();
+        batch.index_count += face.index_count;
+        batch.faces.push(faceidx);
+    }
+
+    *indices = batched;
+    batches
+}
+
+/// A batch with something visible in it this frame.
+#[derive(Clone, Debug, PartialEq)]
+pub struct VisibleBatch {
+    pub batch: usize,
+    /// The indices of just the visible faces. `None` if the whole batch is visible,
+    /// in which case its range of the full index list can be drawn as it is.
+    pub indices: Option<Vec<u32>>,
+}
+
+/// Works out what to draw of each batch when only `visible_faces` can be seen.
+/// Batches with nothing visible are left out.
+pub fn compact_visible(batches: &[Batch], faces: &[MapFace], indices: &[u32], visible_faces: &[u32]) -> Vec<VisibleBatch> {
+    let mut visible = vec![false; faces.len()];
+    for &faceidx in visible_faces {
+        if (faceidx as usize) < faces.len() {
+            visible[faceidx as usize] = true;
+        }
+    }
+
+    batches.iter().enumerate().filter_map(|(batchidx, batch)| {
+        let n_visible = batch.faces.iter().filter(|&&faceidx| visible[faceidx as usize]).count();
+        if n_visible == 0 {
+            return None;
+        }
+        if n_visible == batch.faces.len() {
+            return Some(VisibleBatch { batch: batchidx, indices: None });
+        }
+
+        let mut compacted = vec![];
+        for &faceidx in batch.faces.iter().filter(|&&faceidx| visible[faceidx as usize]) {
+            let face = &faces[faceidx as usize];
+            compacted.extend(indices[face.index_start as usize..(face.index_start + face.index_count) as usize].iter().cloned());
+        }
+        Some(VisibleBatch { batch: batchidx, indices: Some(compacted) })
+    }).collect()
+}
+
+#[cfg(test)]
+mod test {
+    use MapFace;
+    use super::{
+        batch_faces,
+        compact_visible,
+        Batch,
+        VisibleBatch
+    };
+
+    fn face(texture: i32, lightmap: i32, index_start: u32) -> MapFace {
+        MapFace { texture: texture, lightmap: lightmap, index_start: index_start, index_count: 3, material: None }
+    }
+
+    /// Four triangles, alternating between two textures. Each triangle's indices are
+    /// all the face's number, so it's easy to see where they went.
+    fn faces() -> (Vec<MapFace>, Vec<u32>) {
+        let faces = vec![face(1, 0, 0), face(0, 0, 3), face(1, 0, 6), face(0, 0, 9)];
+        let indices = vec![0, 0, 0, 1, 1, 1, 2, 2, 2, 3, 3, 3];
+        (faces, indices)
+    }
+
+    #[test]
+    fn merges_matching_faces() {
+        let (mut faces, mut indices) = faces();
+        let batches = batch_faces(&mut faces, &mut indices);
+
+        assert_eq!(batches, vec![
+            Batch { texture: 0, lightmap: 0, index_start: 0, index_count: 6, faces: vec![1, 3] },
+            Batch { texture: 1, lightmap: 0, index_start: 6, index_count: 6, faces: vec![0, 2] },
+        ]);
+        assert_eq!(indices, vec![1, 1, 1, 3, 3, 3, 0, 0, 0, 2, 2, 2]);
+        for (faceidx, face) in faces.iter().enumerate() {
+            let start = face.index_start as usize;
+            assert!(indices[start..start + 3].iter().all(|&i| i == faceidx as u32));
+        }
+    }
+
+    #[test]
+    fn lightmaps_split_batches() {
+        let mut faces = vec![face(0, 0, 0), face(0, 1, 3), face(0, 0, 6)];
+        let mut indices = vec![0, 0, 0, 1, 1, 1, 2, 2, 2];
+        let batches = batch_faces(&mut faces, &mut indices);
+        assert_eq!(batches.len(), 2);
+        assert_eq!(batches[0].faces, vec![0, 2]);
+        assert_eq!(batches[1].faces, vec![1]);
+    }
+
+    #[test]
+    fn compacts_partly_visible_batches() {
+        let (mut faces, mut indices) = faces();
+        let batches = batch_faces(&mut faces, &mut indices);
+
+        let visible = compact_visible(&batches, &faces, &indices, &[0, 2, 3]);
+        assert_eq!(visible, vec![
+            VisibleBatch { batch: 0, indices: Some(vec![3, 3, 3]) },
+            VisibleBatch { batch: 1, indices: None },
+        ]);
+
+        assert!(compact_visible(&batches, &faces, &indices, &[]).is_empty());
+    }
+}
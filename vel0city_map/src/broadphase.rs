@@ -0,0 +1,209 @@
+use aabb::Aabb;
+use std::cmp::Ordering;
+use {
+    Model,
+    Entity
+};
+
+/// A bounding volume hierarchy over the entities of a map, so a trace only has to test
+/// the brushes of entities it could actually touch.
+#[derive(Debug, PartialEq)]
+pub struct Broadphase {
+    nodes: Vec<Node>,
+    root: Option<usize>,
+}
+
+#[derive(Debug, PartialEq)]
+enum Node {
+    Branch {
+        bounds: Aabb,
+        left: usize,
+        right: usize,
+    },
+    Leaf {
+        bounds: Aabb,
+        entity: u32,
+    }
+}
+
+impl Broadphase {
+    pub fn new(models: &[Model], entities: &[Entity]) -> Broadphase {
+        let mut items: Vec<(Aabb, u32)> = entities.iter()
+            .enumerate()
+            .map(|(entityidx, entity)| (models[entity.model as usize].bounds, entityidx as u32))
+            .collect();
+
+        let mut nodes = vec![];
+        let root = if items.is_empty() {
+            None
+        } else {
+            Some(build(&mut nodes, &mut items))
+        };
+
+        Broadphase {
+            nodes: nodes,
+            root: root
+        }
+    }
+
+    /// Returns the indices of all entities whose bounds intersect `bounds`, in ascending order.
+    pub fn query(&self, bounds: &Aabb) -> Vec<u32> {
+        let mut found = vec![];
+        if let Some(root) = self.root {
+            self.query_recursive(root, bounds, &mut found);
+        }
+        found.sort();
+        found
+    }
+
+    fn query_recursive(&self, nodeidx: usize, bounds: &Aabb, found: &mut Vec<u32>) {
+        match self.nodes[nodeidx] {
+            Node::Branch { bounds: ref nodebounds, left, right } => {
+                if nodebounds.intersects(bounds) {
+                    self.query_recursive(left, bounds, found);
+                    self.query_recursive(right, bounds, found);
+                }
+            },
+            Node::Leaf { bounds: ref nodebounds, entity } => {
+                if nodebounds.intersects(bounds) {
+                    found.push(entity);
+                }
+            }
+        }
+    }
+}
+
+fn axis_value(aabb: &Aabb, axis: usize) -> f32 {
+    let center = aabb.center();
+    match axis {
+        0 => center.x,
+        1 => center.y,
+        _ => center.z,
+    }
+}
+
+/// Builds the subtree for `items`, returning the index of its root node.
+/// Splits at the median along the longest axis of the items' bounds.
+fn build(nodes: &mut Vec<Node>, items: &mut [(Aabb, u32)]) -> usize {
+    let bounds = items.iter().fold(Aabb::empty(), |acc, &(ref itembounds, _)| acc.merge(itembounds));
+
+    if items.len() == 1 {
+        nodes.push(Node::Leaf {
+            bounds: bounds,
+            entity: items[0].1
+        });
+        return nodes.len() - 1;
+    }
+
+    let extents = bounds.maxs.to_vec() - bounds.mins.to_vec();
+    let axis = if extents.x >= extents.y && extents.x >= extents.z {
+        0
+    } else if extents.y >= extents.z {
+        1
+    } else {
+        2
+    };
+
+    items.sort_by(|&(ref a, _), &(ref b, _)| {
+        axis_value(a, axis).partial_cmp(&axis_value(b, axis)).unwrap_or(Ordering::Equal)
+    });
+
+    let mid = items.len() / 2;
+    let (left_items, right_items) = items.split_at_mut(mid);
+    let left = build(nodes, left_items);
+    let right = build(nodes, right_items);
+
+    nodes.push(Node::Branch {
+        bounds: bounds,
+        left: left,
+        right: right
+    });
+    nodes.len() - 1
+}
+
+#[cfg(test)]
+mod test {
+    use na;
+    use aabb::Aabb;
+    use builder::{box_brush, MapBuilder, CONTENTS_SOLID};
+    use cast::{Ray, Shape};
+    use super::Broadphase;
+    use {
+        Model,
+        Entity,
+        EntityKind
+    };
+
+    fn cube(x: f32, y: f32, z: f32) -> Aabb {
+        Aabb::new(na::Pnt3::new(x, y, z), na::Pnt3::new(x + 16.0, y + 16.0, z + 16.0))
+    }
+
+    #[test]
+    fn query_matches_brute_force() {
+        let mut models = vec![Model { brush: 0, n_brushes: 0, bounds: Aabb::empty() }];
+        let mut entities = vec![];
+        for i in 0..37 {
+            let f = i as f32;
+            models.push(Model {
+                brush: 0,
+                n_brushes: 0,
+                bounds: cube((f * 37.0) % 256.0, (f * 91.0) % 128.0, f * 8.0),
+            });
+            entities.push(Entity { model: i + 1, kind: EntityKind::OutOfBounds });
+        }
+        let broadphase = Broadphase::new(&models, &entities);
+
+        for i in 0..64 {
+            let f = i as f32;
+            let query = cube((f * 13.0) % 256.0, (f * 29.0) % 128.0, (f * 7.0) % 300.0);
+            let expected: Vec<u32> = entities.iter()
+                .enumerate()
+                .filter(|&(_, e)| models[e.model as usize].bounds.intersects(&query))
+                .map(|(idx, _)| idx as u32)
+                .collect();
+            assert_eq!(broadphase.query(&query), expected);
+        }
+    }
+
+    #[test]
+    fn map_casts_match_brute_force() {
+        let mut builder = MapBuilder::new();
+        builder.add_box(na::Pnt3::new(-512.0, 0.0, -512.0), na::Pnt3::new(512.0, 16.0, 512.0));
+        for i in 0..24 {
+            let f = i as f32;
+            let mins = na::Pnt3::new((f * 83.0) % 768.0 - 384.0, -16.0 - (f * 11.0) % 96.0, (f * 59.0) % 768.0 - 384.0);
+            let kind = if i % 2 == 0 { EntityKind::OutOfBounds } else { EntityKind::Goal };
+            builder.add_entity(kind, vec![box_brush(mins, mins + na::Vec3::new(32.0, 16.0, 48.0), CONTENTS_SOLID)]);
+        }
+        let map = builder.build();
+
+        let shapes = [
+            Shape::Box(na::zero()),
+            Shape::Box(na::Vec3::new(8.0, 12.0, 8.0)),
+            Shape::Sphere(10.0),
+            Shape::Capsule { radius: 8.0, halfheight: 8.0 },
+        ];
+        for i in 0..200 {
+            let f = i as f32;
+            let ray = Ray {
+                orig: na::Pnt3::new((f * 37.0) % 900.0 - 450.0, -64.0 - (f * 7.0) % 64.0, (f * 53.0) % 900.0 - 450.0),
+                dir: na::Vec3::new((f * 17.0) % 400.0 - 200.0, (f * 5.0) % 160.0 - 40.0, (f * 29.0) % 400.0 - 200.0),
+                shape: shapes[i % shapes.len()],
+            };
+            let result = map.cast_ray(&ray);
+            let expected = map.cast_ray_brute_force(&ray);
+
+            assert_eq!(result.is_some(), expected.is_some());
+            if let (Some(result), Some(expected)) = (result, expected) {
+                assert_approx_eq!(result.toi, expected.toi);
+                assert_eq!(result.entity, expected.entity);
+            }
+        }
+    }
+
+    #[test]
+    fn empty_broadphase() {
+        let broadphase = Broadphase::new(&[], &[]);
+        assert!(broadphase.query(&cube(0.0, 0.0, 0.0)).is_empty());
+    }
+}
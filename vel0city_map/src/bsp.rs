@@ -1,6 +1,9 @@
 #![allow(dead_code, unused_variables)]
 
 use na;
+use aabb::Aabb;
+use winding::Winding;
+use vis::VisData;
 use cast::{
     Ray,
     CastResult,
@@ -10,6 +13,29 @@ use cast::{
 
 const EPS: f32 = 1.0/8.0;
 
+/// How close to a plane a point has to be to count as lying on it when building brush polygons.
+const CLIP_EPS: f32 = 0.01;
+
+pub const CONTENTS_SOLID: i32 = 0x1;
+pub const CONTENTS_LAVA: i32 = 0x8;
+pub const CONTENTS_SLIME: i32 = 0x10;
+pub const CONTENTS_WATER: i32 = 0x20;
+pub const CONTENTS_FOG: i32 = 0x40;
+pub const CONTENTS_PLAYERCLIP: i32 = 0x10000;
+pub const CONTENTS_TRIGGER: i32 = 0x40000000;
+
+/// Surface flag for ground without any friction.
+pub const SURF_SLICK: i32 = 0x2;
+/// Surface flag for faces that show the sky instead of their texture.
+pub const SURF_SKY: i32 = 0x4;
+pub const SURF_NODRAW: i32 = 0x80;
+pub const SURF_NOLIGHTMAP: i32 = 0x400;
+pub const SURF_NONSOLID: i32 = 0x4000;
+
+/// Brushes with any of these contents stop a trace. Triggers are included, since
+/// that's how we find out the player touched an entity.
+pub const MASK_PLAYERSOLID: i32 = CONTENTS_SOLID | CONTENTS_PLAYERCLIP | CONTENTS_TRIGGER;
+
 fn signcpy(n: f32, from: f32) -> f32 {
     if from >= 0.0 {
         n
@@ -25,7 +51,7 @@ pub enum PlaneTestResult {
     Span(CastResult)
 }
 
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, PartialEq)]
 pub struct Plane {
     pub norm: na::Vec3<f32>,
     pub dist: f32
@@ -36,14 +62,15 @@ impl Plane {
         (self.norm * self.dist).to_pnt()
     }
 
-    fn dist_to_point(&self, point: &na::Pnt3<f32>) -> f32 {
+    /// Signed distance from the plane to `point`, positive in front.
+    pub fn dist_to_point(&self, point: &na::Pnt3<f32>) -> f32 {
         na::dot(&self.norm, point.as_vec()) - self.dist
     }
 }
 
 pub type NodeIndex = i32;
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq)]
 pub struct InnerNode {
     pub plane: Plane,
     /// Subtree in the same direction as the normal.
@@ -54,29 +81,39 @@ pub struct InnerNode {
     pub neg: NodeIndex,
 }
 
-#[derive(Debug)]
+#[derive(Debug, PartialEq)]
 pub struct Leaf {
+    /// Visibility cluster, or -1 if the leaf is inside something solid.
+    pub cluster: i32,
+    pub area: i32,
+    pub bounds: Aabb,
+    pub leafface: i32,
+    pub n_leaffaces: i32,
     pub leafbrush: i32,
     pub n_leafbrushes: i32,
 }
 
-#[derive(Debug)]
+#[derive(Clone, Debug, PartialEq)]
 pub struct Brush {
     pub sides: Vec<BrushSide>
 }
 impl Brush {
+    /// The contents of all the sides put together.
+    pub fn contents(&self) -> i32 {
+        self.sides.iter().fold(0, |acc, side| acc | side.contents)
+    }
+
     pub fn cast_ray(&self, ray: &Ray, (start, end): (f32, f32)) -> Option<CastResult> {
+        if self.contents() & MASK_PLAYERSOLID == 0 {
+            return None;
+        }
+
         let mut sf = -1.0;
         let mut ef = 1.0;
         let mut norm = na::zero();
+        let mut flags = 0;
         for side in &self.sides {
-            if !(side.contents & 1 == 1) {
-                continue;
-            }
-
-            let pad = na::abs(&(ray.halfextents.x * side.plane.norm.x)) +
-                na::abs(&(ray.halfextents.y * side.plane.norm.y)) + 
-                na::abs(&(ray.halfextents.z * side.plane.norm.z));
+            let pad = ray.shape.plane_offset(&side.plane.norm);
 
             let startpos = (ray.orig.to_vec()).to_pnt();
             let endpos = (ray.orig.to_vec() + ray.dir).to_pnt();
@@ -94,6 +131,7 @@ impl Brush {
                 if frac > sf {
                     sf = frac;
                     norm = side.plane.norm;
+                    flags = side.flags;
                 }
             } else {
                 let frac = (d1 + EPS) / (d1 - d2);
@@ -107,26 +145,57 @@ impl Brush {
             return Some(CastResult {
                 toi: sf,
                 norm: norm,
+                flags: flags,
                 entity: None, 
             });
         }
         None
     }
+
+    /// Builds the polygon of every side, by clipping a huge square on the side's plane
+    /// against all the other sides. Sides that end up with no area are left out.
+    pub fn polygons(&self) -> Vec<Winding> {
+        self.sides.iter().enumerate().filter_map(|(sideidx, side)| {
+            let mut winding = Some(Winding::from_plane(&side.plane));
+            for (otheridx, other) in self.sides.iter().enumerate() {
+                if otheridx == sideidx {
+                    continue;
+                }
+                winding = winding.and_then(|w| w.clip(&other.plane, CLIP_EPS));
+            }
+            winding
+        }).collect()
+    }
+
+    /// The bounds of the brush's polygons. Empty if the brush doesn't enclose anything.
+    pub fn bounds(&self) -> Aabb {
+        let mut bounds = Aabb::empty();
+        for polygon in self.polygons() {
+            for point in &polygon.points {
+                bounds.add_point(point);
+            }
+        }
+        bounds
+    }
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq)]
 pub struct BrushSide {
     pub plane: Plane,
     pub flags: i32,
     pub contents: i32,
 }
 
-#[derive(Debug)]
+#[derive(Debug, PartialEq)]
 pub struct Tree {
     pub inodes: Vec<InnerNode>,
     pub leaves: Vec<Leaf>,
     pub brushes: Vec<Brush>,
     pub leafbrushes: Vec<u32>,
+    /// Indices into the graphics faces, for drawing only what's in visible leaves.
+    pub leaffaces: Vec<u32>,
+    /// Which clusters can see each other. Without it, everything is visible.
+    pub visdata: Option<VisData>,
 }
 impl Tree {
     /// Looks up a leaf by (negative) NodeIndex.
@@ -134,6 +203,25 @@ impl Tree {
         &self.leaves[(-nodeidx - 1) as usize]
     }
 
+    /// The index of the leaf containing `point`.
+    pub fn find_leaf(&self, point: &na::Pnt3<f32>) -> usize {
+        let mut nodeidx = 0;
+        while nodeidx >= 0 {
+            let node = &self.inodes[nodeidx as usize];
+            nodeidx = if node.plane.dist_to_point(point) >= 0.0 {
+                node.pos
+            } else {
+                node.neg
+            };
+        }
+        (-nodeidx - 1) as usize
+    }
+
+    /// The bounds of all the brushes in the tree, entity brushes included.
+    pub fn bounds(&self) -> Aabb {
+        self.brushes.iter().fold(Aabb::empty(), |acc, brush| acc.merge(&brush.bounds()))
+    }
+
     pub fn cast_ray(&self, ray: &Ray) -> Option<CastResult> {
         self.cast_ray_recursive(ray, 0, (0.0, 1.0), (ray.orig, (ray.orig.to_vec() + ray.dir).to_pnt()))
     }
@@ -166,9 +254,7 @@ impl Tree {
         let d1 = plane.dist_to_point(&startpos);
         let d2 = plane.dist_to_point(&endpos);
 
-        let pad = na::abs(&(ray.halfextents.x * plane.norm.x)) +
-            na::abs(&(ray.halfextents.y * plane.norm.y)) + 
-            na::abs(&(ray.halfextents.z * plane.norm.z)) + 0.5 * EPS;
+        let pad = ray.shape.plane_offset(&plane.norm) + 0.5 * EPS;
 
 
         // How does the ray interact with this plane?
@@ -210,8 +296,9 @@ impl Tree {
             let (nearbounds, farbounds) =
                 ((start, ns), (fs, end));
 
-            let nmid = (startpos.to_vec() + ray.dir * ns).to_pnt();
-            let fmid = (startpos.to_vec() + ray.dir * fs).to_pnt();
+            // ns and fs are fractions of the whole ray, not of this piece of it.
+            let nmid = (ray.orig.to_vec() + ray.dir * ns).to_pnt();
+            let fmid = (ray.orig.to_vec() + ray.dir * fs).to_pnt();
 
             combine_results(self.cast_ray_recursive(ray, near, nearbounds, (startpos, nmid)), self.cast_ray_recursive(ray, far, farbounds, (fmid, endpos)))
         }
@@ -221,18 +308,23 @@ impl Tree {
 
 #[cfg(test)]
 pub mod test {
-    use na::{
-        self,
-        ApproxEq
+    use na;
+    use aabb::Aabb;
+    use builder::{box_brush, MapBuilder};
+    use cast::{
+        CastResult,
+        Ray,
+        Shape,
     };
     use super::{
-        test_tree,
+        Brush,
+        InnerNode,
+        Leaf,
         Plane,
-        PlaneTestResult
-
-    };
-    use super::cast::{
-        Ray,
+        Tree,
+        CONTENTS_PLAYERCLIP,
+        CONTENTS_SOLID,
+        EPS
     };
 
     macro_rules! assert_castresult {
@@ -255,61 +347,129 @@ pub mod test {
         }
     }
 
+    /// A floor whose top is at y = 0, plus a wall at x = 64.
+    fn test_tree() -> super::Tree {
+        let mut builder = MapBuilder::new();
+        builder.add_box(na::Pnt3::new(-256.0, 0.0, -256.0), na::Pnt3::new(256.0, 16.0, 256.0));
+        builder.add_box(na::Pnt3::new(64.0, -128.0, -256.0), na::Pnt3::new(80.0, 0.0, 256.0));
+        builder.build().bsp
+    }
+
     #[test]
-    fn plane_raytest() {
-        let plane = Plane {
-            norm: na::Vec3::new(1.0, 0.0, 0.0),
-            dist: 0.0,
-        };
+    fn ray_hits_floor() {
+        let tree = test_tree();
+        let result = tree.cast_ray(&Ray {
+            orig: na::Pnt3::new(0.0, -32.0, 0.0),
+            dir: na::Vec3::new(0.0, 64.0, 0.0),
+            shape: Shape::Box(na::zero()),
+        });
+        assert_castresult!(result, (32.0 - EPS) / 64.0, na::Vec3::new(0.0, -1.0, 0.0));
+    }
 
-        let result = plane.test_ray(&Ray {
-            orig: na::Pnt3::new(-0.5, 0.0, 0.0),
-            dir: na::Vec3::new(1.0, 0.0, 0.0),
-            halfextents: na::zero(),
+    #[test]
+    fn box_hits_floor() {
+        let tree = test_tree();
+        let result = tree.cast_ray(&Ray {
+            orig: na::Pnt3::new(0.0, -32.0, 0.0),
+            dir: na::Vec3::new(0.0, 64.0, 0.0),
+            shape: Shape::Box(na::Vec3::new(8.0, 12.0, 8.0)),
         });
+        assert_castresult!(result, (20.0 - EPS) / 64.0, na::Vec3::new(0.0, -1.0, 0.0));
+    }
 
-        match result {
-            PlaneTestResult::Span(c) => {
-                assert_approx_eq!(c.toi, 0.5);
-                assert_approx_eq!(c.norm, plane.norm);
-            },
-            x => panic!("{:?}", x)
-        };
+    /// Casts a ray from x = 0 to x = 128 at height `z`, past a brush at x = 64..80.
+    fn cast_past(brush: &Brush, z: f32) -> Option<CastResult> {
+        brush.cast_ray(&Ray {
+            orig: na::Pnt3::new(0.0, 0.0, z),
+            dir: na::Vec3::new(128.0, 0.0, 0.0),
+            shape: Shape::Box(na::zero()),
+        }, (0.0, 1.0))
     }
 
     #[test]
-    fn plane_cubetest() {
-        let plane = Plane {
-            norm: na::Vec3::new(1.0, 0.0, 0.0),
-            dist: 16.0,
-        };
+    fn whole_brush_contents_count() {
+        let (mins, maxs) = (na::Pnt3::new(64.0, -16.0, -16.0), na::Pnt3::new(80.0, 16.0, 16.0));
+        // Player clip isn't solid, but it has to stop players all the same.
+        let clip = box_brush(mins, maxs, CONTENTS_PLAYERCLIP);
+        assert_castresult!(cast_past(&clip, 0.0), (64.0 - EPS) / 128.0, na::Vec3::new(-1.0, 0.0, 0.0));
+        // Water doesn't.
+        let water = box_brush(mins, maxs, 0x20);
+        assert!(cast_past(&water, 0.0).is_none());
+
+        // A side without contents of its own still bounds the brush. Sides like
+        // this used to be skipped, which made the brush reach up forever.
+        let mut brush = box_brush(mins, maxs, CONTENTS_SOLID);
+        brush.sides[4].contents = 0;
+        assert!(cast_past(&brush, 32.0).is_none());
+        assert_castresult!(cast_past(&brush, 0.0), (64.0 - EPS) / 128.0, na::Vec3::new(-1.0, 0.0, 0.0));
+    }
 
-        let result = plane.test_ray(&Ray {
-            orig: na::Pnt3::new(16.1, 0.0, 0.0),
-            dir: na::Vec3::new(0.0, 0.0, 1.0),
-            halfextents: na::Vec3::new(1.0, 1.0, 1.0),
+    #[test]
+    fn shapes_hit_wall() {
+        let tree = test_tree();
+        let cast = |shape| tree.cast_ray(&Ray {
+            orig: na::Pnt3::new(0.0, -64.0, 0.0),
+            dir: na::Vec3::new(64.0, 0.0, 0.0),
+            shape: shape,
         });
+        assert_castresult!(cast(Shape::Sphere(16.0)), (48.0 - EPS) / 64.0, na::Vec3::new(-1.0, 0.0, 0.0));
+        assert_castresult!(cast(Shape::Capsule { radius: 8.0, halfheight: 16.0 }), (56.0 - EPS) / 64.0, na::Vec3::new(-1.0, 0.0, 0.0));
+    }
 
-        match result {
-            PlaneTestResult::Span(c) => {
-                assert_approx_eq!(c.toi, 0.5);
-                assert_approx_eq!(c.norm, plane.norm);
-            },
-            x => panic!("{:?}", x)
+    /// Three planes across x, at 20, 60 and 70, with a brush between the last two.
+    fn split_tree() -> Tree {
+        let split = |x: f32, pos, neg| InnerNode {
+            plane: Plane { norm: na::Vec3::new(1.0, 0.0, 0.0), dist: x },
+            pos: pos,
+            neg: neg,
+        };
+        let leaf = |n_leafbrushes| Leaf {
+            cluster: 0,
+            area: 0,
+            bounds: Aabb::empty(),
+            leafface: 0,
+            n_leaffaces: 0,
+            leafbrush: 0,
+            n_leafbrushes: n_leafbrushes,
         };
+        Tree {
+            inodes: vec![split(20.0, 1, -1), split(60.0, 2, -2), split(70.0, -3, -4)],
+            leaves: vec![leaf(0), leaf(0), leaf(0), leaf(1)],
+            brushes: vec![box_brush(na::Pnt3::new(64.0, -16.0, -16.0), na::Pnt3::new(68.0, 16.0, 16.0), CONTENTS_SOLID)],
+            leafbrushes: vec![0],
+            leaffaces: vec![],
+            visdata: None,
+        }
+    }
+
+    #[test]
+    fn ray_crosses_several_splits() {
+        // Once past the first split, the pieces of the ray have to be measured from
+        // its origin. Measuring from the start of the piece puts the rest of the ray
+        // past x = 70, and the brush before it gets skipped.
+        let result = split_tree().cast_ray(&Ray {
+            orig: na::Pnt3::new(0.0, 0.0, 0.0),
+            dir: na::Vec3::new(100.0, 0.0, 0.0),
+            shape: Shape::Box(na::zero()),
+        });
+        assert_castresult!(result, (64.0 - EPS) / 100.0, na::Vec3::new(-1.0, 0.0, 0.0));
+    }
 
-        let result = plane.test_ray(&Ray {
-            orig: na::Pnt3::new(0.1, 0.0, 0.0),
-            dir: na::Vec3::new(1.0, 0.0, 0.0),
-            halfextents: na::Vec3::new(0.5, 0.0, 0.0),
+    #[test]
+    fn ray_misses() {
+        let tree = test_tree();
+        let result = tree.cast_ray(&Ray {
+            orig: na::Pnt3::new(0.0, -32.0, 0.0),
+            dir: na::Vec3::new(0.0, -64.0, 0.0),
+            shape: Shape::Box(na::Vec3::new(8.0, 12.0, 8.0)),
         });
+        assert!(result.is_none());
 
-        match result {
-            PlaneTestResult::Span(c) => {
-                assert_approx_eq!(c.toi, 0.0);
-                assert_approx_eq!(c.norm, plane.norm);
-            },
-            x => panic!("{:?}", x)
-        };
+        let result = tree.cast_ray(&Ray {
+            orig: na::Pnt3::new(0.0, -32.0, 0.0),
+            dir: na::Vec3::new(0.0, 0.0, 128.0),
+            shape: Shape::Box(na::Vec3::new(8.0, 12.0, 8.0)),
+        });
+        assert!(result.is_none());
     }
 }
@@ -0,0 +1,419 @@
+//! Builds maps out of convex brushes instead of loading them from a `.bsp`,
+//! for tests and procedurally generated levels.
+
+use na;
+use aabb::Aabb;
+use broadphase::Broadphase;
+use bsp::{
+    self,
+    Brush,
+    BrushSide,
+    Plane,
+    NodeIndex
+};
+use {
+    Map,
+    Model,
+    Entity,
+    EntityKind
+};
+
+pub use bsp::CONTENTS_SOLID;
+
+/// Points closer than this to a split plane count as lying on it.
+const ON_EPS: f32 = 0.01;
+
+/// Past this depth, whatever brushes are left all end up in the same leaf.
+const MAX_DEPTH: u32 = 64;
+
+/// A brush bounded by `planes`. Whatever is behind all of them is inside the brush.
+pub fn brush_from_planes(planes: &[Plane], contents: i32) -> Brush {
+    Brush {
+        sides: planes.iter().map(|plane| BrushSide {
+            plane: plane.clone(),
+            flags: 0,
+            contents: contents,
+        }).collect()
+    }
+}
+
+/// An axis-aligned box brush.
+pub fn box_brush(mins: na::Pnt3<f32>, maxs: na::Pnt3<f32>, contents: i32) -> Brush {
+    brush_from_planes(&[
+        Plane { norm: na::Vec3::new(1.0, 0.0, 0.0), dist: maxs.x },
+        Plane { norm: na::Vec3::new(-1.0, 0.0, 0.0), dist: -mins.x },
+        Plane { norm: na::Vec3::new(0.0, 1.0, 0.0), dist: maxs.y },
+        Plane { norm: na::Vec3::new(0.0, -1.0, 0.0), dist: -mins.y },
+        Plane { norm: na::Vec3::new(0.0, 0.0, 1.0), dist: maxs.z },
+        Plane { norm: na::Vec3::new(0.0, 0.0, -1.0), dist: -mins.z },
+    ], contents)
+}
+
+/// Collects world and entity brushes and compiles them into a `Map`.
+///
+/// World brushes go into the BSP tree. Every entity gets a model of its own,
+/// which is tested separately like the brush models of an imported map.
+pub struct MapBuilder {
+    world: Vec<Brush>,
+    entities: Vec<(EntityKind, Vec<Brush>)>,
+}
+
+impl MapBuilder {
+    pub fn new() -> MapBuilder {
+        MapBuilder {
+            world: vec![],
+            entities: vec![],
+        }
+    }
+
+    pub fn add_brush(&mut self, brush: Brush) -> &mut MapBuilder {
+        self.world.push(brush);
+        self
+    }
+
+    /// Adds a solid axis-aligned box to the world.
+    pub fn add_box(&mut self, mins: na::Pnt3<f32>, maxs: na::Pnt3<f32>) -> &mut MapBuilder {
+        self.add_brush(box_brush(mins, maxs, CONTENTS_SOLID))
+    }
+
+    pub fn add_entity(&mut self, kind: EntityKind, brushes: Vec<Brush>) -> &mut MapBuilder {
+        self.entities.push((kind, brushes));
+        self
+    }
+
+    pub fn build(&self) -> Map {
+        let mut brushes = self.world.clone();
+        let mut models = vec![Model {
+            brush: 0,
+            n_brushes: self.world.len() as u32,
+            bounds: brush_bounds(&self.world),
+        }];
+        let mut entities = vec![];
+
+        for &(kind, ref entbrushes) in &self.entities {
+            models.push(Model {
+                brush: brushes.len() as u32,
+                n_brushes: entbrushes.len() as u32,
+                bounds: brush_bounds(entbrushes),
+            });
+            entities.push(Entity {
+                model: (models.len() - 1) as u32,
+                kind: kind
+            });
+            brushes.extend(entbrushes.iter().cloned());
+        }
+
+        let (inodes, leaves, leafbrushes) = {
+            let mut treebuilder = TreeBuilder {
+                bounds: leaf_bounds(&brush_bounds(&self.world)),
+                points: self.world.iter().map(|brush| {
+                    brush.polygons().into_iter().flat_map(|w| w.points.into_iter()).collect()
+                }).collect(),
+                brushes: &self.world,
+                inodes: vec![],
+                leaves: vec![],
+                leafbrushes: vec![],
+            };
+            treebuilder.build();
+            (treebuilder.inodes, treebuilder.leaves, treebuilder.leafbrushes)
+        };
+
+        let broadphase = Broadphase::new(&models, &entities);
+        Map {
+            bsp: bsp::Tree {
+                inodes: inodes,
+                leaves: leaves,
+                brushes: brushes,
+                leafbrushes: leafbrushes,
+                leaffaces: vec![],
+                visdata: None,
+            },
+            models: models,
+            entities: entities,
+            broadphase: broadphase,
+        }
+    }
+}
+
+fn brush_bounds(brushes: &[Brush]) -> Aabb {
+    brushes.iter().fold(Aabb::empty(), |acc, brush| acc.merge(&brush.bounds()))
+}
+
+/// Rounds `bounds` out to whole units, the way leaf bounds are stored in a `.bsp`.
+fn leaf_bounds(bounds: &Aabb) -> Aabb {
+    if bounds.is_empty() {
+        return Aabb::new(na::Pnt3::new(0.0, 0.0, 0.0), na::Pnt3::new(0.0, 0.0, 0.0));
+    }
+    Aabb::new(
+        na::Pnt3::new(bounds.mins.x.floor(), bounds.mins.y.floor(), bounds.mins.z.floor()),
+        na::Pnt3::new(bounds.maxs.x.ceil(), bounds.maxs.y.ceil(), bounds.maxs.z.ceil()),
+        )
+}
+
+/// The part of `bounds` on one side of `plane`. Only axial planes cut the box down,
+/// anything else leaves it as it is.
+fn split_bounds(bounds: &Aabb, plane: &Plane, front: bool) -> Aabb {
+    let norm = [plane.norm.x, plane.norm.y, plane.norm.z];
+    let mut mins = [bounds.mins.x, bounds.mins.y, bounds.mins.z];
+    let mut maxs = [bounds.maxs.x, bounds.maxs.y, bounds.maxs.z];
+    for axis in 0..3 {
+        let n = norm[axis];
+        if n != 1.0 && n != -1.0 {
+            continue;
+        }
+        // Along this axis, the front of the plane is everything past n * dist.
+        let at = n * plane.dist;
+        if (n > 0.0) == front {
+            mins[axis] = mins[axis].max(at).min(maxs[axis]);
+        } else {
+            maxs[axis] = maxs[axis].min(at).max(mins[axis]);
+        }
+    }
+    leaf_bounds(&Aabb::new(
+        na::Pnt3::new(mins[0], mins[1], mins[2]),
+        na::Pnt3::new(maxs[0], maxs[1], maxs[2]),
+        ))
+}
+
+enum Side {
+    Front,
+    Back,
+    Both
+}
+
+struct TreeBuilder<'a> {
+    brushes: &'a [Brush],
+    /// Bounds of all the world brushes. Leaves get the part of this their node's
+    /// axial planes cut out for them.
+    bounds: Aabb,
+    /// The corners of each brush, used to sort brushes against split planes.
+    points: Vec<Vec<na::Pnt3<f32>>>,
+
+    inodes: Vec<bsp::InnerNode>,
+    leaves: Vec<bsp::Leaf>,
+    leafbrushes: Vec<u32>,
+}
+
+impl<'a> TreeBuilder<'a> {
+    fn build(&mut self) {
+        let all = (0..self.brushes.len() as u32).collect();
+        let bounds = self.bounds;
+        let root = self.build_node(all, bounds, 0);
+
+        // Traces always start at inner node 0, so there has to be one even if
+        // everything fits in a single leaf.
+        if root < 0 {
+            self.inodes.push(bsp::InnerNode {
+                plane: Plane { norm: na::Vec3::new(0.0, 1.0, 0.0), dist: 0.0 },
+                pos: root,
+                neg: root,
+            });
+        }
+    }
+
+    fn classify(&self, brushidx: u32, plane: &Plane) -> Side {
+        let mut front = false;
+        let mut back = false;
+        for point in &self.points[brushidx as usize] {
+            let d = plane.dist_to_point(point);
+            if d > ON_EPS {
+                front = true;
+            } else if d < -ON_EPS {
+                back = true;
+            }
+        }
+        match (front, back) {
+            (true, true) => Side::Both,
+            (false, true) => Side::Back,
+            _ => Side::Front,
+        }
+    }
+
+    /// Picks the brush side that best divides `brushidxs`: it has to leave fewer brushes
+    /// on each side than we started with, and should cut through as few brushes as possible.
+    fn choose_split(&self, brushidxs: &[u32]) -> Option<Plane> {
+        let n = brushidxs.len();
+        let mut best: Option<(usize, &Plane)> = None;
+
+        for &brushidx in brushidxs {
+            for side in &self.brushes[brushidx as usize].sides {
+                let (mut front, mut back, mut both) = (0, 0, 0);
+                for &other in brushidxs {
+                    match self.classify(other, &side.plane) {
+                        Side::Front => front += 1,
+                        Side::Back => back += 1,
+                        Side::Both => both += 1,
+                    }
+                }
+                if front + both == n || back + both == n {
+                    continue;
+                }
+
+                let score = both * 4 + if front > back { front - back } else { back - front };
+                if best.map_or(true, |(best_score, _)| score < best_score) {
+                    best = Some((score, &side.plane));
+                }
+            }
+        }
+
+        best.map(|(_, plane)| plane.clone())
+    }
+
+    fn build_node(&mut self, brushidxs: Vec<u32>, bounds: Aabb, depth: u32) -> NodeIndex {
+        let split = if depth < MAX_DEPTH {
+            self.choose_split(&brushidxs)
+        } else {
+            None
+        };
+
+        let plane = match split {
+            Some(plane) => plane,
+            None => return self.make_leaf(&brushidxs, bounds),
+        };
+
+        let mut front = vec![];
+        let mut back = vec![];
+        for &brushidx in &brushidxs {
+            match self.classify(brushidx, &plane) {
+                Side::Front => front.push(brushidx),
+                Side::Back => back.push(brushidx),
+                Side::Both => {
+                    front.push(brushidx);
+                    back.push(brushidx);
+                }
+            }
+        }
+
+        let frontbounds = split_bounds(&bounds, &plane, true);
+        let backbounds = split_bounds(&bounds, &plane, false);
+        let nodeidx = self.inodes.len();
+        self.inodes.push(bsp::InnerNode {
+            plane: plane,
+            pos: 0,
+            neg: 0,
+        });
+        let pos = self.build_node(front, frontbounds, depth + 1);
+        let neg = self.build_node(back, backbounds, depth + 1);
+        self.inodes[nodeidx].pos = pos;
+        self.inodes[nodeidx].neg = neg;
+
+        nodeidx as NodeIndex
+    }
+
+    fn make_leaf(&mut self, brushidxs: &[u32], bounds: Aabb) -> NodeIndex {
+        // There's no visdata, so every leaf can go in the same cluster.
+        self.leaves.push(bsp::Leaf {
+            cluster: 0,
+            area: 0,
+            bounds: bounds,
+            leafface: 0,
+            n_leaffaces: 0,
+            leafbrush: self.leafbrushes.len() as i32,
+            n_leafbrushes: brushidxs.len() as i32,
+        });
+        self.leafbrushes.extend(brushidxs.iter().cloned());
+
+        // Leaf k is stored as -(k + 1).
+        -(self.leaves.len() as NodeIndex)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use na;
+    use cast::{
+        Ray,
+        Shape
+    };
+    use super::{
+        MapBuilder,
+        box_brush,
+        CONTENTS_SOLID
+    };
+    use EntityKind;
+
+    fn staircase() -> MapBuilder {
+        let mut builder = MapBuilder::new();
+        for i in 0..8 {
+            let f = i as f32;
+            builder.add_box(na::Pnt3::new(f * 64.0, -f * 16.0, -128.0), na::Pnt3::new(f * 64.0 + 64.0, 64.0, 128.0));
+        }
+        builder
+    }
+
+    #[test]
+    fn tree_is_well_formed() {
+        let map = staircase().build();
+        let tree = &map.bsp;
+
+        assert!(tree.inodes.len() > 0);
+        assert!(tree.leaves.len() > 1);
+        for node in &tree.inodes {
+            for &child in &[node.pos, node.neg] {
+                if child < 0 {
+                    assert!(((-child - 1) as usize) < tree.leaves.len());
+                } else {
+                    assert!((child as usize) < tree.inodes.len());
+                }
+            }
+        }
+        for leaf in &tree.leaves {
+            assert!((leaf.leafbrush + leaf.n_leafbrushes) as usize <= tree.leafbrushes.len());
+        }
+        for &leafbrush in &tree.leafbrushes {
+            assert!((leafbrush as usize) < map.models[0].n_brushes as usize);
+        }
+    }
+
+    #[test]
+    fn empty_world_has_a_root() {
+        let map = MapBuilder::new().build();
+        assert_eq!(map.bsp.inodes.len(), 1);
+        assert!(map.bsp.cast_ray(&Ray {
+            orig: na::orig(),
+            dir: na::Vec3::new(0.0, 100.0, 0.0),
+            shape: Shape::Box(na::zero()),
+        }).is_none());
+    }
+
+    #[test]
+    fn tree_matches_brushes() {
+        let map = staircase().build();
+
+        for i in 0..40 {
+            let f = i as f32;
+            let ray = Ray {
+                orig: na::Pnt3::new(-32.0 + f * 13.0, -160.0 + f * 3.0, -64.0 + f * 2.0),
+                dir: na::Vec3::new(100.0 - f * 5.0, 200.0, 30.0 - f),
+                shape: Shape::Box(na::Vec3::new(8.0, 12.0, 8.0)),
+            };
+            let expected = map.bsp.brushes.iter()
+                .map(|brush| brush.cast_ray(&ray, (0.0, 1.0)))
+                .fold(None, |best, result| ::cast::combine_results(best, result));
+            let result = map.bsp.cast_ray(&ray);
+
+            assert_eq!(result.is_some(), expected.is_some());
+            if let (Some(result), Some(expected)) = (result, expected) {
+                assert_approx_eq!(result.toi, expected.toi);
+            }
+        }
+    }
+
+    #[test]
+    fn entity_brushes_report_their_entity() {
+        let mut builder = MapBuilder::new();
+        builder.add_box(na::Pnt3::new(-256.0, 0.0, -256.0), na::Pnt3::new(256.0, 16.0, 256.0));
+        builder.add_entity(EntityKind::Goal, vec![
+            box_brush(na::Pnt3::new(64.0, -64.0, -64.0), na::Pnt3::new(128.0, 0.0, 64.0), CONTENTS_SOLID)
+        ]);
+        let map = builder.build();
+
+        let result = map.cast_ray(&Ray {
+            orig: na::Pnt3::new(0.0, -32.0, 0.0),
+            dir: na::Vec3::new(128.0, 0.0, 0.0),
+            shape: Shape::Box(na::Vec3::new(8.0, 12.0, 8.0)),
+        }).unwrap();
+        assert_eq!(result.entity, Some(0));
+        assert_approx_eq!(result.norm, na::Vec3::new(-1.0, 0.0, 0.0));
+    }
+}
@@ -0,0 +1,175 @@
+//! Reading and writing the entity lump: a list of `{ "key" "value" ... }` blocks.
+
+use na;
+use std::f32::consts::PI;
+
+/// One block of the entity lump. Keys keep the order they were written in.
+#[derive(Clone, Debug, PartialEq)]
+pub struct EntityDef {
+    pub pairs: Vec<(String, String)>
+}
+
+impl EntityDef {
+    pub fn new() -> EntityDef {
+        EntityDef { pairs: vec![] }
+    }
+
+    pub fn with(mut self, key: &str, value: &str) -> EntityDef {
+        self.pairs.push((key.to_string(), value.to_string()));
+        self
+    }
+
+    /// The value of the first pair with the given key.
+    pub fn get(&self, key: &str) -> Option<&str> {
+        self.pairs.iter()
+            .find(|&&(ref k, _)| k == key)
+            .map(|&(_, ref v)| &v[..])
+    }
+
+    /// The number of the brush model this entity uses, if it has one.
+    pub fn model(&self) -> Option<u32> {
+        self.get("model").and_then(|model| {
+            if model.starts_with("*") {
+                model[1..].parse().ok()
+            } else {
+                None
+            }
+        })
+    }
+}
+
+/// Somewhere players can start.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Spawn {
+    pub pos: na::Pnt3<f32>,
+    /// Which way to face, as the yaw of a player's `eyeang`.
+    pub yaw: f32,
+}
+impl Spawn {
+    /// For maps that don't say where to start.
+    pub fn origin() -> Spawn {
+        Spawn { pos: na::Pnt3::new(0.0, 0.0, 0.0), yaw: 0.0 }
+    }
+}
+
+/// The `info_player_start`s and `info_player_deathmatch`es, in the order they come
+/// in. Their origins and angles are in Quake's axes, where an angle of 0 faces +x.
+pub fn find_spawns(defs: &[EntityDef]) -> Vec<Spawn> {
+    defs.iter()
+        .filter(|def| match def.get("classname") {
+            Some("info_player_start") | Some("info_player_deathmatch") => true,
+            _ => false,
+        })
+        .filter_map(|def| {
+            let origin: Vec<f32> = match def.get("origin") {
+                Some(origin) => origin.split_whitespace().filter_map(|v| v.parse().ok()).collect(),
+                None => return None,
+            };
+            if origin.len() != 3 {
+                return None;
+            }
+            let angle: f32 = def.get("angle").and_then(|a| a.parse().ok()).unwrap_or(0.0);
+            let yaw = (-angle * PI / 180.0 - PI / 2.0) % (2.0 * PI);
+            Some(Spawn {
+                pos: na::Pnt3::new(origin[0], -origin[2], origin[1]),
+                yaw: if yaw < 0.0 { yaw + 2.0 * PI } else { yaw },
+            })
+        })
+        .collect()
+}
+
+/// Parses an entity lump. Anything that doesn't look like a quoted string
+/// or a brace is skipped, as is a key without a value.
+pub fn parse(lump: &str) -> Vec<EntityDef> {
+    let mut defs = vec![];
+    let mut current: Option<EntityDef> = None;
+    let mut key: Option<String> = None;
+
+    let mut chars = lump.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '{' => {
+                current = Some(EntityDef::new());
+                key = None;
+            },
+            '}' => {
+                if let Some(def) = current.take() {
+                    defs.push(def);
+                }
+                key = None;
+            },
+            '"' => {
+                let s: String = chars.by_ref().take_while(|&c| c != '"').collect();
+                if let Some(ref mut def) = current {
+                    match key.take() {
+                        Some(k) => def.pairs.push((k, s)),
+                        None => key = Some(s),
+                    }
+                }
+            },
+            _ => ()
+        }
+    }
+    defs
+}
+
+/// Writes entities out in the same format `parse` reads.
+pub fn write(defs: &[EntityDef]) -> String {
+    let mut out = String::new();
+    for def in defs {
+        out.push_str("{\n");
+        for &(ref k, ref v) in &def.pairs {
+            out.push_str(&format!("\"{}\" \"{}\"\n", k, v));
+        }
+        out.push_str("}\n");
+    }
+    out
+}
+
+#[cfg(test)]
+mod test {
+    use na;
+    use std::f32::consts::PI;
+    use super::{
+        find_spawns,
+        parse,
+        write,
+        EntityDef
+    };
+
+    #[test]
+    fn parse_lump() {
+        let lump = "{\n\"classname\" \"worldspawn\"\n\"message\" \"a { tricky } map\"\n}\n\
+                    {\n\"classname\" \"trigger_hurt\"\n\"model\" \"*3\"\n}\n\0";
+        let defs = parse(lump);
+        assert_eq!(defs.len(), 2);
+        assert_eq!(defs[0].get("classname"), Some("worldspawn"));
+        assert_eq!(defs[0].get("message"), Some("a { tricky } map"));
+        assert_eq!(defs[0].model(), None);
+        assert_eq!(defs[1].model(), Some(3));
+    }
+
+    #[test]
+    fn write_roundtrip() {
+        let defs = vec![
+            EntityDef::new().with("classname", "worldspawn"),
+            EntityDef::new().with("classname", "info_player_start").with("origin", "0 0 64"),
+        ];
+        assert_eq!(parse(&write(&defs)), defs);
+    }
+
+    #[test]
+    fn spawns() {
+        let defs = vec![
+            EntityDef::new().with("classname", "worldspawn"),
+            EntityDef::new().with("classname", "info_player_start").with("origin", "16 32 64").with("angle", "90"),
+            EntityDef::new().with("classname", "info_player_deathmatch").with("origin", "0 0 0"),
+            EntityDef::new().with("classname", "info_player_start").with("origin", "broken"),
+        ];
+        let spawns = find_spawns(&defs);
+        assert_eq!(spawns.len(), 2);
+        assert_eq!(spawns[0].pos, na::Pnt3::new(16.0, -64.0, 32.0));
+        assert!(na::approx_eq(&spawns[0].yaw, &PI));
+        assert!(na::approx_eq(&spawns[1].yaw, &(1.5 * PI)));
+    }
+}
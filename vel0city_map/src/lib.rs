@@ -1,83 +1,253 @@
-#![feature(core)]
+#![feature(core, scoped)]
 
 #[macro_use]
 extern crate glium;
 extern crate vel0city_base;
+#[macro_use]
 extern crate nalgebra as na;
 extern crate byteorder;
 extern crate image;
+#[cfg(test)]
+extern crate wavefront_obj;
 
+pub mod aabb;
+pub mod batch;
+pub mod broadphase;
 pub mod bsp;
+pub mod builder;
+pub mod entities;
+pub mod lightgrid;
+pub mod lightmaps;
+pub mod map_cache;
+pub mod obj_export;
+pub mod q3_export;
 pub mod q3_import;
+pub mod shader;
+pub mod technique;
+pub mod textures;
+pub mod vis;
+pub mod winding;
 
+use std::rc::Rc;
+use std::thread;
+use aabb::Aabb;
 use cast::{
     CastResult,
     Ray
 };
 
+#[derive(Debug, PartialEq)]
 pub struct Model {
     pub brush: u32,
-    pub n_brushes: u32 
+    pub n_brushes: u32,
+    pub bounds: Aabb,
 }
+#[derive(Debug, PartialEq)]
 pub struct Entity {
     pub model: u32,
     pub kind: EntityKind
 }
-#[derive(PartialEq)]
+#[derive(Copy, Clone, Debug, PartialEq)]
 pub enum EntityKind {
     OutOfBounds,
     Goal
 }
 
+/// How much the broadphase query box is grown, so brushes that are only hit
+/// thanks to the cast epsilon aren't culled.
+const BROADPHASE_SLACK: f32 = 1.0;
+
+#[derive(Debug, PartialEq)]
 pub struct Map {
     pub bsp: bsp::Tree,
     pub models: Vec<Model>,
-    pub entities: Vec<Entity>
+    pub entities: Vec<Entity>,
+    pub broadphase: broadphase::Broadphase,
 }
 
 impl Map {
     pub fn cast_ray(&self, ray: &Ray) -> Option<CastResult> {
         let mut best = self.bsp.cast_ray(ray);
-        for (entityidx, entity) in self.entities.iter().enumerate() {
-            let model = &self.models[entity.model as usize];
-            for brush in &self.bsp.brushes[model.brush as usize .. (model.brush + model.n_brushes) as usize] {
-                let mut brushcast = brush.cast_ray(ray, (0.0, 1.0));
-                if let Some(brushcast) = brushcast.as_mut() {
-                    brushcast.entity = Some(entityidx as u32);
-                }
-                best = cast::combine_results(best, brushcast);
-            }
+        let bounds = Aabb::from_ray(ray).expand(&na::Vec3::new(BROADPHASE_SLACK, BROADPHASE_SLACK, BROADPHASE_SLACK));
+        for entityidx in self.broadphase.query(&bounds) {
+            best = cast::combine_results(best, self.cast_ray_entity(ray, entityidx));
+        }
+
+        best
+    }
+
+    /// Same as `cast_ray`, but tests the brushes of every entity instead of asking the broadphase.
+    pub fn cast_ray_brute_force(&self, ray: &Ray) -> Option<CastResult> {
+        let mut best = self.bsp.cast_ray(ray);
+        for entityidx in 0..self.entities.len() {
+            best = cast::combine_results(best, self.cast_ray_entity(ray, entityidx as u32));
         }
 
         best
     }
+
+    fn cast_ray_entity(&self, ray: &Ray, entityidx: u32) -> Option<CastResult> {
+        let model = &self.models[self.entities[entityidx as usize].model as usize];
+        let mut best = None;
+        for brush in &self.bsp.brushes[model.brush as usize .. (model.brush + model.n_brushes) as usize] {
+            let mut brushcast = brush.cast_ray(ray, (0.0, 1.0));
+            if let Some(brushcast) = brushcast.as_mut() {
+                brushcast.entity = Some(entityidx);
+            }
+            best = cast::combine_results(best, brushcast);
+        }
+        best
+    }
+
+    /// Casts every ray in `rays`, returning the results in the same order.
+    pub fn cast_rays(&self, rays: &[Ray]) -> Vec<Option<CastResult>> {
+        rays.iter().map(|ray| self.cast_ray(ray)).collect()
+    }
+
+    /// Same as `cast_rays`, but splits the rays evenly between `n_threads` workers.
+    /// The map is only ever read, so the workers share it without locking.
+    pub fn cast_rays_parallel(&self, rays: &[Ray], n_threads: usize) -> Vec<Option<CastResult>> {
+        let n_threads = std::cmp::max(n_threads, 1);
+        let chunk_size = (rays.len() + n_threads - 1) / n_threads;
+        if n_threads == 1 || chunk_size == 0 {
+            return self.cast_rays(rays);
+        }
+
+        let mut results = vec![None; rays.len()];
+        {
+            // The guards join their threads when they're dropped at the end of this block.
+            let _guards: Vec<_> = rays.chunks(chunk_size)
+                .zip(results.chunks_mut(chunk_size))
+                .map(|(rays, results)| thread::scoped(move || {
+                    for (ray, result) in rays.iter().zip(results.iter_mut()) {
+                        *result = self.cast_ray(ray);
+                    }
+                }))
+                .collect();
+        }
+        results
+    }
 }
 
-#[derive(Debug)]
+#[derive(Clone, Debug, PartialEq)]
 pub struct MapFace {
     pub texture: i32,
     pub lightmap: i32,
     pub index_start: u32,
     pub index_count: u32,
+    /// Index into the map's materials, if the face's texture has a shader script.
+    pub material: Option<u32>,
 }
 
-#[derive(Copy, Clone, Debug)]
+#[derive(Copy, Clone, Debug, PartialEq)]
 pub struct MapVertex {
     pub position: [f32; 3],
     pub texcoords: [f32; 2],
     pub lightmaptexcoords: [f32; 2],
-    pub normal: [f32; 3]
+    pub normal: [f32; 3],
+    /// Baked vertex lighting, for faces without a lightmap.
+    pub color: [f32; 4],
+}
+implement_vertex!(MapVertex, position, texcoords, lightmaptexcoords, normal, color);
+
+/// Width and height of the lightmaps in a BSP.
+pub const LIGHTMAP_SIZE: u32 = 128;
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Lightmap {
+    pub width: u32,
+    pub height: u32,
+    /// RGB, a row at a time from the top, ready to upload.
+    pub data: Vec<u8>,
+}
+impl Lightmap {
+    /// The pixels a row at a time, which is how glium takes them.
+    pub fn rows(&self) -> Vec<Vec<(u8, u8, u8)>> {
+        self.data.chunks(self.width as usize * 3)
+            .map(|row| row.chunks(3).map(|px| (px[0], px[1], px[2])).collect())
+            .collect()
+    }
+}
+
+/// Everything a `GraphicsMap` is made from, before any of it is uploaded.
+#[derive(Debug, PartialEq)]
+pub struct GraphicsMapData {
+    pub vertices: Vec<MapVertex>,
+    pub indices: Vec<u32>,
+    pub faces: Vec<MapFace>,
+    /// Names of the textures faces refer to, without an extension.
+    pub textures: Vec<String>,
+    /// Surface flags of each texture, like `bsp::SURF_SKY`.
+    pub texture_flags: Vec<i32>,
+    /// The shader scripts of the textures that have them. See `shader::attach_materials`.
+    pub materials: Vec<shader::Material>,
+    pub lightmaps: Vec<Lightmap>,
+    /// Faces grouped by texture and lightmap. See `batch::batch_faces`.
+    pub batches: Vec<batch::Batch>,
+    /// Light for things that move, if the map was compiled with it.
+    pub lightgrid: Option<lightgrid::LightGrid>,
 }
-implement_vertex!(MapVertex, position, texcoords, lightmaptexcoords, normal);
 
 pub struct GraphicsMap {
     pub vertices: glium::VertexBuffer<MapVertex>,
     pub indices: glium::IndexBuffer,
+    /// A copy of `indices`, for picking out the visible faces of a batch.
+    pub index_data: Vec<u32>,
     pub faces: Vec<MapFace>, 
-    pub textures: Vec<glium::Texture2d>,
+    pub batches: Vec<batch::Batch>,
+    /// For lighting things that move.
+    pub lightgrid: Option<lightgrid::LightGrid>,
+    /// Shared with other maps through a `textures::TextureCache`.
+    pub textures: Vec<Rc<glium::Texture2d>>,
+    pub texture_flags: Vec<i32>,
+    pub materials: Vec<shader::Material>,
+    /// How to draw each of `materials`.
+    pub techniques: Vec<technique::Technique>,
+    /// The images the techniques' passes refer to.
+    pub stage_textures: Vec<Rc<glium::Texture2d>>,
     pub lightmaps: Vec<glium::Texture2d>,
-    pub shaders: Vec<glium::Program>,
+    /// Stands in for the lightmap of faces that don't have one.
+    pub white_lightmap: glium::Texture2d,
+    pub shaders: MapPrograms,
+}
+
+/// The programs faces are drawn with, indexed by `SHADER_MAIN`, `SHADER_VERTEXLIT`,
+/// `SHADER_SKY` and `SHADER_STAGE`. The main program stands in for any of the
+/// others that couldn't be loaded.
+pub struct MapPrograms {
+    programs: Vec<Option<glium::Program>>,
 }
+impl MapPrograms {
+    /// `programs` has one for each `SHADER_*`, and a main program.
+    pub fn new(programs: Vec<Option<glium::Program>>) -> MapPrograms {
+        assert!(programs[SHADER_MAIN].is_some());
+        MapPrograms { programs: programs }
+    }
+
+    /// Whether `shader` has its own program, instead of the main one.
+    pub fn loaded(&self, shader: usize) -> bool {
+        self.programs[shader].is_some()
+    }
+}
+impl std::ops::Index<usize> for MapPrograms {
+    type Output = glium::Program;
+
+    fn index(&self, shader: usize) -> &glium::Program {
+        match self.programs[shader] {
+            Some(ref program) => program,
+            None => self.programs[SHADER_MAIN].as_ref().unwrap(),
+        }
+    }
+}
+
+/// For lightmapped faces.
+pub const SHADER_MAIN: usize = 0;
+/// For faces lit by their vertex colors instead of a lightmap.
+pub const SHADER_VERTEXLIT: usize = 1;
+/// For faces with `bsp::SURF_SKY`.
+pub const SHADER_SKY: usize = 2;
+/// For one pass of a material's technique.
+pub const SHADER_STAGE: usize = 3;
 
 pub mod cast {
     use na;
@@ -86,7 +256,49 @@ pub mod cast {
     pub struct Ray {
         pub orig: na::Pnt3<f32>,
         pub dir: na::Vec3<f32>,
-        pub halfextents: na::Vec3<f32>,
+        pub shape: Shape,
+    }
+
+    /// The volume that gets swept along a `Ray`.
+    #[derive(Copy, Clone, Debug, PartialEq)]
+    pub enum Shape {
+        /// An axis-aligned box with the given half extents.
+        /// A zero-sized box is a plain ray.
+        Box(na::Vec3<f32>),
+        /// A sphere with the given radius.
+        Sphere(f32),
+        /// A capsule standing along the Y axis. `halfheight` is the distance from
+        /// the center to the center of either cap.
+        Capsule {
+            radius: f32,
+            halfheight: f32,
+        },
+    }
+    impl Shape {
+        /// How far a plane with normal `norm` has to be pushed out so that the shape
+        /// touches it when its center is on the pushed plane.
+        pub fn plane_offset(&self, norm: &na::Vec3<f32>) -> f32 {
+            match *self {
+                Shape::Box(ref halfextents) => {
+                    na::abs(&(halfextents.x * norm.x)) +
+                        na::abs(&(halfextents.y * norm.y)) +
+                        na::abs(&(halfextents.z * norm.z))
+                },
+                Shape::Sphere(radius) => radius,
+                Shape::Capsule { radius, halfheight } => {
+                    radius + na::abs(&(halfheight * norm.y))
+                },
+            }
+        }
+
+        /// Half extents of the smallest axis-aligned box containing the shape.
+        pub fn bounding_halfextents(&self) -> na::Vec3<f32> {
+            match *self {
+                Shape::Box(halfextents) => halfextents,
+                Shape::Sphere(radius) => na::Vec3::new(radius, radius, radius),
+                Shape::Capsule { radius, halfheight } => na::Vec3::new(radius, radius + halfheight, radius),
+            }
+        }
     }
 
     #[derive(Copy, Clone,Debug, PartialEq)]
@@ -95,6 +307,8 @@ pub mod cast {
         pub toi: f32,
         /// Normal of the plane it hit. 
         pub norm: na::Vec3<f32>,
+        /// Surface flags of the side it hit, like `bsp::SURF_SLICK`.
+        pub flags: i32,
 
         /// Entity hit by the cast.
         pub entity: Option<u32>
@@ -116,4 +330,57 @@ pub mod cast {
         }
     }
 
+    #[cfg(test)]
+    mod test {
+        use na;
+        use bsp::{Brush, BrushSide, Plane};
+        use super::{Ray, Shape};
+
+        /// Everything behind a plane with the given normal and distance.
+        fn half_space(norm: na::Vec3<f32>, dist: f32) -> Brush {
+            Brush {
+                sides: vec![BrushSide { plane: Plane { norm: norm, dist: dist }, flags: 0, contents: 1 }]
+            }
+        }
+
+        fn sweep_toi(brush: &Brush, shape: Shape, orig: na::Pnt3<f32>, dir: na::Vec3<f32>) -> f32 {
+            brush.cast_ray(&Ray { orig: orig, dir: dir, shape: shape }, (0.0, 1.0)).unwrap().toi
+        }
+
+        // Matches the cast epsilon in `bsp`.
+        const EPS: f32 = 1.0 / 8.0;
+
+        #[test]
+        fn shapes_hit_wall() {
+            // Solid where x >= 64.
+            let wall = half_space(na::Vec3::new(-1.0, 0.0, 0.0), -64.0);
+            let cast = |shape| sweep_toi(&wall, shape, na::Pnt3::new(0.0, 0.0, 0.0), na::Vec3::new(64.0, 0.0, 0.0));
+            assert!(na::approx_eq(&cast(Shape::Sphere(16.0)), &((48.0 - EPS) / 64.0)));
+            // The capsule is upright, so only its radius faces the wall.
+            assert!(na::approx_eq(&cast(Shape::Capsule { radius: 8.0, halfheight: 16.0 }), &((56.0 - EPS) / 64.0)));
+        }
+
+        #[test]
+        fn shapes_hit_floor() {
+            // Up is -y, so this is solid where y >= 0.
+            let floor = half_space(na::Vec3::new(0.0, -1.0, 0.0), 0.0);
+            let cast = |shape| sweep_toi(&floor, shape, na::Pnt3::new(0.0, -64.0, 0.0), na::Vec3::new(0.0, 64.0, 0.0));
+            assert!(na::approx_eq(&cast(Shape::Sphere(16.0)), &((48.0 - EPS) / 64.0)));
+            // Landing on a cap, which is half the height above the center.
+            assert!(na::approx_eq(&cast(Shape::Capsule { radius: 8.0, halfheight: 16.0 }), &((40.0 - EPS) / 64.0)));
+        }
+
+        #[test]
+        fn capsule_hits_slope() {
+            let s = 0.5f32.sqrt();
+            // A 45 degree slope through the origin, solid where x + y >= 0.
+            let slope = half_space(na::Vec3::new(-s, -s, 0.0), 0.0);
+            let cast = |shape| sweep_toi(&slope, shape, na::Pnt3::new(0.0, -64.0, 0.0), na::Vec3::new(0.0, 64.0, 0.0));
+            // The plane starts 64s away along its normal, and the ray closes that at s per unit.
+            assert!(na::approx_eq(&cast(Shape::Sphere(8.0)), &((64.0 * s - 8.0 - EPS) / (64.0 * s))));
+            // Only the part of the half height along the normal's y counts.
+            let capsule = Shape::Capsule { radius: 8.0, halfheight: 16.0 };
+            assert!(na::approx_eq(&cast(capsule), &((64.0 * s - 8.0 - 16.0 * s - EPS) / (64.0 * s))));
+        }
+    }
 }
@@ -0,0 +1,215 @@
+//! The light grid from the lightvols lump: baked light at regular points through the
+//! map, for lighting things that move around and so can't have lightmaps.
+
+use na;
+use std::f32::consts::PI;
+
+/// Spacing of the grid, in Quake's axes, when the worldspawn doesn't say otherwise.
+pub const DEFAULT_CELL_SIZE: [f32; 3] = [64.0, 64.0, 128.0];
+
+/// One point of the grid, as stored in the lump.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct LightCell {
+    pub ambient: [u8; 3],
+    pub directed: [u8; 3],
+    /// Direction towards the light, as spherical angles where 256 is a full turn.
+    pub lng: u8,
+    pub lat: u8,
+}
+impl LightCell {
+    /// Cells inside solid space have no light at all, and shouldn't darken their neighbours.
+    fn is_solid(&self) -> bool {
+        self.ambient == [0, 0, 0] && self.directed == [0, 0, 0]
+    }
+
+    /// The direction towards the light, in Quake's axes.
+    fn dir(&self) -> na::Vec3<f32> {
+        let lat = self.lat as f32 * (2.0 * PI / 256.0);
+        let lng = self.lng as f32 * (2.0 * PI / 256.0);
+        na::Vec3::new(lat.cos() * lng.sin(), lat.sin() * lng.sin(), lng.cos())
+    }
+}
+
+/// Light at a point, with colors from 0 to 1.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct LightSample {
+    pub ambient: na::Vec3<f32>,
+    pub directed: na::Vec3<f32>,
+    /// Unit vector pointing towards where the directed light comes from.
+    pub dir: na::Vec3<f32>,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct LightGrid {
+    /// Position of the first cell, in Quake's axes.
+    pub origin: [f32; 3],
+    pub cell_size: [f32; 3],
+    /// Number of cells along each of Quake's axes.
+    pub dims: [usize; 3],
+    /// X changes fastest, then Y, then Z.
+    pub cells: Vec<LightCell>,
+}
+
+impl LightGrid {
+    /// Where the grid covering the world's bounds starts and how many cells it has.
+    /// The bounds are in Quake's axes. Returns `None` if the bounds are too small to
+    /// hold a single cell.
+    pub fn layout(mins: [f32; 3], maxs: [f32; 3], cell_size: [f32; 3]) -> Option<([f32; 3], [usize; 3])> {
+        let mut origin = [0.0; 3];
+        let mut dims = [0; 3];
+        for i in 0..3 {
+            if !(cell_size[i] > 0.0) {
+                return None;
+            }
+            origin[i] = cell_size[i] * (mins[i] / cell_size[i]).ceil();
+            let last = cell_size[i] * (maxs[i] / cell_size[i]).floor();
+            let n = (last - origin[i]) / cell_size[i] + 1.0;
+            // Anything this big is a broken file, not a real map.
+            if !(n >= 1.0 && n < 65536.0) {
+                return None;
+            }
+            dims[i] = n as usize;
+        }
+        Some((origin, dims))
+    }
+
+    fn cell(&self, x: usize, y: usize, z: usize) -> &LightCell {
+        &self.cells[x + self.dims[0] * (y + self.dims[1] * z)]
+    }
+
+    /// Blends the eight cells around `pos`. Positions outside the grid get the light
+    /// at its edge.
+    pub fn sample(&self, pos: &na::Pnt3<f32>) -> LightSample {
+        let q = [pos.x, pos.z, -pos.y];
+        let mut base = [0; 3];
+        let mut frac = [0.0; 3];
+        for i in 0..3 {
+            let v = (q[i] - self.origin[i]) / self.cell_size[i];
+            let last = (self.dims[i] - 1) as f32;
+            let cell = na::clamp(v.floor(), 0.0, last);
+            base[i] = cell as usize;
+            frac[i] = na::clamp(v - cell, 0.0, 1.0);
+        }
+
+        let mut total = 0.0;
+        let mut ambient = na::Vec3::new(0.0, 0.0, 0.0);
+        let mut directed = na::Vec3::new(0.0, 0.0, 0.0);
+        let mut dir = na::Vec3::new(0.0, 0.0, 0.0);
+        for corner in 0..8 {
+            let mut factor = 1.0;
+            let mut at = [0; 3];
+            for i in 0..3 {
+                if corner & (1 << i) != 0 {
+                    factor *= frac[i];
+                    at[i] = ::std::cmp::min(base[i] + 1, self.dims[i] - 1);
+                } else {
+                    factor *= 1.0 - frac[i];
+                    at[i] = base[i];
+                }
+            }
+
+            let cell = self.cell(at[0], at[1], at[2]);
+            if factor == 0.0 || cell.is_solid() {
+                continue;
+            }
+            total += factor;
+            ambient = ambient + na::Vec3::new(cell.ambient[0] as f32, cell.ambient[1] as f32, cell.ambient[2] as f32) * factor;
+            directed = directed + na::Vec3::new(cell.directed[0] as f32, cell.directed[1] as f32, cell.directed[2] as f32) * factor;
+            dir = dir + cell.dir() * factor;
+        }
+
+        // Make up for the solid cells that got skipped.
+        if total > 0.0 {
+            ambient = ambient * (1.0 / total);
+            directed = directed * (1.0 / total);
+        }
+        let dir = if na::sqnorm(&dir) > 0.0 {
+            na::normalize(&dir)
+        } else {
+            na::Vec3::new(0.0, 0.0, 1.0)
+        };
+
+        LightSample {
+            ambient: ambient * (1.0 / 255.0),
+            directed: directed * (1.0 / 255.0),
+            dir: na::Vec3::new(dir.x, -dir.z, dir.y),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use na;
+    use super::{
+        LightCell,
+        LightGrid
+    };
+
+    fn cell(ambient: u8, directed: u8, lat: u8, lng: u8) -> LightCell {
+        LightCell {
+            ambient: [ambient, ambient, ambient],
+            directed: [directed, directed, directed],
+            lat: lat,
+            lng: lng,
+        }
+    }
+
+    /// Two cells along Quake's X (our X), with a 64 unit spacing.
+    fn pair(a: LightCell, b: LightCell) -> LightGrid {
+        LightGrid {
+            origin: [0.0, 0.0, 0.0],
+            cell_size: [64.0, 64.0, 128.0],
+            dims: [2, 1, 1],
+            cells: vec![a, b],
+        }
+    }
+
+    #[test]
+    fn layout() {
+        let (origin, dims) = LightGrid::layout([-100.0, 0.0, 10.0], [100.0, 64.0, 300.0], [64.0, 64.0, 128.0]).unwrap();
+        assert_eq!(origin, [-64.0, 0.0, 128.0]);
+        assert_eq!(dims, [3, 2, 2]);
+        assert_eq!(LightGrid::layout([0.0, 0.0, 10.0], [64.0, 64.0, 100.0], [64.0, 64.0, 128.0]), None);
+    }
+
+    #[test]
+    fn samples_cells_exactly() {
+        let grid = pair(cell(255, 0, 0, 0), cell(0, 255, 0, 0));
+        let s = grid.sample(&na::Pnt3::new(0.0, 0.0, 0.0));
+        assert!(na::approx_eq(&s.ambient, &na::Vec3::new(1.0, 1.0, 1.0)));
+        assert!(na::approx_eq(&s.directed, &na::Vec3::new(0.0, 0.0, 0.0)));
+        // lng 0 is straight up Quake's Z, which is up for us too.
+        assert!(na::approx_eq(&s.dir, &na::Vec3::new(0.0, -1.0, 0.0)));
+    }
+
+    #[test]
+    fn interpolates_between_cells() {
+        let grid = pair(cell(255, 0, 0, 0), cell(51, 102, 0, 0));
+        let s = grid.sample(&na::Pnt3::new(32.0, 0.0, 0.0));
+        assert!(na::approx_eq(&s.ambient, &na::Vec3::new(0.6, 0.6, 0.6)));
+        assert!(na::approx_eq(&s.directed, &na::Vec3::new(0.2, 0.2, 0.2)));
+    }
+
+    #[test]
+    fn clamps_outside_grid() {
+        let grid = pair(cell(255, 0, 0, 0), cell(51, 0, 0, 0));
+        assert_eq!(grid.sample(&na::Pnt3::new(-500.0, 0.0, 0.0)), grid.sample(&na::Pnt3::new(0.0, 0.0, 0.0)));
+        assert_eq!(grid.sample(&na::Pnt3::new(500.0, 900.0, -900.0)), grid.sample(&na::Pnt3::new(64.0, 0.0, 0.0)));
+    }
+
+    #[test]
+    fn skips_solid_cells() {
+        let grid = pair(cell(102, 0, 0, 0), cell(0, 0, 0, 0));
+        let s = grid.sample(&na::Pnt3::new(48.0, 0.0, 0.0));
+        assert!(na::approx_eq(&s.ambient, &na::Vec3::new(0.4, 0.4, 0.4)));
+    }
+
+    #[test]
+    fn directions() {
+        // lng 64 is a quarter turn down from straight up, lat 64 a quarter turn around from X to Y.
+        let grid = pair(cell(0, 255, 64, 64), cell(0, 255, 64, 64));
+        let s = grid.sample(&na::Pnt3::new(0.0, 0.0, 0.0));
+        // Quake's Y is our Z.
+        assert!(na::approx_eq(&s.dir, &na::Vec3::new(0.0, 0.0, 1.0)));
+    }
+}
@@ -0,0 +1,352 @@
+//! Packing lightmaps into atlases, so faces with different lightmaps can share a
+//! batch, and brightening baked light the way Quake 3 does.
+
+use batch;
+use std::cmp;
+use std::env;
+use std::str::FromStr;
+use {
+    GraphicsMapData,
+    Lightmap
+};
+
+/// The biggest atlas `pack_atlases` makes, unless a single lightmap is bigger.
+pub const DEFAULT_ATLAS_SIZE: u32 = 2048;
+
+/// Environment variable that overrides `LightingSettings::overbright_bits`.
+pub const OVERBRIGHT_BITS_VAR: &'static str = "VEL0CITY_OVERBRIGHT_BITS";
+/// Environment variable that overrides `LightingSettings::gamma`.
+pub const GAMMA_VAR: &'static str = "VEL0CITY_GAMMA";
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct LightingSettings {
+    /// Baked light is multiplied by 2 to the power of this, like Quake 3's
+    /// `r_mapOverBrightBits`. Maps are lit expecting 2, which makes them look dark
+    /// without it.
+    pub overbright_bits: u32,
+    /// Brightens baked light when above 1, like `r_gamma`.
+    pub gamma: f32,
+}
+impl ::std::default::Default for LightingSettings {
+    fn default() -> LightingSettings {
+        LightingSettings {
+            overbright_bits: 2,
+            gamma: 1.0,
+        }
+    }
+}
+impl LightingSettings {
+    /// The defaults, changed by `OVERBRIGHT_BITS_VAR` and `GAMMA_VAR` where they're set.
+    pub fn from_env() -> LightingSettings {
+        LightingSettings::from_vars(env::var(OVERBRIGHT_BITS_VAR).ok(), env::var(GAMMA_VAR).ok())
+    }
+
+    /// `from_env` with the variables' values passed in. Values that don't parse
+    /// are ignored, with a warning.
+    pub fn from_vars(overbright_bits: Option<String>, gamma: Option<String>) -> LightingSettings {
+        let mut settings = LightingSettings::default();
+        parse_var(OVERBRIGHT_BITS_VAR, overbright_bits, &mut settings.overbright_bits);
+        parse_var(GAMMA_VAR, gamma, &mut settings.gamma);
+        settings
+    }
+}
+
+fn parse_var<T: FromStr>(name: &str, value: Option<String>, out: &mut T) {
+    if let Some(value) = value {
+        match value.trim().parse() {
+            Ok(v) => *out = v,
+            Err(_) => println!("Warning: ignoring {}={}", name, value),
+        }
+    }
+}
+
+/// Where a lightmap ended up in the atlases, in pixels.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Placement {
+    pub atlas: u32,
+    pub x: u32,
+    pub y: u32,
+}
+
+fn next_power_of_two(n: u32) -> u32 {
+    let mut p = 1;
+    while p < n {
+        p *= 2;
+    }
+    p
+}
+
+/// Lays out rectangles of the given sizes in as few atlases of at most `max_size`
+/// square as it can, filling shelves from the tallest rectangle down. A rectangle
+/// bigger than `max_size` makes that the limit instead. Returns the size of each
+/// atlas, rounded up to powers of two, and where each rectangle went.
+pub fn pack(sizes: &[(u32, u32)], max_size: u32) -> (Vec<(u32, u32)>, Vec<Placement>) {
+    let max_size = sizes.iter().fold(max_size, |size, &(w, h)| cmp::max(size, cmp::max(w, h)));
+    let mut order: Vec<usize> = (0..sizes.len()).collect();
+    order.sort_by(|&a, &b| sizes[b].1.cmp(&sizes[a].1));
+
+    let mut atlases: Vec<(u32, u32)> = vec![];
+    let mut placements = vec![Placement { atlas: 0, x: 0, y: 0 }; sizes.len()];
+    let (mut x, mut shelf_y, mut shelf_height) = (0, 0, 0);
+    for idx in order {
+        let (w, h) = sizes[idx];
+        if x + w > max_size {
+            shelf_y += shelf_height;
+            x = 0;
+            shelf_height = 0;
+        }
+        if atlases.is_empty() || shelf_y + h > max_size {
+            atlases.push((0, 0));
+            x = 0;
+            shelf_y = 0;
+            shelf_height = 0;
+        }
+        let atlas = atlases.len() - 1;
+        placements[idx] = Placement { atlas: atlas as u32, x: x, y: shelf_y };
+        x += w;
+        shelf_height = cmp::max(shelf_height, h);
+        let extent = &mut atlases[atlas];
+        *extent = (cmp::max(extent.0, x), cmp::max(extent.1, shelf_y + h));
+    }
+
+    let atlases = atlases.into_iter().map(|(w, h)| (next_power_of_two(w), next_power_of_two(h))).collect();
+    (atlases, placements)
+}
+
+/// Copies `lightmaps` into atlases laid out by `pack`. Space nothing was packed
+/// into is black.
+pub fn build_atlases(lightmaps: &[Lightmap], max_size: u32) -> (Vec<Lightmap>, Vec<Placement>) {
+    let sizes: Vec<_> = lightmaps.iter().map(|lm| (lm.width, lm.height)).collect();
+    let (sizes, placements) = pack(&sizes, max_size);
+    let mut atlases: Vec<Lightmap> = sizes.into_iter()
+        .map(|(w, h)| Lightmap { width: w, height: h, data: vec![0; w as usize * h as usize * 3] })
+        .collect();
+
+    for (lightmap, placement) in lightmaps.iter().zip(placements.iter()) {
+        let atlas = &mut atlases[placement.atlas as usize];
+        let row_len = lightmap.width as usize * 3;
+        for (row, src) in lightmap.data.chunks(row_len).enumerate() {
+            let start = ((placement.y as usize + row) * atlas.width as usize + placement.x as usize) * 3;
+            for (dst, &b) in atlas.data[start..start + row_len].iter_mut().zip(src.iter()) {
+                *dst = b;
+            }
+        }
+    }
+    (atlases, placements)
+}
+
+/// Replaces the map's lightmaps with atlases, moving each face's lightmap
+/// texcoords to where its lightmap went. Faces are batched again afterwards, since
+/// faces that used to have different lightmaps might share an atlas now.
+pub fn pack_atlases(graphics: &mut GraphicsMapData, max_size: u32) {
+    let (atlases, placements) = build_atlases(&graphics.lightmaps, max_size);
+
+    let mut moved = vec![false; graphics.vertices.len()];
+    for face in graphics.faces.iter_mut() {
+        if face.lightmap < 0 || face.lightmap as usize >= placements.len() {
+            continue;
+        }
+        let lightmap = &graphics.lightmaps[face.lightmap as usize];
+        let placement = placements[face.lightmap as usize];
+        let atlas = &atlases[placement.atlas as usize];
+        let start = face.index_start as usize;
+        for &index in &graphics.indices[start..start + face.index_count as usize] {
+            if moved[index as usize] {
+                continue;
+            }
+            moved[index as usize] = true;
+            let coords = &mut graphics.vertices[index as usize].lightmaptexcoords;
+            coords[0] = (placement.x as f32 + coords[0] * lightmap.width as f32) / atlas.width as f32;
+            coords[1] = (placement.y as f32 + coords[1] * lightmap.height as f32) / atlas.height as f32;
+        }
+        face.lightmap = placement.atlas as i32;
+    }
+
+    graphics.lightmaps = atlases;
+    graphics.batches = batch::batch_faces(&mut graphics.faces, &mut graphics.indices);
+}
+
+/// Brightens a baked color like Quake 3's `R_ColorShiftLightingBytes`: shifted
+/// up by the overbright bits and scaled back into range without changing its hue,
+/// then gamma corrected.
+pub fn shift_color(rgb: [u8; 3], settings: &LightingSettings) -> [u8; 3] {
+    let shift = cmp::min(settings.overbright_bits, 8);
+    let mut c = [(rgb[0] as u32) << shift, (rgb[1] as u32) << shift, (rgb[2] as u32) << shift];
+    let max = cmp::max(c[0], cmp::max(c[1], c[2]));
+    if max > 255 {
+        for v in c.iter_mut() {
+            *v = *v * 255 / max;
+        }
+    }
+
+    let mut out = [0; 3];
+    for (o, &v) in out.iter_mut().zip(c.iter()) {
+        *o = if settings.gamma == 1.0 || settings.gamma <= 0.0 {
+            v as u8
+        } else {
+            let corrected = 255.0 * (v as f32 / 255.0).powf(1.0 / settings.gamma) + 0.5;
+            if corrected > 255.0 { 255 } else { corrected as u8 }
+        };
+    }
+    out
+}
+
+/// Applies `shift_color` to all the map's baked light: lightmaps, vertex colors and
+/// the light grid.
+pub fn apply_lighting(graphics: &mut GraphicsMapData, settings: &LightingSettings) {
+    for lightmap in graphics.lightmaps.iter_mut() {
+        for px in lightmap.data.chunks_mut(3) {
+            let shifted = shift_color([px[0], px[1], px[2]], settings);
+            px[0] = shifted[0];
+            px[1] = shifted[1];
+            px[2] = shifted[2];
+        }
+    }
+    for vertex in graphics.vertices.iter_mut() {
+        let c = &mut vertex.color;
+        let bytes = [(c[0] * 255.0 + 0.5) as u8, (c[1] * 255.0 + 0.5) as u8, (c[2] * 255.0 + 0.5) as u8];
+        let shifted = shift_color(bytes, settings);
+        for i in 0..3 {
+            c[i] = shifted[i] as f32 / 255.0;
+        }
+    }
+    if let Some(ref mut grid) = graphics.lightgrid {
+        for cell in grid.cells.iter_mut() {
+            cell.ambient = shift_color(cell.ambient, settings);
+            cell.directed = shift_color(cell.directed, settings);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use {
+        GraphicsMapData,
+        Lightmap,
+        MapFace,
+        MapVertex
+    };
+    use super::{
+        apply_lighting,
+        build_atlases,
+        pack,
+        pack_atlases,
+        shift_color,
+        LightingSettings,
+        Placement
+    };
+
+    #[test]
+    fn packs_shelves() {
+        let (atlases, placements) = pack(&[(128, 128); 5], 256);
+        assert_eq!(atlases, vec![(256, 256), (128, 128)]);
+        assert_eq!(placements, vec![
+            Placement { atlas: 0, x: 0, y: 0 },
+            Placement { atlas: 0, x: 128, y: 0 },
+            Placement { atlas: 0, x: 0, y: 128 },
+            Placement { atlas: 0, x: 128, y: 128 },
+            Placement { atlas: 1, x: 0, y: 0 },
+        ]);
+
+        // Tallest first, and lightmaps bigger than the atlas size make it bigger.
+        let (atlases, placements) = pack(&[(16, 8), (16, 32), (64, 64)], 32);
+        assert_eq!(atlases, vec![(64, 64), (32, 32)]);
+        assert_eq!(placements[2], Placement { atlas: 0, x: 0, y: 0 });
+        assert_eq!(placements[1], Placement { atlas: 1, x: 0, y: 0 });
+        assert_eq!(placements[0], Placement { atlas: 1, x: 16, y: 0 });
+    }
+
+    #[test]
+    fn copies_pixels() {
+        let lightmaps = vec![
+            Lightmap { width: 1, height: 2, data: vec![1, 1, 1, 2, 2, 2] },
+            Lightmap { width: 2, height: 1, data: vec![3, 3, 3, 4, 4, 4] },
+        ];
+        let (atlases, _) = build_atlases(&lightmaps, 4);
+        assert_eq!(atlases, vec![Lightmap {
+            width: 4,
+            height: 2,
+            data: vec![1, 1, 1, 3, 3, 3, 4, 4, 4, 0, 0, 0,
+                       2, 2, 2, 0, 0, 0, 0, 0, 0, 0, 0, 0],
+        }]);
+    }
+
+    fn vertex(lightmaptexcoords: [f32; 2]) -> MapVertex {
+        MapVertex {
+            position: [0.0; 3],
+            texcoords: [0.0; 2],
+            lightmaptexcoords: lightmaptexcoords,
+            normal: [0.0; 3],
+            color: [0.25, 0.5, 1.0, 1.0],
+        }
+    }
+
+    fn test_graphics() -> GraphicsMapData {
+        GraphicsMapData {
+            vertices: vec![vertex([0.0, 0.0]), vertex([1.0, 0.5]), vertex([0.5, 1.0]),
+                           vertex([0.0, 0.0]), vertex([1.0, 0.5]), vertex([0.5, 1.0])],
+            indices: vec![0, 1, 2, 3, 4, 5],
+            faces: vec![
+                MapFace { texture: 0, lightmap: 0, index_start: 0, index_count: 3, material: None },
+                MapFace { texture: 0, lightmap: 1, index_start: 3, index_count: 3, material: None },
+            ],
+            textures: vec!["textures/base/floor".to_string()],
+            texture_flags: vec![0],
+            materials: vec![],
+            lightmaps: vec![
+                Lightmap { width: 128, height: 128, data: vec![10; 128 * 128 * 3] },
+                Lightmap { width: 128, height: 128, data: vec![20; 128 * 128 * 3] },
+            ],
+            batches: vec![],
+            lightgrid: None,
+        }
+    }
+
+    #[test]
+    fn atlases_move_texcoords() {
+        let mut graphics = test_graphics();
+        pack_atlases(&mut graphics, 2048);
+
+        assert_eq!(graphics.lightmaps.len(), 1);
+        assert_eq!((graphics.lightmaps[0].width, graphics.lightmaps[0].height), (256, 128));
+        assert!(graphics.faces.iter().all(|face| face.lightmap == 0));
+        assert_eq!(graphics.batches.len(), 1);
+
+        let coords: Vec<_> = graphics.vertices.iter().map(|v| v.lightmaptexcoords).collect();
+        assert_eq!(coords, vec![[0.0, 0.0], [0.5, 0.5], [0.25, 1.0],
+                                [0.5, 0.0], [1.0, 0.5], [0.75, 1.0]]);
+    }
+
+    #[test]
+    fn overbright_keeps_hue() {
+        let settings = LightingSettings::default();
+        assert_eq!(shift_color([10, 20, 30], &settings), [40, 80, 120]);
+        assert_eq!(shift_color([255, 128, 0], &settings), [255, 128, 0]);
+        assert_eq!(shift_color([100, 50, 0], &settings), [255, 127, 0]);
+
+        let plain = LightingSettings { overbright_bits: 0, gamma: 1.0 };
+        assert_eq!(shift_color([10, 20, 30], &plain), [10, 20, 30]);
+    }
+
+    #[test]
+    fn gamma_brightens() {
+        let settings = LightingSettings { overbright_bits: 0, gamma: 2.0 };
+        assert_eq!(shift_color([0, 64, 255], &settings), [0, 128, 255]);
+    }
+
+    #[test]
+    fn settings_from_vars() {
+        assert_eq!(LightingSettings::from_vars(None, None), LightingSettings::default());
+        assert_eq!(LightingSettings::from_vars(Some("1".to_string()), Some(" 1.5".to_string())),
+                   LightingSettings { overbright_bits: 1, gamma: 1.5 });
+        assert_eq!(LightingSettings::from_vars(Some("lots".to_string()), None), LightingSettings::default());
+    }
+
+    #[test]
+    fn lighting_applies_everywhere() {
+        let mut graphics = test_graphics();
+        apply_lighting(&mut graphics, &LightingSettings::default());
+        assert!(graphics.lightmaps[0].data.iter().all(|&b| b == 40));
+        assert_eq!(graphics.vertices[0].color, [64.0 / 255.0, 128.0 / 255.0, 1.0, 1.0]);
+    }
+}
@@ -0,0 +1,796 @@
+//! Maps as they are after importing, saved so the next load can skip parsing the
+//! BSP. Each cache file remembers a hash of what it was made from, and is ignored
+//! once that changes.
+//!
+//! Everything is little-endian. The file starts with `MAGIC`, `VERSION` and the
+//! source key, then the collision data, then the graphics data. Lists are a `u32`
+//! count followed by their items.
+
+use byteorder::{self, LittleEndian, ReadBytesExt, WriteBytesExt};
+use na;
+use std::env;
+use std::fs::{self, File};
+use std::io::{Cursor, Read, Write};
+use std::path::{Path, PathBuf};
+use aabb::Aabb;
+use batch::Batch;
+use broadphase::Broadphase;
+use bsp::{self, Plane};
+use lightgrid::{LightCell, LightGrid};
+use lightmaps;
+use q3_export::{put_f32, put_i32, put_u32};
+use q3_import::{self, check_child, check_index, check_range, check_visdata, BspError};
+use shader::Material;
+use vis::VisData;
+use {
+    Map,
+    Model,
+    Entity,
+    EntityKind,
+    GraphicsMapData,
+    Lightmap,
+    MapVertex,
+    MapFace
+};
+
+pub const MAGIC: &'static [u8; 4] = b"V0MC";
+/// Bumped whenever the layout changes, so old caches get rebuilt. Version 2 has
+/// lightmap atlases instead of the BSP's lightmaps.
+pub const VERSION: u32 = 2;
+
+/// Environment variable with the directory cache files go in.
+pub const CACHE_DIR_VAR: &'static str = "VEL0CITY_CACHE";
+/// Where cache files go when `CACHE_DIR_VAR` isn't set.
+pub const DEFAULT_CACHE_DIR: &'static str = "cache";
+
+#[derive(Debug)]
+pub enum CacheError {
+    /// The file doesn't start with `MAGIC`.
+    BadMagic,
+    /// Written by a different version of the cache format.
+    UnsupportedVersion(u32),
+    /// Made from a different BSP or different materials.
+    Stale,
+    /// The file ends partway through.
+    Truncated,
+    /// Something in it doesn't add up.
+    Corrupt(&'static str),
+}
+impl ::std::convert::From<byteorder::Error> for CacheError {
+    fn from(_: byteorder::Error) -> CacheError {
+        CacheError::Truncated
+    }
+}
+impl ::std::convert::From<BspError> for CacheError {
+    fn from(e: BspError) -> CacheError {
+        match e {
+            BspError::DanglingIndex { lump, .. } => CacheError::Corrupt(lump),
+            BspError::BadVisData { .. } => CacheError::Corrupt("visdata"),
+            _ => CacheError::Corrupt("map"),
+        }
+    }
+}
+impl ::std::fmt::Display for CacheError {
+    fn fmt(&self, f: &mut ::std::fmt::Formatter) -> ::std::fmt::Result {
+        match *self {
+            CacheError::BadMagic => write!(f, "Not a map cache"),
+            CacheError::UnsupportedVersion(v) => write!(f, "Unsupported map cache version {} (expected {})", v, VERSION),
+            CacheError::Stale => write!(f, "Map cache is out of date"),
+            CacheError::Truncated => write!(f, "Map cache is truncated"),
+            CacheError::Corrupt(what) => write!(f, "Map cache has a broken {}", what),
+        }
+    }
+}
+
+/// 64-bit FNV-1a. Unlike std's hashers, it's the same from one build to the next.
+fn fnv(hash: u64, bytes: &[u8]) -> u64 {
+    bytes.iter().fold(hash, |hash, &b| (hash ^ b as u64).wrapping_mul(0x100000001b3))
+}
+
+/// Identifies what a cache is made from: the BSP, and the surfaceparms of the
+/// materials, since they change what's solid.
+pub fn source_key(bsp: &[u8], materials: &[Material]) -> u64 {
+    let mut hash = fnv(0xcbf29ce484222325, bsp);
+    for material in materials.iter().filter(|material| !material.surfaceparms.is_empty()) {
+        hash = fnv(hash, material.name.as_bytes());
+        for parm in &material.surfaceparms {
+            hash = fnv(hash, &[0]);
+            hash = fnv(hash, parm.as_bytes());
+        }
+        hash = fnv(hash, &[0, 0]);
+    }
+    hash
+}
+
+fn put_len(buf: &mut Vec<u8>, len: usize) {
+    put_u32(buf, len as u32);
+}
+fn put_floats(buf: &mut Vec<u8>, vs: &[f32]) {
+    for &v in vs {
+        put_f32(buf, v);
+    }
+}
+fn put_u32s(buf: &mut Vec<u8>, vs: &[u32]) {
+    put_len(buf, vs.len());
+    for &v in vs {
+        put_u32(buf, v);
+    }
+}
+fn put_bytes(buf: &mut Vec<u8>, bytes: &[u8]) {
+    put_len(buf, bytes.len());
+    buf.extend(bytes.iter().cloned());
+}
+fn put_plane(buf: &mut Vec<u8>, plane: &Plane) {
+    put_floats(buf, &[plane.norm.x, plane.norm.y, plane.norm.z, plane.dist]);
+}
+fn put_aabb(buf: &mut Vec<u8>, aabb: &Aabb) {
+    put_floats(buf, &[aabb.mins.x, aabb.mins.y, aabb.mins.z, aabb.maxs.x, aabb.maxs.y, aabb.maxs.z]);
+}
+
+fn put_map(buf: &mut Vec<u8>, map: &Map) {
+    let tree = &map.bsp;
+    put_len(buf, tree.inodes.len());
+    for node in &tree.inodes {
+        put_plane(buf, &node.plane);
+        put_i32(buf, node.pos);
+        put_i32(buf, node.neg);
+    }
+    put_len(buf, tree.leaves.len());
+    for leaf in &tree.leaves {
+        put_i32(buf, leaf.cluster);
+        put_i32(buf, leaf.area);
+        put_aabb(buf, &leaf.bounds);
+        put_i32(buf, leaf.leafface);
+        put_i32(buf, leaf.n_leaffaces);
+        put_i32(buf, leaf.leafbrush);
+        put_i32(buf, leaf.n_leafbrushes);
+    }
+    put_len(buf, tree.brushes.len());
+    for brush in &tree.brushes {
+        put_len(buf, brush.sides.len());
+        for side in &brush.sides {
+            put_plane(buf, &side.plane);
+            put_i32(buf, side.flags);
+            put_i32(buf, side.contents);
+        }
+    }
+    put_u32s(buf, &tree.leafbrushes);
+    put_u32s(buf, &tree.leaffaces);
+    match tree.visdata {
+        Some(ref visdata) => {
+            buf.push(1);
+            put_len(buf, visdata.n_clusters);
+            put_len(buf, visdata.cluster_size);
+            put_bytes(buf, &visdata.bits);
+        },
+        None => buf.push(0),
+    }
+
+    put_len(buf, map.models.len());
+    for model in &map.models {
+        put_u32(buf, model.brush);
+        put_u32(buf, model.n_brushes);
+        put_aabb(buf, &model.bounds);
+    }
+    put_len(buf, map.entities.len());
+    for entity in &map.entities {
+        put_u32(buf, entity.model);
+        buf.push(match entity.kind {
+            EntityKind::OutOfBounds => 0,
+            EntityKind::Goal => 1,
+        });
+    }
+}
+
+fn put_graphics(buf: &mut Vec<u8>, graphics: &GraphicsMapData) {
+    put_len(buf, graphics.vertices.len());
+    for vertex in &graphics.vertices {
+        put_floats(buf, &vertex.position);
+        put_floats(buf, &vertex.texcoords);
+        put_floats(buf, &vertex.lightmaptexcoords);
+        put_floats(buf, &vertex.normal);
+        put_floats(buf, &vertex.color);
+    }
+    put_u32s(buf, &graphics.indices);
+    put_len(buf, graphics.faces.len());
+    for face in &graphics.faces {
+        put_i32(buf, face.texture);
+        put_i32(buf, face.lightmap);
+        put_u32(buf, face.index_start);
+        put_u32(buf, face.index_count);
+    }
+    put_len(buf, graphics.textures.len());
+    for (name, &flags) in graphics.textures.iter().zip(graphics.texture_flags.iter()) {
+        put_bytes(buf, name.as_bytes());
+        put_i32(buf, flags);
+    }
+    put_len(buf, graphics.lightmaps.len());
+    for lightmap in &graphics.lightmaps {
+        put_u32(buf, lightmap.width);
+        put_u32(buf, lightmap.height);
+        put_bytes(buf, &lightmap.data);
+    }
+    put_len(buf, graphics.batches.len());
+    for batch in &graphics.batches {
+        put_i32(buf, batch.texture);
+        put_i32(buf, batch.lightmap);
+        put_u32(buf, batch.index_start);
+        put_u32(buf, batch.index_count);
+        put_u32s(buf, &batch.faces);
+    }
+    match graphics.lightgrid {
+        Some(ref grid) => {
+            buf.push(1);
+            put_floats(buf, &grid.origin);
+            put_floats(buf, &grid.cell_size);
+            for &dim in &grid.dims {
+                put_len(buf, dim);
+            }
+            put_len(buf, grid.cells.len());
+            for cell in &grid.cells {
+                buf.extend(cell.ambient.iter().cloned());
+                buf.extend(cell.directed.iter().cloned());
+                buf.push(cell.lng);
+                buf.push(cell.lat);
+            }
+        },
+        None => buf.push(0),
+    }
+}
+
+/// Serializes a map whose BSP and materials have the given `source_key`. The
+/// graphics should be straight from `import_graphics_data`, before materials are
+/// attached, since those are read from their scripts every time.
+pub fn write(key: u64, map: &Map, graphics: &GraphicsMapData) -> Vec<u8> {
+    let mut buf = vec![];
+    buf.extend(MAGIC.iter().cloned());
+    put_u32(&mut buf, VERSION);
+    buf.write_u64::<LittleEndian>(key).unwrap();
+    put_map(&mut buf, map);
+    put_graphics(&mut buf, graphics);
+    buf
+}
+
+struct Reader<'a> {
+    cursor: Cursor<&'a [u8]>,
+}
+impl<'a> Reader<'a> {
+    fn u8(&mut self) -> Result<u8, CacheError> {
+        Ok(try!(self.cursor.read_u8()))
+    }
+    fn u32(&mut self) -> Result<u32, CacheError> {
+        Ok(try!(self.cursor.read_u32::<LittleEndian>()))
+    }
+    fn i32(&mut self) -> Result<i32, CacheError> {
+        Ok(try!(self.cursor.read_i32::<LittleEndian>()))
+    }
+    fn f32(&mut self) -> Result<f32, CacheError> {
+        Ok(try!(self.cursor.read_f32::<LittleEndian>()))
+    }
+
+    /// A count of items that are at least `item_size` bytes each. Counts that can't
+    /// fit in what's left of the file are refused before anything is allocated.
+    fn len(&mut self, item_size: usize) -> Result<usize, CacheError> {
+        let len = try!(self.u32()) as usize;
+        let left = self.cursor.get_ref().len() - self.cursor.position() as usize;
+        if len.saturating_mul(item_size) > left {
+            return Err(CacheError::Truncated);
+        }
+        Ok(len)
+    }
+
+    fn bytes(&mut self) -> Result<Vec<u8>, CacheError> {
+        let len = try!(self.len(1));
+        let start = self.cursor.position() as usize;
+        self.cursor.set_position((start + len) as u64);
+        Ok(self.cursor.get_ref()[start..start + len].to_vec())
+    }
+    fn u32s(&mut self) -> Result<Vec<u32>, CacheError> {
+        let len = try!(self.len(4));
+        (0..len).map(|_| self.u32()).collect()
+    }
+    fn floats(&mut self, out: &mut [f32]) -> Result<(), CacheError> {
+        for v in out.iter_mut() {
+            *v = try!(self.f32());
+        }
+        Ok(())
+    }
+    fn plane(&mut self) -> Result<Plane, CacheError> {
+        let mut v = [0.0; 4];
+        try!(self.floats(&mut v));
+        Ok(Plane { norm: na::Vec3::new(v[0], v[1], v[2]), dist: v[3] })
+    }
+    fn aabb(&mut self) -> Result<Aabb, CacheError> {
+        let mut v = [0.0; 6];
+        try!(self.floats(&mut v));
+        Ok(Aabb::new(na::Pnt3::new(v[0], v[1], v[2]), na::Pnt3::new(v[3], v[4], v[5])))
+    }
+}
+
+fn read_map(r: &mut Reader) -> Result<Map, CacheError> {
+    let n_inodes = try!(r.len(24));
+    let mut inodes = Vec::with_capacity(n_inodes);
+    for _ in 0..n_inodes {
+        let plane = try!(r.plane());
+        inodes.push(bsp::InnerNode { plane: plane, pos: try!(r.i32()), neg: try!(r.i32()) });
+    }
+    let n_leaves = try!(r.len(48));
+    let mut leaves = Vec::with_capacity(n_leaves);
+    for _ in 0..n_leaves {
+        leaves.push(bsp::Leaf {
+            cluster: try!(r.i32()),
+            area: try!(r.i32()),
+            bounds: try!(r.aabb()),
+            leafface: try!(r.i32()),
+            n_leaffaces: try!(r.i32()),
+            leafbrush: try!(r.i32()),
+            n_leafbrushes: try!(r.i32()),
+        });
+    }
+    let n_brushes = try!(r.len(4));
+    let mut brushes = Vec::with_capacity(n_brushes);
+    for _ in 0..n_brushes {
+        let n_sides = try!(r.len(24));
+        let mut sides = Vec::with_capacity(n_sides);
+        for _ in 0..n_sides {
+            let plane = try!(r.plane());
+            sides.push(bsp::BrushSide { plane: plane, flags: try!(r.i32()), contents: try!(r.i32()) });
+        }
+        brushes.push(bsp::Brush { sides: sides });
+    }
+    let leafbrushes = try!(r.u32s());
+    let leaffaces = try!(r.u32s());
+    let visdata = match try!(r.u8()) {
+        0 => None,
+        _ => {
+            let n_clusters = try!(r.u32());
+            let cluster_size = try!(r.u32());
+            let bits = try!(r.bytes());
+            let (n_clusters, cluster_size) = try!(check_visdata(n_clusters as i32, cluster_size as i32, bits.len()));
+            if bits.len() != n_clusters * cluster_size {
+                return Err(CacheError::Corrupt("visdata"));
+            }
+            Some(VisData { n_clusters: n_clusters, cluster_size: cluster_size, bits: bits })
+        },
+    };
+
+    for (record, node) in inodes.iter().enumerate() {
+        try!(check_child("nodes", record, node.pos, inodes.len(), leaves.len()));
+        try!(check_child("nodes", record, node.neg, inodes.len(), leaves.len()));
+    }
+    for (record, leaf) in leaves.iter().enumerate() {
+        try!(check_range("leaves", record, leaf.leafface, leaf.n_leaffaces, leaffaces.len()));
+        try!(check_range("leaves", record, leaf.leafbrush, leaf.n_leafbrushes, leafbrushes.len()));
+        if let Some(ref visdata) = visdata {
+            if leaf.cluster >= 0 {
+                try!(check_index("leaves", record, leaf.cluster, visdata.n_clusters));
+            }
+        }
+    }
+    for (record, &brush) in leafbrushes.iter().enumerate() {
+        try!(check_index("leafbrushes", record, brush as i32, brushes.len()));
+    }
+
+    let n_models = try!(r.len(32));
+    let mut models = Vec::with_capacity(n_models);
+    for record in 0..n_models {
+        let model = Model { brush: try!(r.u32()), n_brushes: try!(r.u32()), bounds: try!(r.aabb()) };
+        try!(check_range("models", record, model.brush as i32, model.n_brushes as i32, brushes.len()));
+        models.push(model);
+    }
+    let n_entities = try!(r.len(5));
+    let mut entities = Vec::with_capacity(n_entities);
+    for record in 0..n_entities {
+        let model = try!(r.u32());
+        try!(check_index("entities", record, model as i32, models.len()));
+        let kind = match try!(r.u8()) {
+            0 => EntityKind::OutOfBounds,
+            1 => EntityKind::Goal,
+            _ => return Err(CacheError::Corrupt("entities")),
+        };
+        entities.push(Entity { model: model, kind: kind });
+    }
+
+    let broadphase = Broadphase::new(&models, &entities);
+    Ok(Map {
+        bsp: bsp::Tree {
+            inodes: inodes,
+            leaves: leaves,
+            brushes: brushes,
+            leafbrushes: leafbrushes,
+            leaffaces: leaffaces,
+            visdata: visdata,
+        },
+        models: models,
+        entities: entities,
+        broadphase: broadphase,
+    })
+}
+
+fn read_graphics(r: &mut Reader) -> Result<GraphicsMapData, CacheError> {
+    let n_vertices = try!(r.len(56));
+    let mut vertices = Vec::with_capacity(n_vertices);
+    for _ in 0..n_vertices {
+        let mut vertex = MapVertex {
+            position: [0.0; 3],
+            texcoords: [0.0; 2],
+            lightmaptexcoords: [0.0; 2],
+            normal: [0.0; 3],
+            color: [0.0; 4],
+        };
+        try!(r.floats(&mut vertex.position));
+        try!(r.floats(&mut vertex.texcoords));
+        try!(r.floats(&mut vertex.lightmaptexcoords));
+        try!(r.floats(&mut vertex.normal));
+        try!(r.floats(&mut vertex.color));
+        vertices.push(vertex);
+    }
+    let indices = try!(r.u32s());
+    let n_faces = try!(r.len(16));
+    let mut faces = Vec::with_capacity(n_faces);
+    for _ in 0..n_faces {
+        faces.push(MapFace {
+            texture: try!(r.i32()),
+            lightmap: try!(r.i32()),
+            index_start: try!(r.u32()),
+            index_count: try!(r.u32()),
+            material: None,
+        });
+    }
+    let n_textures = try!(r.len(8));
+    let mut textures = Vec::with_capacity(n_textures);
+    let mut texture_flags = Vec::with_capacity(n_textures);
+    for _ in 0..n_textures {
+        textures.push(String::from_utf8_lossy(&try!(r.bytes())).into_owned());
+        texture_flags.push(try!(r.i32()));
+    }
+    let n_lightmaps = try!(r.len(12));
+    let mut lightmaps = Vec::with_capacity(n_lightmaps);
+    for _ in 0..n_lightmaps {
+        let width = try!(r.u32());
+        let height = try!(r.u32());
+        let data = try!(r.bytes());
+        if data.len() != width as usize * height as usize * 3 {
+            return Err(CacheError::Corrupt("lightmap"));
+        }
+        lightmaps.push(Lightmap { width: width, height: height, data: data });
+    }
+    let n_batches = try!(r.len(20));
+    let mut batches = Vec::with_capacity(n_batches);
+    for _ in 0..n_batches {
+        batches.push(Batch {
+            texture: try!(r.i32()),
+            lightmap: try!(r.i32()),
+            index_start: try!(r.u32()),
+            index_count: try!(r.u32()),
+            faces: try!(r.u32s()),
+        });
+    }
+
+    for (record, &index) in indices.iter().enumerate() {
+        try!(check_index("indices", record, index as i32, vertices.len()));
+    }
+    for (record, face) in faces.iter().enumerate() {
+        try!(check_index("faces", record, face.texture, textures.len()));
+        // Negative lightmaps mean the face doesn't have one.
+        if face.lightmap >= 0 {
+            try!(check_index("faces", record, face.lightmap, lightmaps.len()));
+        }
+        try!(check_range("faces", record, face.index_start as i32, face.index_count as i32, indices.len()));
+    }
+    for (record, batch) in batches.iter().enumerate() {
+        try!(check_index("batches", record, batch.texture, textures.len()));
+        if batch.lightmap >= 0 {
+            try!(check_index("batches", record, batch.lightmap, lightmaps.len()));
+        }
+        try!(check_range("batches", record, batch.index_start as i32, batch.index_count as i32, indices.len()));
+        for &face in &batch.faces {
+            try!(check_index("batches", record, face as i32, faces.len()));
+        }
+    }
+    let lightgrid = match try!(r.u8()) {
+        0 => None,
+        _ => {
+            let mut origin = [0.0; 3];
+            let mut cell_size = [0.0; 3];
+            try!(r.floats(&mut origin));
+            try!(r.floats(&mut cell_size));
+            let dims = [try!(r.u32()) as usize, try!(r.u32()) as usize, try!(r.u32()) as usize];
+            let n_cells = try!(r.len(8));
+            let mut cells = Vec::with_capacity(n_cells);
+            for _ in 0..n_cells {
+                let mut cell = [0u8; 8];
+                for b in cell.iter_mut() {
+                    *b = try!(r.u8());
+                }
+                cells.push(LightCell {
+                    ambient: [cell[0], cell[1], cell[2]],
+                    directed: [cell[3], cell[4], cell[5]],
+                    lng: cell[6],
+                    lat: cell[7],
+                });
+            }
+            let n_expected = dims.iter().fold(Some(1usize), |n, &dim| n.and_then(|n| n.checked_mul(dim)));
+            if dims.iter().any(|&dim| dim == 0) || n_expected != Some(cells.len()) {
+                return Err(CacheError::Corrupt("lightgrid"));
+            }
+            Some(LightGrid { origin: origin, cell_size: cell_size, dims: dims, cells: cells })
+        },
+    };
+
+    Ok(GraphicsMapData {
+        vertices: vertices,
+        indices: indices,
+        faces: faces,
+        textures: textures,
+        texture_flags: texture_flags,
+        materials: vec![],
+        lightmaps: lightmaps,
+        batches: batches,
+        lightgrid: lightgrid,
+    })
+}
+
+/// Reads a cache written by `write`, if it was made from what `key` identifies.
+pub fn read(data: &[u8], key: u64) -> Result<(Map, GraphicsMapData), CacheError> {
+    if data.len() < 4 || &data[..4] != &MAGIC[..] {
+        return Err(CacheError::BadMagic);
+    }
+    let mut r = Reader { cursor: Cursor::new(data) };
+    r.cursor.set_position(4);
+    let version = try!(r.u32());
+    if version != VERSION {
+        return Err(CacheError::UnsupportedVersion(version));
+    }
+    if try!(r.cursor.read_u64::<LittleEndian>()) != key {
+        return Err(CacheError::Stale);
+    }
+    let map = try!(read_map(&mut r));
+    let graphics = try!(read_graphics(&mut r));
+    // Leaf faces are the only part of the map that refers to the graphics.
+    for (record, &face) in map.bsp.leaffaces.iter().enumerate() {
+        try!(check_index("leaffaces", record, face as i32, graphics.faces.len()));
+    }
+    Ok((map, graphics))
+}
+
+/// Where the cache of the map asset `name` goes. The whole asset path is kept in
+/// the file name, with separators escaped, so maps in different directories don't
+/// share a cache.
+pub fn cache_path(name: &str) -> PathBuf {
+    let dir = match env::var_os(CACHE_DIR_VAR) {
+        Some(dir) => PathBuf::from(dir),
+        None => PathBuf::from(DEFAULT_CACHE_DIR),
+    };
+    let mut file_name = String::new();
+    for c in name.chars() {
+        match c {
+            '%' => file_name.push_str("%25"),
+            '/' => file_name.push_str("%2F"),
+            '\\' => file_name.push_str("%5C"),
+            ':' => file_name.push_str("%3A"),
+            c => file_name.push(c),
+        }
+    }
+    dir.join(format!("{}.v0mc", file_name))
+}
+
+fn read_file(path: &Path) -> Option<Vec<u8>> {
+    let mut data = vec![];
+    match File::open(path).and_then(|mut file| file.read_to_end(&mut data)) {
+        Ok(_) => Some(data),
+        Err(_) => None,
+    }
+}
+
+fn write_file(path: &Path, data: &[u8]) -> ::std::io::Result<()> {
+    if let Some(dir) = path.parent() {
+        try!(fs::create_dir_all(dir));
+    }
+    File::create(path).and_then(|mut file| file.write_all(data))
+}
+
+/// Loads the map asset `name`, whose contents are `bsp`, from its cache, with its
+/// lightmaps packed into atlases. Without a usable cache, it's imported and the
+/// cache is written for next time. Not being able to write the cache doesn't stop
+/// the map loading.
+pub fn load(name: &str, bsp: &[u8], materials: &[Material]) -> Result<(Map, GraphicsMapData), BspError> {
+    let key = source_key(bsp, materials);
+    let path = cache_path(name);
+    if let Some(data) = read_file(&path) {
+        match read(&data, key) {
+            Ok(loaded) => return Ok(loaded),
+            Err(e) => println!("Not using {}: {}", path.display(), e),
+        }
+    }
+
+    let map = try!(q3_import::import_with_materials(bsp, materials));
+    let mut graphics = try!(q3_import::import_graphics_data(bsp));
+    lightmaps::pack_atlases(&mut graphics, lightmaps::DEFAULT_ATLAS_SIZE);
+    if let Err(e) = write_file(&path, &write(key, &map, &graphics)) {
+        println!("Couldn't write {}: {}", path.display(), e);
+    }
+    Ok((map, graphics))
+}
+
+#[cfg(test)]
+mod test {
+    use batch::Batch;
+    use builder::{self, MapBuilder};
+    use lightgrid::{LightCell, LightGrid};
+    use na;
+    use shader;
+    use vis::VisData;
+    use {
+        EntityKind,
+        GraphicsMapData,
+        Lightmap,
+        MapFace,
+        MapVertex
+    };
+    use super::{
+        cache_path,
+        put_graphics,
+        read,
+        source_key,
+        write,
+        CacheError,
+        MAGIC
+    };
+
+    fn test_graphics() -> GraphicsMapData {
+        let vertex = MapVertex {
+            position: [1.0, 2.0, 3.0],
+            texcoords: [0.5, 0.25],
+            lightmaptexcoords: [0.125, 0.75],
+            normal: [0.0, -1.0, 0.0],
+            color: [1.0, 0.5, 0.25, 1.0],
+        };
+        GraphicsMapData {
+            vertices: vec![vertex; 3],
+            indices: vec![0, 1, 2],
+            faces: vec![MapFace { texture: 0, lightmap: 0, index_start: 0, index_count: 3, material: None }],
+            textures: vec!["textures/base/floor".to_string()],
+            texture_flags: vec![4],
+            materials: vec![],
+            lightmaps: vec![Lightmap { width: 2, height: 1, data: vec![1, 2, 3, 4, 5, 6] }],
+            batches: vec![],
+            lightgrid: Some(LightGrid {
+                origin: [0.0, 0.0, 0.0],
+                cell_size: [64.0, 64.0, 128.0],
+                dims: [1, 1, 1],
+                cells: vec![LightCell { ambient: [1, 2, 3], directed: [4, 5, 6], lng: 7, lat: 8 }],
+            }),
+        }
+    }
+
+    #[test]
+    fn roundtrip() {
+        let mut builder = MapBuilder::new();
+        builder.add_box(na::Pnt3::new(-64.0, 0.0, -64.0), na::Pnt3::new(64.0, 32.0, 64.0));
+        builder.add_box(na::Pnt3::new(-64.0, -128.0, -64.0), na::Pnt3::new(-32.0, 0.0, 64.0));
+        let mut map = builder.build();
+        map.bsp.visdata = Some(VisData { n_clusters: 2, cluster_size: 1, bits: vec![3, 2] });
+        let graphics = test_graphics();
+
+        let data = write(42, &map, &graphics);
+        assert_eq!(&data[..4], &MAGIC[..]);
+        let (read_map, read_graphics) = read(&data, 42).unwrap();
+        assert_eq!(read_map, map);
+        assert_eq!(read_graphics, graphics);
+    }
+
+    #[test]
+    fn refuses_stale_and_broken_caches() {
+        let mut builder = MapBuilder::new();
+        builder.add_box(na::Pnt3::new(-64.0, 0.0, -64.0), na::Pnt3::new(64.0, 32.0, 64.0));
+        let map = builder.build();
+        let data = write(42, &map, &test_graphics());
+        match read(&data, 43) {
+            Err(CacheError::Stale) => (),
+            other => panic!("{:?}", other.err()),
+        }
+        match read(&data[..data.len() - 1], 42) {
+            Err(CacheError::Truncated) => (),
+            other => panic!("{:?}", other.err()),
+        }
+        match read(b"IBSP....", 42) {
+            Err(CacheError::BadMagic) => (),
+            other => panic!("{:?}", other.err()),
+        }
+        let mut old = data.clone();
+        old[4] = 0;
+        match read(&old, 42) {
+            Err(CacheError::UnsupportedVersion(0)) => (),
+            other => panic!("{:?}", other.err()),
+        }
+    }
+
+    fn expect_corrupt(data: &[u8]) {
+        match read(data, 42) {
+            Err(CacheError::Corrupt(_)) => (),
+            other => panic!("{:?}", other.err()),
+        }
+    }
+
+    #[test]
+    fn refuses_dangling_indices() {
+        let mut builder = MapBuilder::new();
+        builder.add_box(na::Pnt3::new(-64.0, 0.0, -64.0), na::Pnt3::new(64.0, 32.0, 64.0));
+        builder.add_box(na::Pnt3::new(-64.0, -128.0, -64.0), na::Pnt3::new(-32.0, 0.0, 64.0));
+        let trigger = builder::box_brush(na::Pnt3::new(-8.0, -8.0, -8.0), na::Pnt3::new(8.0, 8.0, 8.0), 0);
+        builder.add_entity(EntityKind::Goal, vec![trigger]);
+        let map = builder.build();
+        let graphics = test_graphics();
+        assert!(!map.bsp.inodes.is_empty());
+
+        let mut bad = builder.build();
+        bad.bsp.inodes[0].pos = bad.bsp.inodes.len() as i32;
+        expect_corrupt(&write(42, &bad, &graphics));
+        let mut bad = builder.build();
+        bad.bsp.leaves[0].n_leafbrushes = bad.bsp.leafbrushes.len() as i32 + 1;
+        expect_corrupt(&write(42, &bad, &graphics));
+        let mut bad = builder.build();
+        bad.bsp.leafbrushes[0] = bad.bsp.brushes.len() as u32;
+        expect_corrupt(&write(42, &bad, &graphics));
+        let mut bad = builder.build();
+        bad.bsp.leaffaces = vec![1];
+        expect_corrupt(&write(42, &bad, &graphics));
+        let mut bad = builder.build();
+        bad.bsp.visdata = Some(VisData { n_clusters: 16, cluster_size: 1, bits: vec![0; 16] });
+        expect_corrupt(&write(42, &bad, &graphics));
+        let mut bad = builder.build();
+        bad.models[1].n_brushes = 0xffffffff;
+        expect_corrupt(&write(42, &bad, &graphics));
+        let mut bad = builder.build();
+        bad.entities[0].model = bad.models.len() as u32;
+        expect_corrupt(&write(42, &bad, &graphics));
+
+        // Entity kinds we don't know about aren't goals.
+        let mut data = write(42, &map, &graphics);
+        let mut graphics_data = vec![];
+        put_graphics(&mut graphics_data, &graphics);
+        let kind = data.len() - graphics_data.len() - 1;
+        assert_eq!(data[kind], 1);
+        data[kind] = 2;
+        expect_corrupt(&data);
+
+        let mut bad = test_graphics();
+        bad.indices[2] = 3;
+        expect_corrupt(&write(42, &map, &bad));
+        let mut bad = test_graphics();
+        bad.faces[0].texture = 1;
+        expect_corrupt(&write(42, &map, &bad));
+        let mut bad = test_graphics();
+        bad.faces[0].index_count = 4;
+        expect_corrupt(&write(42, &map, &bad));
+        let mut bad = test_graphics();
+        bad.batches = vec![Batch { texture: 0, lightmap: 0, index_start: 0, index_count: 3, faces: vec![1] }];
+        expect_corrupt(&write(42, &map, &bad));
+        let mut bad = test_graphics();
+        bad.lightgrid.as_mut().unwrap().dims = [2, 1, 1];
+        expect_corrupt(&write(42, &map, &bad));
+
+        assert!(read(&write(42, &map, &graphics), 42).is_ok());
+    }
+
+    #[test]
+    fn keys_change_with_surfaceparms() {
+        let bsp = b"IBSP";
+        let plain = shader::parse("textures/a/b\n{\n}\n");
+        let nonsolid = shader::parse("textures/a/b\n{\n\tsurfaceparm nonsolid\n}\n");
+        assert_eq!(source_key(bsp, &[]), source_key(bsp, &plain));
+        assert!(source_key(bsp, &plain) != source_key(bsp, &nonsolid));
+        assert!(source_key(bsp, &[]) != source_key(b"IBSP2", &[]));
+    }
+
+    #[test]
+    fn cache_paths_keep_directories() {
+        let name = |path: &str| cache_path(path).file_name().unwrap().to_string_lossy().into_owned();
+        assert_eq!(name("maps/a/test.bsp"), "maps%2Fa%2Ftest.bsp.v0mc");
+        assert!(name("maps/a/test.bsp") != name("maps/b/test.bsp"));
+        assert!(name("maps/a_test.bsp") != name("maps/a/test.bsp"));
+        assert!(name("maps/a%2Ftest.bsp") != name("maps/a/test.bsp"));
+        assert!(name("maps\\a\\test.bsp") != name("maps/a/test.bsp"));
+    }
+}
@@ -0,0 +1,131 @@
+//! Dumps collision geometry as Wavefront OBJ, so it can be laid over the visual
+//! mesh in a modelling tool. The output only uses triangles, so it can also be
+//! read back with `wavefront_obj` the same way `vel0city_graphics::wavefront` does.
+
+use std::io::{self, Write};
+use Map;
+
+fn material_name(contents: i32) -> String {
+    format!("contents_{:08x}", contents)
+}
+
+/// Picks a color for a contents value. Solid stays grey so everything else stands out.
+fn contents_color(contents: i32) -> (f32, f32, f32) {
+    if contents == 1 {
+        return (0.6, 0.6, 0.6);
+    }
+    let hash = (contents as u32).wrapping_mul(2654435761);
+    let channel = |shift: u32| 0.25 + 0.75 * ((hash >> shift) & 0xff) as f32 / 255.0;
+    (channel(0), channel(8), channel(16))
+}
+
+/// Name of the OBJ object for a model, based on which entity (if any) uses it.
+fn model_name(map: &Map, modelidx: usize) -> String {
+    if modelidx == 0 {
+        return "world".to_string();
+    }
+    match map.entities.iter().position(|e| e.model as usize == modelidx) {
+        Some(entityidx) => format!("entity{}_{:?}", entityidx, map.entities[entityidx].kind),
+        None => format!("model{}", modelidx),
+    }
+}
+
+/// Writes the brushes of every model in `map` as an OBJ, one object per model and one
+/// material per contents value. If `mtllib` is given, it's referenced as the material library.
+pub fn write_obj<W: Write>(map: &Map, mtllib: Option<&str>, out: &mut W) -> io::Result<()> {
+    if let Some(mtllib) = mtllib {
+        try!(writeln!(out, "mtllib {}", mtllib));
+    }
+
+    // OBJ indices are global and start at 1.
+    let mut n_verts = 1;
+    for (modelidx, model) in map.models.iter().enumerate() {
+        try!(writeln!(out, "o {}", model_name(map, modelidx)));
+
+        let brushes = &map.bsp.brushes[model.brush as usize .. (model.brush + model.n_brushes) as usize];
+        for brush in brushes {
+            try!(writeln!(out, "usemtl {}", material_name(brush.contents())));
+            for winding in brush.polygons() {
+                for p in &winding.points {
+                    try!(writeln!(out, "v {} {} {}", p.x, p.y, p.z));
+                }
+                // Fan out from the first point.
+                for i in 1..winding.points.len() - 1 {
+                    try!(writeln!(out, "f {} {} {}", n_verts, n_verts + i, n_verts + i + 1));
+                }
+                n_verts += winding.points.len();
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Writes the material library to go with `write_obj`.
+pub fn write_mtl<W: Write>(map: &Map, out: &mut W) -> io::Result<()> {
+    let mut contents: Vec<i32> = map.bsp.brushes.iter().map(|brush| brush.contents()).collect();
+    contents.sort();
+    contents.dedup();
+
+    for contents in contents {
+        let (r, g, b) = contents_color(contents);
+        try!(writeln!(out, "newmtl {}", material_name(contents)));
+        try!(writeln!(out, "Kd {} {} {}", r, g, b));
+        try!(writeln!(out, "d 0.5"));
+        try!(writeln!(out, ""));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use na;
+    use std::collections::HashMap;
+    use wavefront_obj::obj::{self, Shape};
+    use bsp::{CONTENTS_PLAYERCLIP, CONTENTS_SOLID, CONTENTS_TRIGGER};
+    use builder::{box_brush, MapBuilder};
+    use EntityKind;
+    use super::{material_name, write_mtl, write_obj};
+
+    #[test]
+    fn parses_back() {
+        let mut builder = MapBuilder::new();
+        builder.add_brush(box_brush(na::Pnt3::new(-64.0, 0.0, -64.0), na::Pnt3::new(64.0, 16.0, 64.0), CONTENTS_SOLID));
+        builder.add_brush(box_brush(na::Pnt3::new(-64.0, -64.0, -64.0), na::Pnt3::new(-48.0, 0.0, 64.0), CONTENTS_PLAYERCLIP));
+        builder.add_entity(EntityKind::Goal, vec![box_brush(na::Pnt3::new(0.0, -32.0, 0.0), na::Pnt3::new(16.0, 0.0, 16.0), CONTENTS_TRIGGER)]);
+        let map = builder.build();
+
+        let mut out = vec![];
+        write_obj(&map, Some("map.mtl"), &mut out).unwrap();
+        let objects = obj::parse(String::from_utf8(out).unwrap()).unwrap().objects;
+        assert_eq!(objects.len(), map.models.len());
+        assert_eq!(objects[0].name, "world");
+        assert_eq!(objects[1].name, "entity0_Goal");
+
+        for (object, model) in objects.iter().zip(map.models.iter()) {
+            // Triangles per material, as written and as they should be.
+            let mut expected = HashMap::new();
+            for brush in &map.bsp.brushes[model.brush as usize .. (model.brush + model.n_brushes) as usize] {
+                let n_triangles = brush.polygons().iter().fold(0, |n, winding| n + winding.points.len() - 2);
+                *expected.entry(material_name(brush.contents())).or_insert(0) += n_triangles;
+            }
+            let mut found = HashMap::new();
+            for geometry in &object.geometry {
+                let n_triangles = geometry.shapes.iter().filter(|shape| match **shape {
+                    Shape::Triangle(..) => true,
+                    _ => false,
+                }).count();
+                *found.entry(geometry.material_name.clone().unwrap()).or_insert(0) += n_triangles;
+            }
+            assert_eq!(found, expected);
+        }
+
+        let mut mtl = vec![];
+        write_mtl(&map, &mut mtl).unwrap();
+        let mtl = String::from_utf8(mtl).unwrap();
+        for &contents in &[CONTENTS_SOLID, CONTENTS_PLAYERCLIP, CONTENTS_TRIGGER] {
+            let newmtl = format!("newmtl {}\n", material_name(contents));
+            assert_eq!(mtl.matches(&newmtl[..]).count(), 1);
+        }
+        assert_eq!(mtl.matches("newmtl").count(), 3);
+    }
+}
@@ -0,0 +1,541 @@
+//! Writes maps back out as Quake 3 (version 46) BSPs that `q3_import` can read again.
+//!
+//! Lightmaps have to be the BSP's own 128x128 ones, not atlases from
+//! `lightmaps::pack_atlases`. Only what we import gets written. Everything else (visibility, effects, patches,
+//! node bounds...) is left empty or zeroed.
+
+use byteorder::{LittleEndian, WriteBytesExt};
+use na;
+use bsp;
+use entities::{self, EntityDef};
+use lightgrid::{LightGrid, DEFAULT_CELL_SIZE};
+use {
+    Map,
+    EntityKind,
+    GraphicsMapData
+};
+
+const LUMP_ENTITIES: usize = 0;
+const LUMP_TEXTURES: usize = 1;
+const LUMP_PLANES: usize = 2;
+const LUMP_NODES: usize = 3;
+const LUMP_LEAVES: usize = 4;
+const LUMP_LEAFFACES: usize = 5;
+const LUMP_LEAFBRUSHES: usize = 6;
+const LUMP_MODELS: usize = 7;
+const LUMP_BRUSHES: usize = 8;
+const LUMP_BRUSHSIDES: usize = 9;
+const LUMP_VERTICES: usize = 10;
+const LUMP_MESHVERTS: usize = 11;
+const LUMP_FACES: usize = 13;
+const LUMP_LIGHTMAPS: usize = 14;
+const LUMP_LIGHTVOLS: usize = 15;
+const LUMP_VISDATA: usize = 16;
+const N_LUMPS: usize = 17;
+
+const FACE_SIZE: usize = 104;
+
+/// What goal triggers are set to fire, so the importer can tell them apart from other triggers.
+const GOAL_TARGET: &'static str = "vel0city_goal";
+
+// Writing to a Vec can't fail, so these don't bother returning errors. The map
+// cache writes with them too.
+pub fn put_u32(buf: &mut Vec<u8>, v: u32) {
+    buf.write_u32::<LittleEndian>(v).unwrap();
+}
+pub fn put_i32(buf: &mut Vec<u8>, v: i32) {
+    buf.write_i32::<LittleEndian>(v).unwrap();
+}
+pub fn put_f32(buf: &mut Vec<u8>, v: f32) {
+    buf.write_f32::<LittleEndian>(v).unwrap();
+}
+
+/// Writes a vector in Quake's axes. This undoes the swizzle `q3_import` does.
+fn put_vec3(buf: &mut Vec<u8>, v: &na::Vec3<f32>) {
+    put_f32(buf, v.x);
+    put_f32(buf, v.z);
+    put_f32(buf, -v.y);
+}
+
+fn put_name(buf: &mut Vec<u8>, name: &str) {
+    let bytes = name.as_bytes();
+    let len = ::std::cmp::min(bytes.len(), 63);
+    buf.extend(bytes[..len].iter().cloned());
+    buf.extend((len..64).map(|_| 0u8));
+}
+
+fn add_plane(planes: &mut Vec<u8>, plane: &bsp::Plane) -> i32 {
+    let idx = planes.len() / 16;
+    put_vec3(planes, &plane.norm);
+    put_f32(planes, plane.dist);
+    idx as i32
+}
+
+/// Finds or makes a texture for brush sides with the given flags and contents.
+fn collision_texture(textures: &mut Vec<(String, i32, i32)>, flags: i32, contents: i32) -> i32 {
+    let name = format!("vel0city/collision_{:x}_{:x}", flags, contents);
+    match textures.iter().position(|&(ref n, _, _)| *n == name) {
+        Some(idx) => idx as i32,
+        None => {
+            textures.push((name, flags, contents));
+            (textures.len() - 1) as i32
+        }
+    }
+}
+
+/// Writes `defs` back, with a worldspawn first, then adds triggers for any of
+/// `map`'s entities whose model none of them use. The worldspawn's `gridsize`
+/// is set to match the light grid, if there is one.
+fn entity_lump(map: &Map, defs: &[EntityDef], lightgrid: Option<&LightGrid>) -> Vec<u8> {
+    let mut defs = defs.to_vec();
+    if defs.first().and_then(|def| def.get("classname")) != Some("worldspawn") {
+        defs.insert(0, EntityDef::new().with("classname", "worldspawn"));
+    }
+    if let Some(lightgrid) = lightgrid {
+        let worldspawn = &mut defs[0];
+        worldspawn.pairs.retain(|&(ref key, _)| key != "gridsize");
+        let cell_size = lightgrid.cell_size;
+        if cell_size != DEFAULT_CELL_SIZE {
+            let gridsize = format!("{} {} {}", cell_size[0], cell_size[1], cell_size[2]);
+            worldspawn.pairs.push(("gridsize".to_string(), gridsize));
+        }
+    }
+    let has_goal = defs.iter().any(|def| def.get("targetname") == Some(GOAL_TARGET));
+    let mut needs_goal = false;
+    for entity in &map.entities {
+        if defs.iter().any(|def| def.model() == Some(entity.model)) {
+            continue;
+        }
+        let model = format!("*{}", entity.model);
+        defs.push(match entity.kind {
+            EntityKind::OutOfBounds => EntityDef::new()
+                .with("classname", "trigger_hurt")
+                .with("model", &model)
+                .with("dmg", "9999"),
+            EntityKind::Goal => {
+                needs_goal = true;
+                EntityDef::new()
+                    .with("classname", "trigger_multiple")
+                    .with("model", &model)
+                    .with("target", GOAL_TARGET)
+            }
+        });
+    }
+    if needs_goal && !has_goal {
+        defs.push(EntityDef::new()
+                  .with("classname", "target_stopTimer")
+                  .with("targetname", GOAL_TARGET));
+    }
+
+    let mut lump = entities::write(&defs).into_bytes();
+    lump.push(0);
+    lump
+}
+
+/// Serializes `map`, and the CPU side of its graphics if given, as an IBSP file.
+/// `defs` is the entity lump the map was imported with, empty if it has none.
+pub fn export(map: &Map, defs: &[EntityDef], graphics: Option<&GraphicsMapData>) -> Vec<u8> {
+    let mut lumps: Vec<Vec<u8>> = (0..N_LUMPS).map(|_| vec![]).collect();
+
+    lumps[LUMP_ENTITIES] = entity_lump(map, defs, graphics.and_then(|graphics| graphics.lightgrid.as_ref()));
+
+    // Face textures keep their indices, collision-only textures go after them.
+    let mut textures: Vec<(String, i32, i32)> = match graphics {
+        Some(graphics) => graphics.textures.iter()
+            .enumerate()
+            .map(|(texidx, name)| (name.clone(), graphics.texture_flags.get(texidx).cloned().unwrap_or(0), 0))
+            .collect(),
+        None => vec![],
+    };
+
+    let tree = &map.bsp;
+    let mut planes = vec![];
+
+    {
+        let nodes = &mut lumps[LUMP_NODES];
+        for node in &tree.inodes {
+            put_i32(nodes, add_plane(&mut planes, &node.plane));
+            put_i32(nodes, node.pos);
+            put_i32(nodes, node.neg);
+            // Node bounds aren't used for collision.
+            for _ in 0..6 {
+                put_i32(nodes, 0);
+            }
+        }
+    }
+
+    {
+        let leaves = &mut lumps[LUMP_LEAVES];
+        for leaf in &tree.leaves {
+            put_i32(leaves, leaf.cluster);
+            put_i32(leaves, leaf.area);
+            // Leaf bounds are whole units, in Quake's axes.
+            let (mins, maxs) = if leaf.bounds.is_empty() {
+                (na::Pnt3::new(0.0, 0.0, 0.0), na::Pnt3::new(0.0, 0.0, 0.0))
+            } else {
+                (leaf.bounds.mins, leaf.bounds.maxs)
+            };
+            for &v in &[mins.x.floor(), mins.z.floor(), (-maxs.y).floor(), maxs.x.ceil(), maxs.z.ceil(), (-mins.y).ceil()] {
+                put_i32(leaves, v as i32);
+            }
+            // Leaf faces point into the face lump, so they only make sense with graphics.
+            if graphics.is_some() {
+                put_i32(leaves, leaf.leafface);
+                put_i32(leaves, leaf.n_leaffaces);
+            } else {
+                put_i32(leaves, 0);
+                put_i32(leaves, 0);
+            }
+            put_i32(leaves, leaf.leafbrush);
+            put_i32(leaves, leaf.n_leafbrushes);
+        }
+    }
+
+    if graphics.is_some() {
+        for &leafface in &tree.leaffaces {
+            put_i32(&mut lumps[LUMP_LEAFFACES], leafface as i32);
+        }
+    }
+
+    for &leafbrush in &tree.leafbrushes {
+        put_i32(&mut lumps[LUMP_LEAFBRUSHES], leafbrush as i32);
+    }
+
+    let mut n_brushsides = 0;
+    for brush in &tree.brushes {
+        let mut brushtexture = 0;
+        for (sideidx, side) in brush.sides.iter().enumerate() {
+            let texture = collision_texture(&mut textures, side.flags, side.contents);
+            if sideidx == 0 {
+                brushtexture = texture;
+            }
+            let brushsides = &mut lumps[LUMP_BRUSHSIDES];
+            put_i32(brushsides, add_plane(&mut planes, &side.plane));
+            put_i32(brushsides, texture);
+        }
+
+        let brushes = &mut lumps[LUMP_BRUSHES];
+        put_i32(brushes, n_brushsides);
+        put_i32(brushes, brush.sides.len() as i32);
+        put_i32(brushes, brushtexture);
+        n_brushsides += brush.sides.len() as i32;
+    }
+
+    {
+        let models = &mut lumps[LUMP_MODELS];
+        let n_faces = graphics.map_or(0, |g| g.faces.len());
+        for (modelidx, model) in map.models.iter().enumerate() {
+            let (mins, maxs) = (model.bounds.mins, model.bounds.maxs);
+            // Y flips when going back to Quake's Z, so mins and maxs trade places on it.
+            put_vec3(models, &na::Vec3::new(mins.x, maxs.y, mins.z));
+            put_vec3(models, &na::Vec3::new(maxs.x, mins.y, maxs.z));
+            // All the faces belong to the world.
+            put_i32(models, 0);
+            put_i32(models, if modelidx == 0 { n_faces as i32 } else { 0 });
+            put_i32(models, model.brush as i32);
+            put_i32(models, model.n_brushes as i32);
+        }
+    }
+
+    if let Some(graphics) = graphics {
+        let vertices = &mut lumps[LUMP_VERTICES];
+        for vert in &graphics.vertices {
+            put_vec3(vertices, &na::Vec3::new(vert.position[0], vert.position[1], vert.position[2]));
+            put_f32(vertices, 1.0 - vert.texcoords[0]);
+            put_f32(vertices, 1.0 - vert.texcoords[1]);
+            put_f32(vertices, vert.lightmaptexcoords[0]);
+            put_f32(vertices, vert.lightmaptexcoords[1]);
+            put_vec3(vertices, &na::Vec3::new(vert.normal[0], vert.normal[1], vert.normal[2]));
+            for &c in &vert.color {
+                vertices.push((na::clamp(c, 0.0, 1.0) * 255.0).round() as u8);
+            }
+        }
+    }
+
+    if let Some(graphics) = graphics {
+        // Our indices are already absolute, so every face starts at vertex 0.
+        for &index in &graphics.indices {
+            put_i32(&mut lumps[LUMP_MESHVERTS], index as i32);
+        }
+
+        let faces = &mut lumps[LUMP_FACES];
+        for face in &graphics.faces {
+            let start = faces.len();
+            put_i32(faces, face.texture);
+            put_i32(faces, -1); // effect
+            put_i32(faces, 1); // polygon
+            put_i32(faces, 0);
+            put_i32(faces, graphics.vertices.len() as i32);
+            put_i32(faces, face.index_start as i32);
+            put_i32(faces, face.index_count as i32);
+            put_i32(faces, face.lightmap);
+            // Lightmap placement, face normal and patch size aren't kept.
+            let end = start + FACE_SIZE;
+            faces.extend((faces.len()..end).map(|_| 0u8));
+        }
+
+        // The grid's layout isn't written, the importer works it out from the world's bounds.
+        if let Some(ref lightgrid) = graphics.lightgrid {
+            let lightvols = &mut lumps[LUMP_LIGHTVOLS];
+            for cell in &lightgrid.cells {
+                lightvols.extend(cell.ambient.iter().cloned());
+                lightvols.extend(cell.directed.iter().cloned());
+                lightvols.push(cell.lng);
+                lightvols.push(cell.lat);
+            }
+        }
+
+        let lightmaps = &mut lumps[LUMP_LIGHTMAPS];
+        for lightmap in &graphics.lightmaps {
+            lightmaps.extend(lightmap.data.iter().cloned());
+        }
+    }
+
+    lumps[LUMP_PLANES] = planes;
+
+    if let Some(ref visdata) = tree.visdata {
+        let lump = &mut lumps[LUMP_VISDATA];
+        put_i32(lump, visdata.n_clusters as i32);
+        put_i32(lump, visdata.cluster_size as i32);
+        lump.extend(visdata.bits.iter().cloned());
+    }
+
+    {
+        let lump = &mut lumps[LUMP_TEXTURES];
+        for &(ref name, flags, contents) in &textures {
+            put_name(lump, name);
+            put_i32(lump, flags);
+            put_i32(lump, contents);
+        }
+    }
+
+    let mut out = vec![];
+    out.extend(b"IBSP".iter().cloned());
+    put_i32(&mut out, 46);
+
+    let mut offset = 8 + N_LUMPS * 8;
+    for lump in &lumps {
+        put_i32(&mut out, offset as i32);
+        put_i32(&mut out, lump.len() as i32);
+        offset += (lump.len() + 3) & !3;
+    }
+    for lump in &lumps {
+        out.extend(lump.iter().cloned());
+        while out.len() % 4 != 0 {
+            out.push(0);
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+mod test {
+    use na;
+    use builder::{
+        MapBuilder,
+        box_brush,
+        brush_from_planes,
+        CONTENTS_SOLID
+    };
+    use bsp::{
+        Plane,
+        CONTENTS_PLAYERCLIP,
+        SURF_SKY
+    };
+    use lightgrid::{
+        LightCell,
+        LightGrid,
+        DEFAULT_CELL_SIZE
+    };
+    use entities::{self, EntityDef};
+    use q3_import;
+    use vis::VisData;
+    use {
+        EntityKind,
+        GraphicsMapData,
+        Lightmap,
+        Map,
+        MapFace,
+        MapVertex
+    };
+    use super::export;
+
+    fn test_map() -> Map {
+        let mut builder = MapBuilder::new();
+        builder.add_box(na::Pnt3::new(-256.0, 0.0, -256.0), na::Pnt3::new(256.0, 16.0, 256.0));
+        builder.add_brush(box_brush(na::Pnt3::new(64.0, -128.0, -256.0), na::Pnt3::new(80.0, 0.0, 256.0), CONTENTS_PLAYERCLIP));
+        builder.add_brush(brush_from_planes(&[
+            Plane { norm: na::Vec3::new(-1.0, 0.0, 0.0), dist: 128.0 },
+            Plane { norm: na::Vec3::new(0.0, 1.0, 0.0), dist: 0.0 },
+            Plane { norm: na::Vec3::new(0.0, 0.0, 1.0), dist: 64.0 },
+            Plane { norm: na::Vec3::new(0.0, 0.0, -1.0), dist: 64.0 },
+            Plane { norm: na::normalize(&na::Vec3::new(1.0, -1.0, 0.0)), dist: -64.0 },
+        ], CONTENTS_SOLID));
+        builder.add_entity(EntityKind::OutOfBounds, vec![
+            box_brush(na::Pnt3::new(-1024.0, 512.0, -1024.0), na::Pnt3::new(1024.0, 600.0, 1024.0), CONTENTS_SOLID)
+        ]);
+        builder.add_entity(EntityKind::Goal, vec![
+            box_brush(na::Pnt3::new(200.0, -64.0, 200.0), na::Pnt3::new(250.0, 0.0, 250.0), CONTENTS_SOLID)
+        ]);
+        builder.build()
+    }
+
+    fn test_graphics() -> GraphicsMapData {
+        let vert = |x: f32, z: f32, s: f32, t: f32| MapVertex {
+            position: [x, 0.0, z],
+            texcoords: [s, t],
+            lightmaptexcoords: [s * 0.5, t * 0.5],
+            normal: [0.0, -1.0, 0.0],
+            color: [s, 1.0 - s, 0.0, 1.0],
+        };
+        GraphicsMapData {
+            vertices: vec![
+                vert(-64.0, -64.0, 0.0, 0.0),
+                vert(64.0, -64.0, 1.0, 0.0),
+                vert(64.0, 64.0, 1.0, 1.0),
+                vert(-64.0, 64.0, 0.0, 0.25),
+            ],
+            indices: vec![0, 1, 2, 0, 2, 3],
+            faces: vec![
+                MapFace { texture: 0, lightmap: 0, index_start: 0, index_count: 3, material: None },
+                MapFace { texture: 1, lightmap: -1, index_start: 3, index_count: 3, material: None },
+            ],
+            textures: vec!["textures/base/floor".to_string(), "textures/base/wall".to_string()],
+            texture_flags: vec![0, SURF_SKY],
+            materials: vec![],
+            lightmaps: vec![Lightmap {
+                width: 128,
+                height: 128,
+                data: (0..128).flat_map(|y| (0..128).flat_map(move |x| vec![x as u8, y as u8, 7].into_iter())).collect(),
+            }],
+            batches: vec![],
+            lightgrid: None,
+        }
+    }
+
+    #[test]
+    fn collision_roundtrip() {
+        let map = test_map();
+        let data = export(&map, &[], None);
+        assert_eq!(&data[0..4], b"IBSP");
+        assert_eq!(q3_import::import(&data).unwrap(), map);
+    }
+
+    #[test]
+    fn visdata_roundtrip() {
+        let mut map = test_map();
+        let n_leaves = map.bsp.leaves.len();
+        for (leafidx, leaf) in map.bsp.leaves.iter_mut().enumerate() {
+            leaf.cluster = leafidx as i32 - 1;
+            leaf.area = 1;
+        }
+        // Every cluster sees itself and nothing else.
+        let cluster_size = (n_leaves + 7) / 8;
+        let mut bits = vec![0; cluster_size * n_leaves];
+        for cluster in 0..n_leaves {
+            bits[cluster * cluster_size + cluster / 8] |= 1 << (cluster % 8);
+        }
+        map.bsp.visdata = Some(VisData {
+            n_clusters: n_leaves,
+            cluster_size: cluster_size,
+            bits: bits,
+        });
+
+        let data = export(&map, &[], None);
+        assert_eq!(q3_import::import(&data).unwrap(), map);
+    }
+
+    fn test_lightgrid(map: &Map, cell_size: [f32; 3]) -> LightGrid {
+        let (mins, maxs) = (map.models[0].bounds.mins, map.models[0].bounds.maxs);
+        let (origin, dims) = LightGrid::layout([mins.x, mins.z, -maxs.y], [maxs.x, maxs.z, -mins.y], cell_size).unwrap();
+        let n_cells = dims[0] * dims[1] * dims[2];
+        LightGrid {
+            origin: origin,
+            cell_size: cell_size,
+            dims: dims,
+            cells: (0..n_cells).map(|i| LightCell {
+                ambient: [i as u8, 1, 2],
+                directed: [3, 4, i as u8],
+                lng: 5,
+                lat: 6,
+            }).collect(),
+        }
+    }
+
+    #[test]
+    fn lightgrid_roundtrip() {
+        let map = test_map();
+        let mut graphics = test_graphics();
+        graphics.lightgrid = Some(test_lightgrid(&map, DEFAULT_CELL_SIZE));
+
+        let data = export(&map, &[], Some(&graphics));
+        assert_eq!(q3_import::import_graphics_data(&data).unwrap().lightgrid, graphics.lightgrid);
+    }
+
+    #[test]
+    fn lightgrid_cell_size_roundtrip() {
+        let map = test_map();
+        let mut graphics = test_graphics();
+        graphics.lightgrid = Some(test_lightgrid(&map, [32.0, 48.0, 96.0]));
+        // A stale gridsize gets replaced rather than written twice.
+        let defs = vec![EntityDef::new()
+                        .with("classname", "worldspawn")
+                        .with("gridsize", "128 128 256")];
+
+        let data = export(&map, &defs, Some(&graphics));
+        assert_eq!(q3_import::import_graphics_data(&data).unwrap().lightgrid, graphics.lightgrid);
+        let imported = entities::parse(&q3_import::import_entities(&data).unwrap());
+        assert_eq!(imported[0].pairs.iter().filter(|&&(ref key, _)| key == "gridsize").count(), 1);
+    }
+
+    #[test]
+    fn graphics_roundtrip() {
+        let mut map = test_map();
+        let graphics = test_graphics();
+        map.bsp.leaffaces = vec![0, 1];
+        for (leafidx, leaf) in map.bsp.leaves.iter_mut().enumerate() {
+            leaf.leafface = (leafidx % 2) as i32;
+            leaf.n_leaffaces = 1;
+        }
+        let data = export(&map, &[], Some(&graphics));
+
+        assert_eq!(q3_import::import(&data).unwrap(), map);
+
+        let imported = q3_import::import_graphics_data(&data).unwrap();
+        assert_eq!(imported.vertices, graphics.vertices);
+        assert_eq!(imported.indices, graphics.indices);
+        assert_eq!(imported.faces, graphics.faces);
+        assert_eq!(imported.lightmaps, graphics.lightmaps);
+        // Collision-only textures come after the ones faces use.
+        assert_eq!(&imported.textures[..graphics.textures.len()], &graphics.textures[..]);
+        assert_eq!(&imported.texture_flags[..graphics.textures.len()], &graphics.texture_flags[..]);
+    }
+
+    #[test]
+    fn entities_roundtrip() {
+        let map = test_map();
+        let defs = vec![
+            EntityDef::new()
+                .with("classname", "worldspawn")
+                .with("message", "Test map")
+                .with("music", "music/fast.wav"),
+            EntityDef::new()
+                .with("classname", "info_player_start")
+                .with("origin", "16 32 48")
+                .with("angle", "90"),
+            EntityDef::new()
+                .with("classname", "trigger_hurt")
+                .with("model", "*1")
+                .with("dmg", "50"),
+        ];
+        let data = export(&map, &defs, None);
+        assert_eq!(q3_import::import(&data).unwrap(), map);
+
+        let imported = entities::parse(&q3_import::import_entities(&data).unwrap());
+        assert_eq!(&imported[..defs.len()], &defs[..]);
+        assert_eq!(entities::find_spawns(&imported), entities::find_spawns(&defs));
+        // Only the goal, which no def used, gets a made up trigger.
+        assert_eq!(imported.iter().filter(|def| def.model() == Some(1)).count(), 1);
+        assert_eq!(imported.iter().filter(|def| def.model() == Some(2)).count(), 1);
+    }
+}
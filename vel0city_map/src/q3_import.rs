@@ -1,18 +1,34 @@
 #![allow(dead_code, unused_variables)]
+use batch;
 use bsp;
 use byteorder::{self, LittleEndian, ReadBytesExt};
 use std::io::{Cursor, SeekFrom, Seek};
 use std;
 use std::borrow::ToOwned;
 use glium;
-use image;
 use na;
+use aabb::Aabb;
+use broadphase::Broadphase;
+use entities::{self, EntityDef};
+use vis::VisData;
+use lightgrid::{self, LightCell, LightGrid};
+use lightmaps::{self, LightingSettings};
+use shader::{self, Material};
+use technique;
+use textures::{self, ImportReport, TextureCache};
 use { 
     Map,
     Model,
+    Entity,
+    EntityKind,
     GraphicsMap,
+    GraphicsMapData,
+    Lightmap,
+    MapPrograms,
     MapVertex,
-    MapFace
+    MapFace,
+    LIGHTMAP_SIZE,
+    SHADER_MAIN
 };
 use vel0city_base::assets;
 
@@ -20,6 +36,40 @@ use vel0city_base::assets;
 pub enum BspError {
     ByteOrderError(byteorder::Error),
     NotUtf8(std::str::Utf8Error),
+    /// The file doesn't start with `IBSP`.
+    BadMagic,
+    /// Only version 46 (Quake 3) is supported.
+    UnsupportedVersion(i32),
+    /// The directory entry for a lump points outside the file.
+    LumpOutOfRange {
+        lump: &'static str,
+        offset: u32,
+        len: u32,
+    },
+    /// A lump's length isn't a whole number of records.
+    BadLumpSize {
+        lump: &'static str,
+        len: usize,
+        record_size: usize,
+    },
+    /// A lump that can't be empty is.
+    EmptyLump(&'static str),
+    /// A record refers to something that doesn't exist. For nodes, this also covers
+    /// children that point back up the tree.
+    DanglingIndex {
+        lump: &'static str,
+        record: usize,
+        index: i64,
+    },
+    /// The visdata header asks for more bytes than the lump has, or for too few
+    /// bytes per cluster to hold a bit for every cluster.
+    BadVisData {
+        n_clusters: i32,
+        cluster_size: i32,
+        len: usize,
+    },
+    /// One of `MAP_PROGRAMS` couldn't be loaded or compiled.
+    BadProgram(String),
 }
 impl std::convert::From<byteorder::Error> for BspError {
     fn from(e: byteorder::Error) -> BspError {
@@ -31,51 +81,256 @@ impl std::convert::From<std::str::Utf8Error> for BspError {
         BspError::NotUtf8(e)
     }
 }
+impl std::fmt::Display for BspError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match *self {
+            BspError::ByteOrderError(ref e) => write!(f, "Truncated data: {:?}", e),
+            BspError::NotUtf8(ref e) => write!(f, "Entity lump isn't UTF-8: {:?}", e),
+            BspError::BadMagic => write!(f, "Not an IBSP file"),
+            BspError::UnsupportedVersion(v) => write!(f, "Unsupported BSP version {} (expected 46)", v),
+            BspError::LumpOutOfRange { lump, offset, len } =>
+                write!(f, "Lump {} ({} bytes at {}) runs past the end of the file", lump, len, offset),
+            BspError::BadLumpSize { lump, len, record_size } =>
+                write!(f, "Lump {} is {} bytes, which isn't a multiple of {}", lump, len, record_size),
+            BspError::EmptyLump(lump) => write!(f, "Lump {} is empty", lump),
+            BspError::DanglingIndex { lump, record, index } =>
+                write!(f, "Record {} of lump {} refers to nonexistent index {}", record, lump, index),
+            BspError::BadVisData { n_clusters, cluster_size, len } =>
+                write!(f, "Visdata for {} clusters of {} bytes each doesn't fit in {} bytes", n_clusters, cluster_size, len),
+            BspError::BadProgram(ref e) => write!(f, "{}", e),
+        }
+    }
+}
+
 pub fn import_entities(data: &[u8]) -> Result<String, BspError> {
     let directory = try!(read_directory(data));
     Ok(try!(std::str::from_utf8(directory.entities)).to_owned())
 }
 
 pub fn import(data: &[u8]) -> Result<Map, BspError> {
+    import_with_materials(data, &[])
+}
+
+/// Same as `import`, but lets the surfaceparms of `materials` change what's solid,
+/// for maps whose compiler didn't already bake them into the texture lump.
+pub fn import_with_materials(data: &[u8], materials: &[Material]) -> Result<Map, BspError> {
     let directory = try!(read_directory(data));
     let planes = try!(read_planes(directory.planes));
-    let nodes = try!(read_nodes(directory.nodes, &planes));
-    let leaves = try!(read_leaves(directory.leaves)); 
-    let textures = try!(read_textures(directory.textures));
+    let mut textures = try!(read_textures(directory.textures));
+    for tex in textures.iter_mut() {
+        if let Some(material) = shader::find(materials, &tex.name) {
+            let (flags, contents) = material.apply_surfaceparms(tex.flags, tex.contents);
+            tex.flags = flags;
+            tex.contents = contents;
+        }
+    }
     let brushsides = try!(read_brushsides(directory.brushsides, &planes, &textures));
     let brushes = try!(read_brushes(directory.brushes, &brushsides));
-    let leafbrushes = try!(read_leafbrushes(directory.leafbrushes));
-    let models = try!(read_models(directory.models));
+    let leafbrushes = try!(read_leafbrushes(directory.leafbrushes, brushes.len()));
+    let visdata = try!(read_visdata(directory.visdata));
+    let n_faces = try!(records(directory.faces, "faces", 104)).count();
+    let leaffaces = try!(read_leaffaces(directory.leaffaces, n_faces));
+    let leaves = try!(read_leaves(directory.leaves, leafbrushes.len(), leaffaces.len(), visdata.as_ref())); 
+    let nodes = try!(read_nodes(directory.nodes, &planes, leaves.len()));
+    let models = try!(read_models(directory.models, brushes.len()));
+    let entity_defs = entities::parse(&String::from_utf8_lossy(directory.entities));
+    let entities = find_entities(&entity_defs, models.len());
+    let broadphase = Broadphase::new(&models, &entities);
 
     Ok(Map {
         bsp: bsp::Tree {
             brushes: brushes,
             leafbrushes: leafbrushes, 
+            leaffaces: leaffaces,
             leaves: leaves,
             inodes: nodes,
+            visdata: visdata,
         },
         models: models, 
-        entities: vec![
-            ::Entity { model: 1, kind: ::EntityKind::OutOfBounds },
-            ::Entity { model: 2, kind: ::EntityKind::Goal },
-        ]
+        entities: entities,
+        broadphase: broadphase,
     })
 }
 
-pub fn import_graphics_model(data: &[u8], display: &glium::Display) -> Result<GraphicsMap, BspError> {
+/// Picks out the entities we know what to do with.
+/// `trigger_hurt`s are out of bounds, and `trigger_multiple`s that fire a
+/// `target_stopTimer` are goals, like in Defrag.
+fn find_entities(defs: &[EntityDef], n_models: usize) -> Vec<Entity> {
+    let goal_targets: Vec<&str> = defs.iter()
+        .filter(|def| def.get("classname") == Some("target_stopTimer"))
+        .filter_map(|def| def.get("targetname"))
+        .collect();
+
+    let mut found = vec![];
+    for def in defs {
+        let model = match def.model() {
+            Some(model) if (model as usize) < n_models => model,
+            _ => continue
+        };
+        let kind = match def.get("classname") {
+            Some("trigger_hurt") => EntityKind::OutOfBounds,
+            Some("trigger_multiple") if def.get("target").map_or(false, |t| goal_targets.contains(&t)) => EntityKind::Goal,
+            _ => continue
+        };
+        found.push(Entity { model: model, kind: kind });
+    }
+
+    // Maps made before we read the entity lump used the first two brush models
+    // for these, so keep that working.
+    if found.is_empty() && n_models >= 3 {
+        found = vec![
+            Entity { model: 1, kind: EntityKind::OutOfBounds },
+            Entity { model: 2, kind: EntityKind::Goal },
+        ];
+    }
+    found
+}
+
+/// Reads the map's graphics and uploads them. See `upload_graphics`.
+pub fn import_graphics_model(data: &[u8],
+                             display: &glium::Display,
+                             materials: &[Material],
+                             texture_cache: &mut TextureCache,
+                             lighting: &LightingSettings) -> Result<(GraphicsMap, ImportReport), BspError> {
+    let mut graphics = try!(import_graphics_data(data));
+    lightmaps::pack_atlases(&mut graphics, lightmaps::DEFAULT_ATLAS_SIZE);
+    upload_graphics(graphics, display, materials, texture_cache, lighting)
+}
+
+/// Uploads graphics read by `import_graphics_data` or from a `map_cache`. Textures
+/// with a shader script are drawn with the script's image. Textures that can't be
+/// loaded are drawn with a placeholder and listed in the report. Baked light is
+/// brightened according to `lighting` on the way.
+pub fn upload_graphics(mut graphics: GraphicsMapData,
+                       display: &glium::Display,
+                       materials: &[Material],
+                       texture_cache: &mut TextureCache,
+                       lighting: &LightingSettings) -> Result<(GraphicsMap, ImportReport), BspError> {
+    shader::attach_materials(&mut graphics, materials);
+    lightmaps::apply_lighting(&mut graphics, lighting);
+    let GraphicsMapData { vertices, indices, faces, textures, texture_flags, materials, lightmaps, batches, lightgrid } = graphics;
+
+    let mut report = ImportReport::new();
+    let loaded_textures = textures.iter().map(|name| {
+        let image_name = match shader::find(&materials, name).and_then(|material| material.texture()) {
+            Some(image) => image,
+            None => name,
+        };
+        textures::load_texture(texture_cache, display, image_name, &mut report)
+    }).collect();
+
+    let mut stage_images = vec![];
+    let techniques = materials.iter()
+        .map(|material| technique::compile(material, &mut stage_images))
+        .collect();
+    let stage_textures = stage_images.iter().map(|name| textures::load_texture(texture_cache, display, name, &mut report)).collect();
+    let loaded_lightmaps = lightmaps.into_iter().map(|lm|  
+                                                     glium::Texture2d::new(display, lm.rows())
+                                                    ).collect();
+
+    let white_lightmap = glium::Texture2d::new(display, vec![vec![(255u8, 255u8, 255u8)]]);
+
+    let programs = try!(load_map_programs(display).map_err(BspError::BadProgram));
+
+    let map = GraphicsMap {
+        vertices: glium::VertexBuffer::new(display, vertices),
+        indices: glium::IndexBuffer::new(display, glium::index::TrianglesList(indices.clone())),
+        index_data: indices,
+        batches: batches,
+        lightgrid: lightgrid,
+        shaders: programs,
+        textures: loaded_textures,
+        texture_flags: texture_flags,
+        materials: materials,
+        techniques: techniques,
+        stage_textures: stage_textures,
+        lightmaps: loaded_lightmaps,
+        white_lightmap: white_lightmap,
+        faces: faces,
+    };
+    Ok((map, report))
+}
+
+/// The vertex and fragment shaders of each of the map's programs, in `SHADER_*` order.
+pub const MAP_PROGRAMS: [(&'static str, &'static str); 4] = [
+    ("shaders/prepass/vertex.glsl", "shaders/prepass/fragment.glsl"),
+    ("shaders/prepass/vertexlit_vertex.glsl", "shaders/prepass/vertexlit_fragment.glsl"),
+    ("shaders/sky/vertex.glsl", "shaders/sky/fragment.glsl"),
+    ("shaders/stage/vertex.glsl", "shaders/stage/fragment.glsl"),
+];
+
+/// Shaders that come with the engine, for asset packs that don't have their own.
+fn builtin_shader(name: &str) -> Option<&'static str> {
+    match name {
+        "shaders/prepass/vertexlit_vertex.glsl" => Some(include_str!("shaders/prepass/vertexlit_vertex.glsl")),
+        "shaders/prepass/vertexlit_fragment.glsl" => Some(include_str!("shaders/prepass/vertexlit_fragment.glsl")),
+        "shaders/sky/vertex.glsl" => Some(include_str!("shaders/sky/vertex.glsl")),
+        "shaders/sky/fragment.glsl" => Some(include_str!("shaders/sky/fragment.glsl")),
+        "shaders/stage/vertex.glsl" => Some(include_str!("shaders/stage/vertex.glsl")),
+        "shaders/stage/fragment.glsl" => Some(include_str!("shaders/stage/fragment.glsl")),
+        _ => None,
+    }
+}
+
+fn load_shader_source(name: &str) -> Result<String, String> {
+    match assets::load_str_asset(name) {
+        Ok(source) => Ok(source),
+        Err(e) => builtin_shader(name).map(|source| source.to_string())
+                                      .ok_or(format!("Couldn't load {}: {}", name, e)),
+    }
+}
+
+/// Compiles all of `MAP_PROGRAMS`, or says why one didn't. Only the main program
+/// has to be there, the others are left out with a warning if they're missing.
+pub fn load_map_programs(display: &glium::Display) -> Result<MapPrograms, String> {
+    let mut programs = vec![];
+    for (shader, &(vertex, fragment)) in MAP_PROGRAMS.iter().enumerate() {
+        let program = try!(load_map_program(display, vertex, fragment));
+        if program.is_none() && shader == SHADER_MAIN {
+            return Err(format!("Couldn't load the main map program, {} with {}", vertex, fragment));
+        }
+        programs.push(program);
+    }
+    Ok(MapPrograms::new(programs))
+}
+
+/// `None` if a shader is missing, since then the main program can stand in. Shaders
+/// that are there but don't compile are an error.
+fn load_map_program(display: &glium::Display, vertex: &str, fragment: &str) -> Result<Option<glium::Program>, String> {
+    let sources = load_shader_source(vertex).and_then(|vertex_src| {
+        load_shader_source(fragment).map(|fragment_src| (vertex_src, fragment_src))
+    });
+    match sources {
+        Ok((vertex_src, fragment_src)) => glium::Program::from_source(display, &vertex_src, &fragment_src, None)
+            .map(Some)
+            .map_err(|e| format!("Couldn't compile {} with {}: {:?}", vertex, fragment, e)),
+        Err(e) => {
+            println!("Warning: {}, using the main map program instead", e);
+            Ok(None)
+        },
+    }
+}
+
+/// Reads everything needed to draw the map, without touching the GPU.
+pub fn import_graphics_data(data: &[u8]) -> Result<GraphicsMapData, BspError> {
     let directory = try!(read_directory(data));
-    let faces = try!(read_faces(directory.faces));
     let vertices = try!(read_vertices(directory.vertices));
     let meshverts = try!(read_meshverts(directory.meshverts));
     let textures = try!(read_textures(directory.textures));
     let lightmaps = try!(read_lightmaps(directory.lightmaps));
+    let faces = try!(read_faces(directory.faces, textures.len(), lightmaps.len()));
 
     let mut indices = vec![];
     let mut fixed_faces = vec![];
-    for face in faces {
+    for (faceidx, face) in faces.into_iter().enumerate() {
+        let (first, last) = try!(check_range("faces", faceidx, face.meshvert, face.n_meshverts, meshverts.len()));
         let index_start = indices.len();
-        for meshvert in &meshverts[face.meshvert as usize.. (face.meshvert + face.n_meshverts) as usize] {
-            indices.push(face.vertex as u32 + *meshvert);
+        for &meshvert in &meshverts[first..last] {
+            let index = face.vertex as i64 + meshvert as i64;
+            if face.vertex < 0 || index >= vertices.len() as i64 {
+                return Err(BspError::DanglingIndex { lump: "faces", record: faceidx, index: index });
+            }
+            indices.push(index as u32);
         }
         let index_end = indices.len();
 
@@ -84,132 +339,199 @@ pub fn import_graphics_model(data: &[u8], display: &glium::Display) -> Result<Gr
             index_start: index_start as u32,
             index_count: (index_end - index_start) as u32,
             lightmap: face.lightmap,
+            material: None,
         });
     }
 
-
-
-    let loaded_textures = textures.iter().map(|tex| {
-        let contents = assets::load_bin_asset(&(tex.name.clone() + ".png")).unwrap_or_else(|_| assets::load_bin_asset("textures/radiant/notex.png").unwrap());
-        let image = image::load(::std::io::Cursor::new(contents), image::PNG).unwrap();
-        let texture = glium::Texture2d::new(display, image);
-        texture
-    }).collect();
-    let loaded_lightmaps = lightmaps.into_iter().map(|lm|  
-                                                     glium::Texture2d::new(display, lm.data)
-                                                    ).collect();
     let loaded_vertices = vertices.iter().map(|vert| {
         MapVertex {
             position: [vert.position.x, -1.0 * vert.position.z, vert.position.y],
             texcoords: [1.0 - vert.texcoords.x, 1.0 - vert.texcoords.y],
             lightmaptexcoords: [vert.lightmaptexcoords.x, vert.lightmaptexcoords.y],
-            normal: [vert.normal.x, -1.0 * vert.normal.z, vert.normal.y]
+            normal: [vert.normal.x, -1.0 * vert.normal.z, vert.normal.y],
+            color: [
+                vert.color[0] as f32 / 255.0,
+                vert.color[1] as f32 / 255.0,
+                vert.color[2] as f32 / 255.0,
+                vert.color[3] as f32 / 255.0
+            ],
         }
     }).collect();
 
-    let main_program = glium::Program::from_source(
-        display,
-        &assets::load_str_asset("shaders/prepass/vertex.glsl").unwrap(),
-        &assets::load_str_asset("shaders/prepass/fragment.glsl").unwrap(),
-        None
-        ).unwrap();
-
-    Ok(GraphicsMap {
-        vertices: glium::VertexBuffer::new(display, loaded_vertices),
-        indices: glium::IndexBuffer::new(display, glium::index::TrianglesList(indices)),
-        shaders: vec![main_program],
-        textures: loaded_textures,
-        lightmaps: loaded_lightmaps,
+    let batches = batch::batch_faces(&mut fixed_faces, &mut indices);
+
+    let models = try!(read_models(directory.models, directory.brushes.len() / 12));
+    let entity_defs = entities::parse(&String::from_utf8_lossy(directory.entities));
+    let lightgrid = match models.first() {
+        Some(world) => read_lightvols(directory.lightvols, &world.bounds, grid_cell_size(&entity_defs)),
+        None => None,
+    };
+
+    Ok(GraphicsMapData {
+        vertices: loaded_vertices,
+        indices: indices,
         faces: fixed_faces,
+        texture_flags: textures.iter().map(|tex| tex.flags).collect(),
+        materials: vec![],
+        textures: textures.into_iter().map(|tex| tex.name).collect(),
+        lightmaps: lightmaps,
+        batches: batches,
+        lightgrid: lightgrid,
+    })
+}
+
+/// The worldspawn can set the light grid's spacing with a `gridsize` key.
+fn grid_cell_size(defs: &[EntityDef]) -> [f32; 3] {
+    let gridsize = defs.iter()
+        .find(|def| def.get("classname") == Some("worldspawn"))
+        .and_then(|def| def.get("gridsize"));
+    let parsed: Vec<f32> = match gridsize {
+        Some(gridsize) => gridsize.split(' ').filter_map(|s| s.parse().ok()).collect(),
+        None => vec![],
+    };
+    if parsed.len() == 3 {
+        [parsed[0], parsed[1], parsed[2]]
+    } else {
+        lightgrid::DEFAULT_CELL_SIZE
+    }
+}
+
+/// Like Quake 3, a light grid that doesn't match the world's bounds is thrown away
+/// rather than treated as an error.
+fn read_lightvols(data: &[u8], world_bounds: &Aabb, cell_size: [f32; 3]) -> Option<LightGrid> {
+    let (mins, maxs) = (world_bounds.mins, world_bounds.maxs);
+    let (origin, dims) = match LightGrid::layout([mins.x, mins.z, -maxs.y], [maxs.x, maxs.z, -mins.y], cell_size) {
+        Some(layout) => layout,
+        None => return None,
+    };
+    if data.len() == 0 || data.len() as u64 != dims[0] as u64 * dims[1] as u64 * dims[2] as u64 * 8 {
+        return None;
+    }
+
+    let cells = data.chunks(8).map(|cell| LightCell {
+        ambient: [cell[0], cell[1], cell[2]],
+        directed: [cell[3], cell[4], cell[5]],
+        lng: cell[6],
+        lat: cell[7],
+    }).collect();
+    Some(LightGrid {
+        origin: origin,
+        cell_size: cell_size,
+        dims: dims,
+        cells: cells,
     })
 }
 
+const LUMP_NAMES: [&'static str; 17] = [
+    "entities",
+    "textures",
+    "planes",
+    "nodes",
+    "leaves",
+    "leaffaces",
+    "leafbrushes",
+    "models",
+    "brushes",
+    "brushsides",
+    "vertices",
+    "meshverts",
+    "effects",
+    "faces",
+    "lightmaps",
+    "lightvols",
+    "visdata",
+];
+
 struct Directory<'a> {
     entities: &'a [u8],
     textures: &'a [u8],
     planes: &'a [u8],
     nodes: &'a [u8],
     leaves: &'a [u8],
+    leaffaces: &'a [u8],
     leafbrushes: &'a [u8],
     models: &'a [u8],
     brushes: &'a [u8],
     brushsides: &'a [u8],
     vertices: &'a [u8],
     meshverts: &'a [u8],
+    effects: &'a [u8],
     faces: &'a [u8],
     lightmaps: &'a [u8],
+    lightvols: &'a [u8],
+    visdata: &'a [u8],
 }
 
-fn read_directory(data: &[u8]) -> byteorder::Result<Directory> {
-    let mut cursor = Cursor::new(data);
-
-    cursor.seek(SeekFrom::Start(8)).unwrap();
-    let entities_offset = try!(cursor.read_u32::<LittleEndian>());
-    let entities_len = try!(cursor.read_u32::<LittleEndian>());
-
-    cursor.seek(SeekFrom::Current(0)).unwrap();
-    let textures_offset = try!(cursor.read_u32::<LittleEndian>());
-    let textures_len = try!(cursor.read_u32::<LittleEndian>());
-
-    cursor.seek(SeekFrom::Current(0)).unwrap();
-    let planes_offset = try!(cursor.read_u32::<LittleEndian>());
-    let planes_len = try!(cursor.read_u32::<LittleEndian>());
-
-    cursor.seek(SeekFrom::Current(0)).unwrap();
-    let nodes_offset = try!(cursor.read_u32::<LittleEndian>());
-    let nodes_len = try!(cursor.read_u32::<LittleEndian>());
-
-    cursor.seek(SeekFrom::Current(0)).unwrap();
-    let leaves_offset = try!(cursor.read_u32::<LittleEndian>());
-    let leaves_len = try!(cursor.read_u32::<LittleEndian>());
-
-    cursor.seek(SeekFrom::Current(8*1)).unwrap();
-    let leafbrushes_offset = try!(cursor.read_u32::<LittleEndian>());
-    let leafbrushes_len = try!(cursor.read_u32::<LittleEndian>());
-
-    cursor.seek(SeekFrom::Current(0)).unwrap();
-    let models_offset = try!(cursor.read_u32::<LittleEndian>());
-    let models_len = try!(cursor.read_u32::<LittleEndian>());
-
-    cursor.seek(SeekFrom::Current(0)).unwrap();
-    let brushes_offset = try!(cursor.read_u32::<LittleEndian>());
-    let brushes_len = try!(cursor.read_u32::<LittleEndian>());
+fn read_directory(data: &[u8]) -> Result<Directory, BspError> {
+    if data.len() < 4 || &data[0..4] != b"IBSP" {
+        return Err(BspError::BadMagic);
+    }
 
-    cursor.seek(SeekFrom::Current(0)).unwrap();
-    let brushsides_offset = try!(cursor.read_u32::<LittleEndian>());
-    let brushsides_len = try!(cursor.read_u32::<LittleEndian>());
+    let mut cursor = Cursor::new(data);
+    cursor.seek(SeekFrom::Start(4)).unwrap();
+    let version = try!(cursor.read_i32::<LittleEndian>());
+    if version != 46 {
+        return Err(BspError::UnsupportedVersion(version));
+    }
 
-    cursor.seek(SeekFrom::Current(0)).unwrap();
-    let vertices_offset = try!(cursor.read_u32::<LittleEndian>());
-    let vertices_len = try!(cursor.read_u32::<LittleEndian>());
+    let mut lumps = vec![];
+    for &lump in LUMP_NAMES.iter() {
+        let offset = try!(cursor.read_u32::<LittleEndian>());
+        let len = try!(cursor.read_u32::<LittleEndian>());
+        let end = offset as u64 + len as u64;
+        if end > data.len() as u64 {
+            return Err(BspError::LumpOutOfRange { lump: lump, offset: offset, len: len });
+        }
+        lumps.push(&data[offset as usize .. end as usize]);
+    }
 
-    cursor.seek(SeekFrom::Current(0)).unwrap();
-    let meshverts_offset = try!(cursor.read_u32::<LittleEndian>());
-    let meshverts_len = try!(cursor.read_u32::<LittleEndian>());
+    Ok(Directory {
+        entities: lumps[0],
+        textures: lumps[1],
+        planes: lumps[2],
+        nodes: lumps[3],
+        leaves: lumps[4],
+        leaffaces: lumps[5],
+        leafbrushes: lumps[6],
+        models: lumps[7],
+        brushes: lumps[8],
+        brushsides: lumps[9],
+        vertices: lumps[10],
+        meshverts: lumps[11],
+        effects: lumps[12],
+        faces: lumps[13],
+        lightmaps: lumps[14],
+        lightvols: lumps[15],
+        visdata: lumps[16],
+    })
+}
 
-    cursor.seek(SeekFrom::Current(8*1)).unwrap();
-    let faces_offset = try!(cursor.read_u32::<LittleEndian>());
-    let faces_len = try!(cursor.read_u32::<LittleEndian>());
+/// Splits a lump into records, making sure there's no partial record at the end.
+fn records<'a>(data: &'a [u8], lump: &'static str, size: usize) -> Result<std::slice::Chunks<'a, u8>, BspError> {
+    if data.len() % size != 0 {
+        Err(BspError::BadLumpSize { lump: lump, len: data.len(), record_size: size })
+    } else {
+        Ok(data.chunks(size))
+    }
+}
 
-    cursor.seek(SeekFrom::Current(8*0)).unwrap();
-    let lightmaps_offset = try!(cursor.read_u32::<LittleEndian>());
-    let lightmaps_len = try!(cursor.read_u32::<LittleEndian>());
+/// Makes sure `index` refers to one of `len` things.
+pub fn check_index(lump: &'static str, record: usize, index: i32, len: usize) -> Result<usize, BspError> {
+    if index < 0 || index as usize >= len {
+        Err(BspError::DanglingIndex { lump: lump, record: record, index: index as i64 })
+    } else {
+        Ok(index as usize)
+    }
+}
 
-    Ok(Directory {
-        entities: &data[entities_offset as usize .. (entities_offset + entities_len) as usize],
-        textures: &data[textures_offset as usize .. (textures_offset + textures_len) as usize],
-        planes: &data[planes_offset as usize .. (planes_offset + planes_len) as usize],
-        nodes: &data[nodes_offset as usize .. (nodes_offset + nodes_len) as usize], 
-        leaves: &data[leaves_offset as usize .. (leaves_offset + leaves_len) as usize],
-        leafbrushes: &data[leafbrushes_offset as usize .. (leafbrushes_offset + leafbrushes_len) as usize],
-        models: &data[models_offset as usize .. (models_offset + models_len) as usize],
-        brushes: &data[brushes_offset as usize .. (brushes_offset + brushes_len) as usize],
-        brushsides: &data[brushsides_offset as usize .. (brushsides_offset + brushsides_len) as usize],
-        vertices: &data[vertices_offset as usize .. (vertices_offset + vertices_len) as usize], 
-        meshverts: &data[meshverts_offset as usize .. (meshverts_offset + meshverts_len) as usize], 
-        faces: &data[faces_offset as usize .. (faces_offset + faces_len) as usize], 
-        lightmaps: &data[lightmaps_offset as usize .. (lightmaps_offset + lightmaps_len) as usize], 
-    })
+/// Makes sure `count` things starting at `start` are all among `len` things,
+/// and returns the range as slice bounds.
+pub fn check_range(lump: &'static str, record: usize, start: i32, count: i32, len: usize) -> Result<(usize, usize), BspError> {
+    if start < 0 || count < 0 || start as u64 + count as u64 > len as u64 {
+        Err(BspError::DanglingIndex { lump: lump, record: record, index: start as i64 + count as i64 })
+    } else {
+        Ok((start as usize, start as usize + count as usize))
+    }
 }
 
 fn read_plane(data: &[u8]) -> byteorder::Result<bsp::Plane> {
@@ -225,98 +547,200 @@ fn read_plane(data: &[u8]) -> byteorder::Result<bsp::Plane> {
         dist: dist
     })
 }
-fn read_planes(data: &[u8]) -> byteorder::Result<Vec<bsp::Plane>> {
-    data.chunks(16)
-        .map(|chunk| read_plane(chunk))
+fn read_planes(data: &[u8]) -> Result<Vec<bsp::Plane>, BspError> {
+    try!(records(data, "planes", 16))
+        .map(|chunk| Ok(try!(read_plane(chunk))))
         .collect()
 }
 
 
-fn read_node(data: &[u8], planes: &[bsp::Plane]) -> byteorder::Result<bsp::InnerNode> {
+/// Makes sure a child of node `record` is one of `n_leaves` leaves, or one of
+/// `n_nodes` nodes that comes after it.
+pub fn check_child(lump: &'static str, record: usize, child: i32, n_nodes: usize, n_leaves: usize) -> Result<(), BspError> {
+    // Children always come after their parents, otherwise traces could go round in circles.
+    let ok = if child < 0 {
+        ((-(child as i64) - 1) as u64) < n_leaves as u64
+    } else {
+        child as usize > record && (child as usize) < n_nodes
+    };
+    if ok {
+        Ok(())
+    } else {
+        Err(BspError::DanglingIndex { lump: lump, record: record, index: child as i64 })
+    }
+}
+
+fn read_node(data: &[u8], record: usize, planes: &[bsp::Plane], n_nodes: usize, n_leaves: usize) -> Result<bsp::InnerNode, BspError> {
     let mut cursor = Cursor::new(data);
 
     let plane_id = try!(cursor.read_i32::<LittleEndian>()); 
     let front = try!(cursor.read_i32::<LittleEndian>()); 
     let back = try!(cursor.read_i32::<LittleEndian>()); 
 
+    let plane_id = try!(check_index("nodes", record, plane_id, planes.len()));
+    try!(check_child("nodes", record, front, n_nodes, n_leaves));
+    try!(check_child("nodes", record, back, n_nodes, n_leaves));
+
     Ok(bsp::InnerNode {
-        plane: planes[plane_id as usize].clone(),
+        plane: planes[plane_id].clone(),
         pos: front as i32,
         neg: back as i32,
     })
 }
-fn read_nodes(data: &[u8], planes: &[bsp::Plane]) -> byteorder::Result<Vec<bsp::InnerNode>> {
-    data.chunks(36)
-        .map(|chunk| read_node(chunk, planes))
+fn read_nodes(data: &[u8], planes: &[bsp::Plane], n_leaves: usize) -> Result<Vec<bsp::InnerNode>, BspError> {
+    // Traces start at node 0, so there has to be one.
+    if data.len() == 0 {
+        return Err(BspError::EmptyLump("nodes"));
+    }
+    let n_nodes = data.len() / 36;
+    try!(records(data, "nodes", 36))
+        .enumerate()
+        .map(|(record, chunk)| read_node(chunk, record, planes, n_nodes, n_leaves))
         .collect()
 }
 
-fn read_brushside(data: &[u8], planes: &[bsp::Plane], textures: &[Texture]) -> byteorder::Result<bsp::BrushSide> {
+fn read_brushside(data: &[u8], record: usize, planes: &[bsp::Plane], textures: &[Texture]) -> Result<bsp::BrushSide, BspError> {
     let mut cursor = Cursor::new(data);
     let plane_id = try!(cursor.read_i32::<LittleEndian>());
     let texture_id = try!(cursor.read_i32::<LittleEndian>());
-    let tex = &textures[texture_id as usize];
+    let plane_id = try!(check_index("brushsides", record, plane_id, planes.len()));
+    let tex = &textures[try!(check_index("brushsides", record, texture_id, textures.len()))];
     Ok(bsp::BrushSide {
-        plane: planes[plane_id as usize].clone(),
+        plane: planes[plane_id].clone(),
         contents: tex.contents,
         flags: tex.flags
     })
 }
 
-fn read_brushsides(data: &[u8], planes: &[bsp::Plane], textures: &[Texture]) -> byteorder::Result<Vec<bsp::BrushSide>> {
-    data.chunks(8)
-        .map(|chunk| read_brushside(chunk, planes, textures))
+fn read_brushsides(data: &[u8], planes: &[bsp::Plane], textures: &[Texture]) -> Result<Vec<bsp::BrushSide>, BspError> {
+    try!(records(data, "brushsides", 8))
+        .enumerate()
+        .map(|(record, chunk)| read_brushside(chunk, record, planes, textures))
         .collect()
 }
 
-fn read_brushes(data: &[u8], brushsides: &[bsp::BrushSide]) -> byteorder::Result<Vec<bsp::Brush>> {
-    data.chunks(12)
-        .map(|chunk| read_brush(chunk, brushsides))
+fn read_brushes(data: &[u8], brushsides: &[bsp::BrushSide]) -> Result<Vec<bsp::Brush>, BspError> {
+    try!(records(data, "brushes", 12))
+        .enumerate()
+        .map(|(record, chunk)| read_brush(chunk, record, brushsides))
         .collect()
 }
 
-fn read_brush(data: &[u8], brushsides: &[bsp::BrushSide]) -> byteorder::Result<bsp::Brush> {
+fn read_brush(data: &[u8], record: usize, brushsides: &[bsp::BrushSide]) -> Result<bsp::Brush, BspError> {
     let mut cursor = Cursor::new(data);
     let brushside = try!(cursor.read_i32::<LittleEndian>());
     let n_brushsides = try!(cursor.read_i32::<LittleEndian>());
+    let (first, last) = try!(check_range("brushes", record, brushside, n_brushsides, brushsides.len()));
     Ok(bsp::Brush {
-        sides: brushsides[brushside as usize .. (brushside + n_brushsides) as usize].to_vec()
+        sides: brushsides[first..last].to_vec()
     })
 }
 
 
-fn read_leaf(data: &[u8]) -> byteorder::Result<bsp::Leaf> {
+fn read_leaf(data: &[u8], record: usize, n_leafbrushes: usize, n_leaffaces: usize, visdata: Option<&VisData>) -> Result<bsp::Leaf, BspError> {
     let mut cursor = Cursor::new(data);
-    cursor.seek(SeekFrom::Start(40)).unwrap();
 
+    let cluster = try!(cursor.read_i32::<LittleEndian>());
+    let area = try!(cursor.read_i32::<LittleEndian>());
+    let min_x = try!(cursor.read_i32::<LittleEndian>()) as f32;
+    let min_y = try!(cursor.read_i32::<LittleEndian>()) as f32;
+    let min_z = try!(cursor.read_i32::<LittleEndian>()) as f32;
+    let max_x = try!(cursor.read_i32::<LittleEndian>()) as f32;
+    let max_y = try!(cursor.read_i32::<LittleEndian>()) as f32;
+    let max_z = try!(cursor.read_i32::<LittleEndian>()) as f32;
+
+    let leafface = try!(cursor.read_i32::<LittleEndian>());
+    let n_leaffaces_here = try!(cursor.read_i32::<LittleEndian>());
     let leafbrush = try!(cursor.read_i32::<LittleEndian>()); 
-    let n_leafbrushes = try!(cursor.read_i32::<LittleEndian>()); 
+    let n_leafbrushes_here = try!(cursor.read_i32::<LittleEndian>()); 
+    try!(check_range("leaves", record, leafface, n_leaffaces_here, n_leaffaces));
+    try!(check_range("leaves", record, leafbrush, n_leafbrushes_here, n_leafbrushes));
+    if let Some(visdata) = visdata {
+        if cluster >= 0 {
+            try!(check_index("leaves", record, cluster, visdata.n_clusters));
+        }
+    }
+
     Ok(bsp::Leaf {
+        cluster: cluster,
+        area: area,
+        // Same swizzle as model bounds.
+        bounds: Aabb::new(
+            na::Pnt3::new(min_x, -max_z, min_y),
+            na::Pnt3::new(max_x, -min_z, max_y)
+            ),
+        leafface: leafface,
+        n_leaffaces: n_leaffaces_here,
         leafbrush: leafbrush,
-        n_leafbrushes: n_leafbrushes
+        n_leafbrushes: n_leafbrushes_here
     })
 }
 
-fn read_leaves(data: &[u8]) -> byteorder::Result<Vec<bsp::Leaf>> {
-    data.chunks(48)
-        .map(|chunk| read_leaf(chunk))
+fn read_leaves(data: &[u8], n_leafbrushes: usize, n_leaffaces: usize, visdata: Option<&VisData>) -> Result<Vec<bsp::Leaf>, BspError> {
+    try!(records(data, "leaves", 48))
+        .enumerate()
+        .map(|(record, chunk)| read_leaf(chunk, record, n_leafbrushes, n_leaffaces, visdata))
         .collect()
 }
 
-fn read_leafbrushes(data: &[u8]) -> byteorder::Result<Vec<u32>> {
-    data.chunks(4)
-        .map(|chunk| {
+fn read_leaffaces(data: &[u8], n_faces: usize) -> Result<Vec<u32>, BspError> {
+    try!(records(data, "leaffaces", 4))
+        .enumerate()
+        .map(|(record, chunk)| {
+            let mut cursor = Cursor::new(chunk);
+            let face = try!(cursor.read_i32::<LittleEndian>());
+            Ok(try!(check_index("leaffaces", record, face, n_faces)) as u32)
+        })
+        .collect()
+}
+
+/// Makes sure `n_clusters` clusters of `cluster_size` bytes fit in `len` bytes,
+/// with a bit for every cluster.
+pub fn check_visdata(n_clusters: i32, cluster_size: i32, len: usize) -> Result<(usize, usize), BspError> {
+    let fits = n_clusters >= 0 && cluster_size >= 0 &&
+        (cluster_size as u64) * 8 >= n_clusters as u64 &&
+        (n_clusters as u64) * (cluster_size as u64) <= len as u64;
+    if fits {
+        Ok((n_clusters as usize, cluster_size as usize))
+    } else {
+        Err(BspError::BadVisData { n_clusters: n_clusters, cluster_size: cluster_size, len: len })
+    }
+}
+
+/// Maps compiled without vis have an empty visdata lump.
+fn read_visdata(data: &[u8]) -> Result<Option<VisData>, BspError> {
+    if data.len() == 0 {
+        return Ok(None);
+    }
+
+    let mut cursor = Cursor::new(data);
+    let n_clusters = try!(cursor.read_i32::<LittleEndian>());
+    let cluster_size = try!(cursor.read_i32::<LittleEndian>());
+    let bits = &data[8..];
+    let (n_clusters, cluster_size) = try!(check_visdata(n_clusters, cluster_size, bits.len()));
+    Ok(Some(VisData {
+        n_clusters: n_clusters,
+        cluster_size: cluster_size,
+        bits: bits[..n_clusters * cluster_size].to_vec(),
+    }))
+}
+
+fn read_leafbrushes(data: &[u8], n_brushes: usize) -> Result<Vec<u32>, BspError> {
+    try!(records(data, "leafbrushes", 4))
+        .enumerate()
+        .map(|(record, chunk)| {
             let mut cursor = Cursor::new(chunk);
-            cursor.read_u32::<LittleEndian>()
+            let brush = try!(cursor.read_i32::<LittleEndian>());
+            Ok(try!(check_index("leafbrushes", record, brush, n_brushes)) as u32)
         })
         .collect()
 }
 
-fn read_meshverts(data: &[u8]) -> byteorder::Result<Vec<u32>> {
-    data.chunks(4)
+fn read_meshverts(data: &[u8]) -> Result<Vec<u32>, BspError> {
+    try!(records(data, "meshverts", 4))
         .map(|chunk| {
             let mut cursor = Cursor::new(chunk);
-            cursor.read_u32::<LittleEndian>()
+            Ok(try!(cursor.read_u32::<LittleEndian>()))
         })
         .collect()
 }
@@ -331,7 +755,7 @@ struct Face {
     n_meshverts: i32,
 }
 
-fn read_face(data: &[u8]) -> byteorder::Result<Face> {
+fn read_face(data: &[u8], record: usize, n_textures: usize, n_lightmaps: usize) -> Result<Face, BspError> {
     let mut cursor = Cursor::new(data);
     let texture = try!(cursor.read_i32::<LittleEndian>()); 
     cursor.seek(SeekFrom::Current(8)).unwrap();
@@ -341,6 +765,12 @@ fn read_face(data: &[u8]) -> byteorder::Result<Face> {
     let n_meshverts = try!(cursor.read_i32::<LittleEndian>()); 
     let lightmap = try!(cursor.read_i32::<LittleEndian>()); 
 
+    try!(check_index("faces", record, texture, n_textures));
+    // Negative lightmaps mean the face doesn't have one.
+    if lightmap >= 0 {
+        try!(check_index("faces", record, lightmap, n_lightmaps));
+    }
+
     Ok(Face {
         texture: texture,
         vertex: vertex,
@@ -351,9 +781,10 @@ fn read_face(data: &[u8]) -> byteorder::Result<Face> {
     })
 }
 
-fn read_faces(data: &[u8]) -> byteorder::Result<Vec<Face>> {
-    data.chunks(104)
-        .map(|chunk| read_face(chunk))
+fn read_faces(data: &[u8], n_textures: usize, n_lightmaps: usize) -> Result<Vec<Face>, BspError> {
+    try!(records(data, "faces", 104))
+        .enumerate()
+        .map(|(record, chunk)| read_face(chunk, record, n_textures, n_lightmaps))
         .collect()
 }
 
@@ -372,6 +803,7 @@ fn read_texture(data: &[u8]) -> byteorder::Result<Texture> {
         .unwrap_or(name.len());
     let name = String::from_utf8_lossy(&name[..namelen]).to_string();
 
+    cursor.seek(SeekFrom::Start(64)).unwrap();
     let flags = try!(cursor.read_i32::<LittleEndian>());
     let contents = try!(cursor.read_i32::<LittleEndian>());
 
@@ -382,30 +814,17 @@ fn read_texture(data: &[u8]) -> byteorder::Result<Texture> {
     })
 }
 
-fn read_textures(data: &[u8]) -> byteorder::Result<Vec<Texture>> {
-    data.chunks(72)
-        .map(|chunk| read_texture(chunk))
+fn read_textures(data: &[u8]) -> Result<Vec<Texture>, BspError> {
+    try!(records(data, "textures", 72))
+        .map(|chunk| Ok(try!(read_texture(chunk))))
         .collect()
 }
 
-#[derive(Debug)]
-struct Lightmap {
-    data: Vec<Vec<(u8, u8, u8)>>
-}
-fn read_lightmaps(data: &[u8]) -> byteorder::Result<Vec<Lightmap>> {
-    data.chunks(128*128*3)
-        .map(|row| Ok(Lightmap { data: try!(row.chunks(128*3)
-             .map(|col| col.chunks(3).map(|px| {
-
-                 let mut cursor = Cursor::new(px);
-                 let r = try!(cursor.read_u8());
-                 let g = try!(cursor.read_u8());
-                 let b = try!(cursor.read_u8());
-                 Ok((r, g, b))
-             })
-                  .collect::<byteorder::Result<Vec<_>>>())
-             .collect::<Result<Vec<_>, _>>()) }))
-        .collect::<Result<Vec<_>, _>>()
+fn read_lightmaps(data: &[u8]) -> Result<Vec<Lightmap>, BspError> {
+    let size = LIGHTMAP_SIZE as usize;
+    Ok(try!(records(data, "lightmaps", size * size * 3))
+        .map(|record| Lightmap { width: LIGHTMAP_SIZE, height: LIGHTMAP_SIZE, data: record.to_vec() })
+        .collect())
 }
 
 struct Vertex {
@@ -413,6 +832,7 @@ struct Vertex {
     texcoords: na::Vec2<f32>,
     lightmaptexcoords: na::Vec2<f32>,
     normal: na::Vec3<f32>,
+    color: [u8; 4],
 }
 fn read_vertex(data: &[u8]) -> byteorder::Result<Vertex> {
     let mut cursor = Cursor::new(data);
@@ -427,34 +847,224 @@ fn read_vertex(data: &[u8]) -> byteorder::Result<Vertex> {
     let n_x = try!(cursor.read_f32::<LittleEndian>());
     let n_y = try!(cursor.read_f32::<LittleEndian>());
     let n_z = try!(cursor.read_f32::<LittleEndian>());
+    let mut color = [0; 4];
+    for c in color.iter_mut() {
+        *c = try!(cursor.read_u8());
+    }
 
     Ok(Vertex {
         position: na::Vec3::new(p_x, p_y, p_z),
         texcoords: na::Vec2::new(t_x, t_y),
         lightmaptexcoords: na::Vec2::new(lt_x, lt_y),
-        normal: na::Vec3::new(n_x, n_y, n_z)
+        normal: na::Vec3::new(n_x, n_y, n_z),
+        color: color,
     })
 }
 
-fn read_vertices(data: &[u8]) -> byteorder::Result<Vec<Vertex>> {
-    data.chunks(44)
-        .map(|chunk| read_vertex(chunk))
+fn read_vertices(data: &[u8]) -> Result<Vec<Vertex>, BspError> {
+    try!(records(data, "vertices", 44))
+        .map(|chunk| Ok(try!(read_vertex(chunk))))
         .collect()
 }
 
-fn read_model(data: &[u8]) -> byteorder::Result<Model> {
+fn read_model(data: &[u8], record: usize, n_brushes: usize) -> Result<Model, BspError> {
     let mut cursor = Cursor::new(data);
-    cursor.seek(SeekFrom::Start(32)).unwrap();
 
-    let brush = try!(cursor.read_u32::<LittleEndian>()); 
-    let n_brushes = try!(cursor.read_u32::<LittleEndian>()); 
+    let min_x = try!(cursor.read_f32::<LittleEndian>());
+    let min_y = try!(cursor.read_f32::<LittleEndian>());
+    let min_z = try!(cursor.read_f32::<LittleEndian>());
+    let max_x = try!(cursor.read_f32::<LittleEndian>());
+    let max_y = try!(cursor.read_f32::<LittleEndian>());
+    let max_z = try!(cursor.read_f32::<LittleEndian>());
+
+    cursor.seek(SeekFrom::Start(32)).unwrap();
+    let brush = try!(cursor.read_i32::<LittleEndian>()); 
+    let n_brushes_here = try!(cursor.read_i32::<LittleEndian>()); 
+    try!(check_range("models", record, brush, n_brushes_here, n_brushes));
     Ok(Model {
-        brush: brush,
-        n_brushes: n_brushes
+        brush: brush as u32,
+        n_brushes: n_brushes_here as u32,
+        // Z is flipped when converting to our coordinates, so mins and maxs swap on that axis.
+        bounds: Aabb::new(
+            na::Pnt3::new(min_x, -max_z, min_y),
+            na::Pnt3::new(max_x, -min_z, max_y)
+            ),
     })
 }
-fn read_models(data: &[u8]) -> byteorder::Result<Vec<Model>> {
-    data.chunks(40)
-        .map(|chunk| read_model(chunk))
+fn read_models(data: &[u8], n_brushes: usize) -> Result<Vec<Model>, BspError> {
+    try!(records(data, "models", 40))
+        .enumerate()
+        .map(|(record, chunk)| read_model(chunk, record, n_brushes))
         .collect()
 }
+
+#[cfg(test)]
+mod test {
+    use na;
+    use builder::MapBuilder;
+    use bsp::SURF_SLICK;
+    use cast::{Ray, Shape};
+    use q3_export;
+    use shader;
+    use byteorder::{LittleEndian, WriteBytesExt};
+    use entities;
+    use {Entity, EntityKind};
+    use super::{
+        find_entities,
+        import,
+        import_with_materials,
+        read_texture,
+        BspError
+    };
+
+    fn test_bsp() -> Vec<u8> {
+        let mut builder = MapBuilder::new();
+        builder.add_box(na::Pnt3::new(-256.0, 0.0, -256.0), na::Pnt3::new(256.0, 16.0, 256.0));
+        builder.add_box(na::Pnt3::new(64.0, -128.0, -256.0), na::Pnt3::new(80.0, 0.0, 256.0));
+        q3_export::export(&builder.build(), &[], None)
+    }
+
+    fn lump_offset(data: &[u8], lump: usize) -> usize {
+        let at = 8 + lump * 8;
+        data[at] as usize | (data[at + 1] as usize) << 8 | (data[at + 2] as usize) << 16 | (data[at + 3] as usize) << 24
+    }
+
+    fn poke_i32(data: &mut [u8], at: usize, value: i32) {
+        for i in 0..4 {
+            data[at + i] = (value >> (i * 8)) as u8;
+        }
+    }
+
+    #[test]
+    fn valid_map_imports() {
+        assert!(import(&test_bsp()).is_ok());
+    }
+
+    #[test]
+    fn bad_header() {
+        let mut data = test_bsp();
+        data[0] = b'X';
+        match import(&data) {
+            Err(BspError::BadMagic) => {},
+            other => panic!("{:?}", other),
+        }
+
+        let mut data = test_bsp();
+        poke_i32(&mut data, 4, 47);
+        match import(&data) {
+            Err(BspError::UnsupportedVersion(47)) => {},
+            other => panic!("{:?}", other),
+        }
+
+        match import(&test_bsp()[..20]) {
+            Err(BspError::ByteOrderError(_)) => {},
+            other => panic!("{:?}", other),
+        }
+    }
+
+    #[test]
+    fn lump_past_end() {
+        let mut data = test_bsp();
+        let len = data.len() as i32;
+        // Length of the planes lump.
+        poke_i32(&mut data, 8 + 2 * 8 + 4, len);
+        match import(&data) {
+            Err(BspError::LumpOutOfRange { lump: "planes", .. }) => {},
+            other => panic!("{:?}", other),
+        }
+    }
+
+    #[test]
+    fn partial_record() {
+        let mut data = test_bsp();
+        // Make the brushes lump one byte short of a whole brush.
+        let at = 8 + 8 * 8 + 4;
+        poke_i32(&mut data, at, 11);
+        match import(&data) {
+            Err(BspError::BadLumpSize { lump: "brushes", len: 11, record_size: 12 }) => {},
+            other => panic!("{:?}", other),
+        }
+    }
+
+    #[test]
+    fn dangling_brushside_plane() {
+        let mut data = test_bsp();
+        let at = lump_offset(&data, 9);
+        poke_i32(&mut data, at, 1 << 20);
+        match import(&data) {
+            Err(BspError::DanglingIndex { lump: "brushsides", record: 0, index }) => assert_eq!(index, 1 << 20),
+            other => panic!("{:?}", other),
+        }
+    }
+
+    #[test]
+    fn node_cycle() {
+        let mut data = test_bsp();
+        let at = lump_offset(&data, 3);
+        // Point the root's front child at itself.
+        poke_i32(&mut data, at + 4, 0);
+        match import(&data) {
+            Err(BspError::DanglingIndex { lump: "nodes", record: 0, index: 0 }) => {},
+            other => panic!("{:?}", other),
+        }
+    }
+
+    #[test]
+    fn materials_change_collision() {
+        let data = test_bsp();
+        // Straight down onto the floor, away from the wall.
+        let ray = Ray {
+            orig: na::Pnt3::new(-128.0, -64.0, 0.0),
+            dir: na::Vec3::new(0.0, 128.0, 0.0),
+            shape: Shape::Box(na::zero()),
+        };
+        assert_eq!(import(&data).unwrap().cast_ray(&ray).unwrap().flags, 0);
+
+        // The exporter names textures after the flags and contents of solid brushes.
+        let slick = shader::parse("vel0city/collision_0_1 { surfaceparm slick }");
+        let hit = import_with_materials(&data, &slick).unwrap().cast_ray(&ray).unwrap();
+        assert_eq!(hit.flags, SURF_SLICK);
+
+        let nonsolid = shader::parse("vel0city/collision_0_1 { surfaceparm nonsolid }");
+        assert!(import_with_materials(&data, &nonsolid).unwrap().cast_ray(&ray).is_none());
+    }
+
+    #[test]
+    fn texture_flags_come_after_the_name() {
+        let name = b"textures/base/floor";
+        let mut data = name.to_vec();
+        data.extend((name.len()..64).map(|_| 0u8));
+        data.write_i32::<LittleEndian>(0x4).unwrap();
+        data.write_i32::<LittleEndian>(0x10000).unwrap();
+        let texture = read_texture(&data).unwrap();
+        assert_eq!(texture.name, "textures/base/floor");
+        // These used to be read from the first bytes of the name.
+        assert_eq!(texture.flags, 0x4);
+        assert_eq!(texture.contents, 0x10000);
+    }
+
+    #[test]
+    fn triggers_come_from_the_entity_lump() {
+        // The goal is the first brush model here, which used to be taken as out of bounds.
+        let lump = "{\n\"classname\" \"worldspawn\"\n}\n\
+                    {\n\"classname\" \"trigger_multiple\"\n\"model\" \"*1\"\n\"target\" \"stop\"\n}\n\
+                    {\n\"classname\" \"target_stopTimer\"\n\"targetname\" \"stop\"\n}\n\
+                    {\n\"classname\" \"trigger_hurt\"\n\"model\" \"*2\"\n}\n\
+                    {\n\"classname\" \"trigger_multiple\"\n\"model\" \"*3\"\n}\n\
+                    {\n\"classname\" \"trigger_hurt\"\n\"model\" \"*9\"\n}\n";
+        assert_eq!(find_entities(&entities::parse(lump), 4), vec![
+            Entity { model: 1, kind: EntityKind::Goal },
+            Entity { model: 2, kind: EntityKind::OutOfBounds },
+        ]);
+    }
+
+    #[test]
+    fn maps_without_triggers_keep_the_old_layout() {
+        let defs = entities::parse("{\n\"classname\" \"worldspawn\"\n}\n");
+        assert_eq!(find_entities(&defs, 3), vec![
+            Entity { model: 1, kind: EntityKind::OutOfBounds },
+            Entity { model: 2, kind: EntityKind::Goal },
+        ]);
+        assert_eq!(find_entities(&defs, 1), vec![]);
+    }
+}
@@ -0,0 +1,679 @@
+//! Quake 3 shader scripts: what a texture name really means, from how it's drawn
+//! to whether you can walk through it.
+
+use bsp;
+use GraphicsMapData;
+use vel0city_base::{assets, vfs};
+
+/// Which side of a face isn't drawn. Quake 3 calls back-face culling `cull front`,
+/// and that's the default.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Cull {
+    Front,
+    Back,
+    None,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum BlendFactor {
+    Zero,
+    One,
+    SrcColor,
+    OneMinusSrcColor,
+    DstColor,
+    OneMinusDstColor,
+    SrcAlpha,
+    OneMinusSrcAlpha,
+    DstAlpha,
+    OneMinusDstAlpha,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum StageMap {
+    Texture(String),
+    Lightmap,
+    WhiteImage,
+    /// Cycles through the textures, `freq` of them a second.
+    Anim {
+        freq: f32,
+        textures: Vec<String>,
+    },
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum TcMod {
+    /// Texture coordinates per second.
+    Scroll { s: f32, t: f32 },
+    Scale { s: f32, t: f32 },
+    /// Degrees per second.
+    Rotate(f32),
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum WaveFunc {
+    Sin,
+    Triangle,
+    Square,
+    Sawtooth,
+    InverseSawtooth,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Wave {
+    pub func: WaveFunc,
+    pub base: f32,
+    pub amp: f32,
+    pub phase: f32,
+    pub freq: f32,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum RgbGen {
+    Identity,
+    IdentityLighting,
+    Vertex,
+    ExactVertex,
+    LightingDiffuse,
+    Entity,
+    Wave(Wave),
+}
+
+/// Pixels failing the test aren't drawn.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum AlphaFunc {
+    Gt0,
+    Lt128,
+    Ge128,
+}
+
+/// One pass of drawing a face.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Stage {
+    pub map: Option<StageMap>,
+    /// Whether the texture is clamped instead of repeated.
+    pub clamp: bool,
+    /// Source and destination factors. `None` means the stage is opaque.
+    pub blend: Option<(BlendFactor, BlendFactor)>,
+    /// Applied in order.
+    pub tcmods: Vec<TcMod>,
+    pub rgbgen: RgbGen,
+    pub alpha_func: Option<AlphaFunc>,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct SkyParms {
+    /// Base name of the skybox images, which get `_rt`, `_bk` and so on tacked on.
+    pub far_box: Option<String>,
+    pub cloud_height: f32,
+    pub near_box: Option<String>,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Material {
+    /// The texture name faces use to refer to this.
+    pub name: String,
+    /// In lower case.
+    pub surfaceparms: Vec<String>,
+    pub cull: Cull,
+    pub sky: Option<SkyParms>,
+    pub stages: Vec<Stage>,
+}
+
+/// What each surfaceparm does to a texture: whether it stops the brush from being
+/// solid, then the surface flags and contents it adds. Taken from q3map.
+const SURFACEPARMS: [(&'static str, bool, i32, i32); 11] = [
+    ("water", true, 0, bsp::CONTENTS_WATER),
+    ("slime", true, 0, bsp::CONTENTS_SLIME),
+    ("lava", true, 0, bsp::CONTENTS_LAVA),
+    ("fog", true, 0, bsp::CONTENTS_FOG),
+    ("playerclip", true, 0, bsp::CONTENTS_PLAYERCLIP),
+    ("trigger", true, 0, bsp::CONTENTS_TRIGGER),
+    ("nonsolid", true, bsp::SURF_NONSOLID, 0),
+    ("sky", false, bsp::SURF_SKY, 0),
+    ("slick", false, bsp::SURF_SLICK, 0),
+    ("nodraw", false, bsp::SURF_NODRAW, 0),
+    ("nolightmap", false, bsp::SURF_NOLIGHTMAP, 0),
+];
+
+impl Material {
+    pub fn has_surfaceparm(&self, parm: &str) -> bool {
+        self.surfaceparms.iter().any(|p| p == parm)
+    }
+
+    /// The surface flags and contents of a texture using this material.
+    pub fn apply_surfaceparms(&self, flags: i32, contents: i32) -> (i32, i32) {
+        let (mut flags, mut contents) = (flags, contents);
+        for &(name, clear_solid, surf, cont) in SURFACEPARMS.iter() {
+            if !self.has_surfaceparm(name) {
+                continue;
+            }
+            if clear_solid {
+                contents &= !bsp::CONTENTS_SOLID;
+            }
+            flags |= surf;
+            contents |= cont;
+        }
+        (flags, contents)
+    }
+
+    /// The image to show for the material when only one can be drawn: the first
+    /// stage that isn't the lightmap.
+    pub fn texture(&self) -> Option<&str> {
+        self.stages.iter().filter_map(|stage| match stage.map {
+            Some(StageMap::Texture(ref name)) => Some(&name[..]),
+            Some(StageMap::Anim { ref textures, .. }) => textures.first().map(|name| &name[..]),
+            _ => None,
+        }).next()
+    }
+}
+
+/// The first material called `name`. Like in Quake 3, names are case insensitive,
+/// and earlier scripts win.
+pub fn find<'a>(materials: &'a [Material], name: &str) -> Option<&'a Material> {
+    let name = name.to_lowercase();
+    materials.iter().find(|m| m.name.to_lowercase() == name)
+}
+
+/// Splits a script into tokens, each with the line it's on. Braces are always
+/// tokens of their own, and quotes can hold spaces.
+fn tokenize(src: &str) -> Vec<(usize, String)> {
+    let mut tokens = vec![];
+    let mut line = 0;
+    let mut chars = src.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '\n' => line += 1,
+            '/' if chars.peek() == Some(&'/') => {
+                while let Some(&c) = chars.peek() {
+                    if c == '\n' {
+                        break;
+                    }
+                    chars.next();
+                }
+            },
+            '/' if chars.peek() == Some(&'*') => {
+                chars.next();
+                let mut last = ' ';
+                while let Some(c) = chars.next() {
+                    if c == '\n' {
+                        line += 1;
+                    }
+                    if last == '*' && c == '/' {
+                        break;
+                    }
+                    last = c;
+                }
+            },
+            '{' | '}' => tokens.push((line, c.to_string())),
+            '"' => {
+                let s: String = chars.by_ref().take_while(|&c| c != '"').collect();
+                tokens.push((line, s));
+            },
+            c if c.is_whitespace() => (),
+            c => {
+                let mut s = c.to_string();
+                while let Some(&c) = chars.peek() {
+                    if c.is_whitespace() || c == '{' || c == '}' || c == '"' {
+                        break;
+                    }
+                    s.push(c);
+                    chars.next();
+                }
+                tokens.push((line, s));
+            }
+        }
+    }
+    tokens
+}
+
+/// A keyword and the rest of the tokens on its line.
+struct Directive {
+    keyword: String,
+    args: Vec<String>,
+}
+impl Directive {
+    fn arg(&self, i: usize) -> Option<&str> {
+        self.args.get(i).map(|s| &s[..])
+    }
+
+    fn float(&self, i: usize) -> f32 {
+        self.arg(i).and_then(|s| s.parse().ok()).unwrap_or(0.0)
+    }
+}
+
+struct Parser {
+    tokens: Vec<(usize, String)>,
+    pos: usize,
+}
+impl Parser {
+    fn peek(&self) -> Option<&str> {
+        self.tokens.get(self.pos).map(|&(_, ref s)| &s[..])
+    }
+
+    fn next(&mut self) -> Option<String> {
+        let token = self.tokens.get(self.pos).map(|&(_, ref s)| s.clone());
+        self.pos += 1;
+        token
+    }
+
+    /// Reads a keyword and its arguments, stopping at a brace.
+    fn directive(&mut self) -> Option<Directive> {
+        let line = match self.tokens.get(self.pos) {
+            Some(&(line, ref s)) if s != "{" && s != "}" => line,
+            _ => return None,
+        };
+        let keyword = self.next().unwrap().to_lowercase();
+        let mut args = vec![];
+        while let Some(&(l, ref s)) = self.tokens.get(self.pos) {
+            if l != line || s == "{" || s == "}" {
+                break;
+            }
+            args.push(s.clone());
+            self.pos += 1;
+        }
+        Some(Directive { keyword: keyword, args: args })
+    }
+
+    /// Skips to the end of the block we're in, nested blocks and all.
+    fn skip_block(&mut self) {
+        let mut depth = 1;
+        while let Some(token) = self.next() {
+            if token == "{" {
+                depth += 1;
+            } else if token == "}" {
+                depth -= 1;
+                if depth == 0 {
+                    return;
+                }
+            }
+        }
+    }
+}
+
+fn blend_factor(name: &str) -> Option<BlendFactor> {
+    match &name.to_uppercase()[..] {
+        "GL_ZERO" => Some(BlendFactor::Zero),
+        "GL_ONE" => Some(BlendFactor::One),
+        "GL_SRC_COLOR" => Some(BlendFactor::SrcColor),
+        "GL_ONE_MINUS_SRC_COLOR" => Some(BlendFactor::OneMinusSrcColor),
+        "GL_DST_COLOR" => Some(BlendFactor::DstColor),
+        "GL_ONE_MINUS_DST_COLOR" => Some(BlendFactor::OneMinusDstColor),
+        "GL_SRC_ALPHA" => Some(BlendFactor::SrcAlpha),
+        "GL_ONE_MINUS_SRC_ALPHA" => Some(BlendFactor::OneMinusSrcAlpha),
+        "GL_DST_ALPHA" => Some(BlendFactor::DstAlpha),
+        "GL_ONE_MINUS_DST_ALPHA" => Some(BlendFactor::OneMinusDstAlpha),
+        _ => None,
+    }
+}
+
+fn blend_func(d: &Directive) -> Option<(BlendFactor, BlendFactor)> {
+    let first = d.arg(0).unwrap_or("").to_lowercase();
+    match &first[..] {
+        "add" => Some((BlendFactor::One, BlendFactor::One)),
+        "filter" => Some((BlendFactor::DstColor, BlendFactor::Zero)),
+        "blend" => Some((BlendFactor::SrcAlpha, BlendFactor::OneMinusSrcAlpha)),
+        _ => match (blend_factor(&first), d.arg(1).and_then(blend_factor)) {
+            (Some(src), Some(dst)) => Some((src, dst)),
+            _ => None,
+        }
+    }
+}
+
+fn wave(d: &Directive, first: usize) -> Option<Wave> {
+    let func = match &d.arg(first).unwrap_or("").to_lowercase()[..] {
+        "sin" => WaveFunc::Sin,
+        "triangle" => WaveFunc::Triangle,
+        "square" => WaveFunc::Square,
+        "sawtooth" => WaveFunc::Sawtooth,
+        "inversesawtooth" => WaveFunc::InverseSawtooth,
+        _ => return None,
+    };
+    Some(Wave {
+        func: func,
+        base: d.float(first + 1),
+        amp: d.float(first + 2),
+        phase: d.float(first + 3),
+        freq: d.float(first + 4),
+    })
+}
+
+/// Turns `-` into `None`, the way sky parameters leave things out.
+fn optional_name(arg: Option<&str>) -> Option<String> {
+    match arg {
+        None | Some("-") => None,
+        Some(name) => Some(name.to_string()),
+    }
+}
+
+fn parse_stage(parser: &mut Parser) -> Stage {
+    let mut stage = Stage {
+        map: None,
+        clamp: false,
+        blend: None,
+        tcmods: vec![],
+        rgbgen: RgbGen::Identity,
+        alpha_func: None,
+    };
+
+    loop {
+        if parser.peek().is_none() {
+            break;
+        }
+        if parser.peek() == Some("}") {
+            parser.next();
+            break;
+        }
+        if parser.peek() == Some("{") {
+            parser.next();
+            parser.skip_block();
+            continue;
+        }
+        let d = parser.directive().unwrap();
+        match &d.keyword[..] {
+            "map" | "clampmap" => {
+                stage.clamp = d.keyword == "clampmap";
+                stage.map = match d.arg(0) {
+                    Some("$lightmap") => Some(StageMap::Lightmap),
+                    Some("$whiteimage") => Some(StageMap::WhiteImage),
+                    Some(name) => Some(StageMap::Texture(name.to_string())),
+                    None => None,
+                };
+            },
+            "animmap" => {
+                stage.map = Some(StageMap::Anim {
+                    freq: d.float(0),
+                    textures: d.args.iter().skip(1).cloned().collect(),
+                });
+            },
+            "blendfunc" => stage.blend = blend_func(&d),
+            "tcmod" => {
+                let tcmod = match &d.arg(0).unwrap_or("").to_lowercase()[..] {
+                    "scroll" => Some(TcMod::Scroll { s: d.float(1), t: d.float(2) }),
+                    "scale" => Some(TcMod::Scale { s: d.float(1), t: d.float(2) }),
+                    "rotate" => Some(TcMod::Rotate(d.float(1))),
+                    _ => None,
+                };
+                if let Some(tcmod) = tcmod {
+                    stage.tcmods.push(tcmod);
+                }
+            },
+            "rgbgen" => {
+                stage.rgbgen = match &d.arg(0).unwrap_or("").to_lowercase()[..] {
+                    "identitylighting" => RgbGen::IdentityLighting,
+                    "vertex" => RgbGen::Vertex,
+                    "exactvertex" => RgbGen::ExactVertex,
+                    "lightingdiffuse" => RgbGen::LightingDiffuse,
+                    "entity" => RgbGen::Entity,
+                    "wave" => wave(&d, 1).map_or(RgbGen::Identity, RgbGen::Wave),
+                    _ => RgbGen::Identity,
+                };
+            },
+            "alphafunc" => {
+                stage.alpha_func = match &d.arg(0).unwrap_or("").to_uppercase()[..] {
+                    "GT0" => Some(AlphaFunc::Gt0),
+                    "LT128" => Some(AlphaFunc::Lt128),
+                    "GE128" => Some(AlphaFunc::Ge128),
+                    _ => None,
+                };
+            },
+            _ => (),
+        }
+    }
+    stage
+}
+
+fn parse_material(parser: &mut Parser, name: String) -> Material {
+    let mut material = Material {
+        name: name,
+        surfaceparms: vec![],
+        cull: Cull::Front,
+        sky: None,
+        stages: vec![],
+    };
+
+    loop {
+        if parser.peek().is_none() {
+            break;
+        }
+        if parser.peek() == Some("}") {
+            parser.next();
+            break;
+        }
+        if parser.peek() == Some("{") {
+            parser.next();
+            let stage = parse_stage(parser);
+            material.stages.push(stage);
+            continue;
+        }
+        let d = parser.directive().unwrap();
+        match &d.keyword[..] {
+            "surfaceparm" => {
+                if let Some(parm) = d.arg(0) {
+                    material.surfaceparms.push(parm.to_lowercase());
+                }
+            },
+            "cull" => {
+                material.cull = match &d.arg(0).unwrap_or("").to_lowercase()[..] {
+                    "back" | "backside" | "backsided" => Cull::Back,
+                    "none" | "twosided" | "disable" => Cull::None,
+                    _ => Cull::Front,
+                };
+            },
+            "skyparms" => {
+                material.sky = Some(SkyParms {
+                    far_box: optional_name(d.arg(0)),
+                    cloud_height: d.arg(1).and_then(|s| s.parse().ok()).unwrap_or(128.0),
+                    near_box: optional_name(d.arg(2)),
+                });
+            },
+            _ => (),
+        }
+    }
+    material
+}
+
+/// Parses a whole script. Anything that isn't understood is skipped, so a script
+/// that's only partly supported still gives us what we can use.
+pub fn parse(src: &str) -> Vec<Material> {
+    let mut parser = Parser { tokens: tokenize(src), pos: 0 };
+    let mut materials = vec![];
+    while let Some(name) = parser.next() {
+        if name == "{" {
+            // A block without a name.
+            parser.skip_block();
+            continue;
+        }
+        if name == "}" {
+            continue;
+        }
+        if parser.peek() == Some("{") {
+            parser.next();
+            materials.push(parse_material(&mut parser, name));
+        }
+    }
+    materials
+}
+
+/// The scripts named in `scripts/shaderlist.txt`, in order. Without the list,
+/// every `.shader` in `scripts/` in alphabetical order.
+pub fn script_paths() -> Vec<String> {
+    match assets::load_str_asset("scripts/shaderlist.txt") {
+        Ok(list) => list.lines()
+            .map(|line| line.trim())
+            .filter(|line| !line.is_empty() && !line.starts_with("//"))
+            .map(|name| format!("scripts/{}.shader", name))
+            .collect(),
+        Err(_) => vfs::list("scripts", "shader"),
+    }
+}
+
+/// Loads every material from the scripts in `script_paths`.
+pub fn load_materials() -> Vec<Material> {
+    let mut materials = vec![];
+    for path in &script_paths() {
+        if let Ok(src) = assets::load_str_asset(path) {
+            materials.extend(parse(&src).into_iter());
+        }
+    }
+    materials
+}
+
+/// Gives every face the material for its texture, if there is one, and folds the
+/// materials' surfaceparms into the texture flags.
+pub fn attach_materials(data: &mut GraphicsMapData, materials: &[Material]) {
+    data.materials = vec![];
+    let mut texture_materials = vec![];
+    for (texidx, name) in data.textures.iter().enumerate() {
+        let index = match find(materials, name) {
+            Some(material) => {
+                if let Some(flags) = data.texture_flags.get_mut(texidx) {
+                    *flags = material.apply_surfaceparms(*flags, 0).0;
+                }
+                data.materials.push(material.clone());
+                Some((data.materials.len() - 1) as u32)
+            },
+            None => None,
+        };
+        texture_materials.push(index);
+    }
+    for face in data.faces.iter_mut() {
+        face.material = texture_materials.get(face.texture as usize).and_then(|&m| m);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use bsp;
+    use super::{
+        parse,
+        find,
+        AlphaFunc,
+        BlendFactor,
+        Cull,
+        RgbGen,
+        SkyParms,
+        StageMap,
+        TcMod,
+        WaveFunc
+    };
+
+    const SCRIPT: &'static str = r#"
+// A comment { that shouldn't open anything
+textures/base/water
+{
+    qer_editorimage textures/base/water.tga
+    surfaceparm nonsolid
+    surfaceparm WATER
+    cull disable
+    {
+        map textures/base/water.tga
+        blendFunc GL_dst_color GL_one
+        tcMod scroll 0.5 -0.25
+        tcMod scale 2 2
+        tcMod rotate 30
+        rgbGen wave sin 0.5 0.5 0 1
+    }
+    {
+        map $lightmap
+        blendFunc filter
+    }
+}
+
+/* A block comment
+   over two lines */
+textures/base/grate { surfaceparm slick
+    {
+        clampMap "textures/base/grate.tga"
+        alphaFunc GE128
+        rgbGen vertex
+    }
+}
+
+textures/skies/night
+{
+    skyParms env/night 512 -
+    surfaceparm sky
+    surfaceparm noimpact
+}
+"#;
+
+    #[test]
+    fn parses_materials() {
+        let materials = parse(SCRIPT);
+        assert_eq!(materials.len(), 3);
+        let names: Vec<&str> = materials.iter().map(|m| &m.name[..]).collect();
+        assert_eq!(names, vec!["textures/base/water", "textures/base/grate", "textures/skies/night"]);
+    }
+
+    #[test]
+    fn parses_stages() {
+        let materials = parse(SCRIPT);
+        let water = &materials[0];
+        assert_eq!(water.cull, Cull::None);
+        assert_eq!(water.surfaceparms, vec!["nonsolid".to_string(), "water".to_string()]);
+        assert_eq!(water.stages.len(), 2);
+
+        let stage = &water.stages[0];
+        assert_eq!(stage.map, Some(StageMap::Texture("textures/base/water.tga".to_string())));
+        assert_eq!(stage.blend, Some((BlendFactor::DstColor, BlendFactor::One)));
+        assert_eq!(stage.tcmods, vec![TcMod::Scroll { s: 0.5, t: -0.25 }, TcMod::Scale { s: 2.0, t: 2.0 }, TcMod::Rotate(30.0)]);
+        match stage.rgbgen {
+            RgbGen::Wave(wave) => {
+                assert_eq!(wave.func, WaveFunc::Sin);
+                assert_eq!((wave.base, wave.amp, wave.phase, wave.freq), (0.5, 0.5, 0.0, 1.0));
+            },
+            other => panic!("Expected a wave, got {:?}", other),
+        }
+
+        assert_eq!(water.stages[1].map, Some(StageMap::Lightmap));
+        assert_eq!(water.stages[1].blend, Some((BlendFactor::DstColor, BlendFactor::Zero)));
+
+        let grate = &materials[1];
+        assert_eq!(grate.cull, Cull::Front);
+        assert!(grate.stages[0].clamp);
+        assert_eq!(grate.stages[0].alpha_func, Some(AlphaFunc::Ge128));
+        assert_eq!(grate.stages[0].rgbgen, RgbGen::Vertex);
+        assert_eq!(grate.texture(), Some("textures/base/grate.tga"));
+    }
+
+    #[test]
+    fn parses_sky() {
+        let materials = parse(SCRIPT);
+        assert_eq!(materials[2].sky, Some(SkyParms {
+            far_box: Some("env/night".to_string()),
+            cloud_height: 512.0,
+            near_box: None,
+        }));
+        assert_eq!(materials[2].texture(), None);
+    }
+
+    #[test]
+    fn surfaceparms() {
+        let materials = parse(SCRIPT);
+        let (flags, contents) = materials[0].apply_surfaceparms(0, bsp::CONTENTS_SOLID);
+        assert_eq!(contents, bsp::CONTENTS_WATER);
+        assert_eq!(flags, bsp::SURF_NONSOLID);
+
+        let (flags, contents) = materials[1].apply_surfaceparms(0, bsp::CONTENTS_SOLID);
+        assert_eq!(contents, bsp::CONTENTS_SOLID);
+        assert_eq!(flags, bsp::SURF_SLICK);
+
+        let (flags, _) = materials[2].apply_surfaceparms(0, bsp::CONTENTS_SOLID);
+        assert_eq!(flags, bsp::SURF_SKY);
+    }
+
+    #[test]
+    fn find_ignores_case() {
+        let materials = parse(SCRIPT);
+        assert_eq!(find(&materials, "TEXTURES/base/Grate").map(|m| &m.name[..]), Some("textures/base/grate"));
+        assert!(find(&materials, "textures/base/floor").is_none());
+    }
+
+    #[test]
+    fn skips_junk() {
+        let materials = parse("{ nameless { } } broken\nfine { { map a.tga } unknown stuff here }");
+        assert_eq!(materials.len(), 1);
+        assert_eq!(materials[0].name, "fine");
+        assert_eq!(materials[0].stages.len(), 1);
+    }
+}
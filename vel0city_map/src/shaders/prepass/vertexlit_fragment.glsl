@@ -0,0 +1,21 @@
+#version 140
+
+uniform sampler2D diffuse;
+
+in vec2 v_texcoords;
+in vec4 v_color;
+in vec3 v_normal;
+in vec3 v_position;
+
+out vec4 diffuse_out;
+out vec4 light_out;
+out vec4 normal_out;
+out vec4 position_out;
+
+void main() {
+    diffuse_out = texture(diffuse, v_texcoords);
+    // The baked vertex lighting takes the place of a lightmap.
+    light_out = vec4(v_color.rgb, 1.0);
+    normal_out = vec4(normalize(v_normal), 0.0);
+    position_out = vec4(v_position, 1.0);
+}
@@ -0,0 +1,24 @@
+#version 140
+
+uniform mat4 w2s;
+uniform mat4 cam;
+uniform mat4 model;
+
+in vec3 position;
+in vec2 texcoords;
+in vec3 normal;
+in vec4 color;
+
+out vec2 v_texcoords;
+out vec4 v_color;
+out vec3 v_normal;
+out vec3 v_position;
+
+void main() {
+    vec4 world = model * vec4(position, 1.0);
+    v_texcoords = texcoords;
+    v_color = color;
+    v_normal = mat3(cam * model) * normal;
+    v_position = (cam * world).xyz;
+    gl_Position = w2s * world;
+}
@@ -0,0 +1,24 @@
+#version 140
+
+uniform sampler2D diffuse;
+
+in vec3 v_dir;
+in vec3 v_normal;
+in vec3 v_position;
+
+out vec4 diffuse_out;
+out vec4 light_out;
+out vec4 normal_out;
+out vec4 position_out;
+
+void main() {
+    // The texture is a flat layer of clouds high overhead, wherever the sky brushes
+    // are. Up is -y, and the horizon is kept from stretching out forever.
+    vec3 dir = normalize(v_dir);
+    vec2 tc = dir.xz / (abs(dir.y) + 0.3) * 0.5;
+    diffuse_out = texture(diffuse, tc);
+    // Skies aren't lit by anything.
+    light_out = vec4(1.0);
+    normal_out = vec4(normalize(v_normal), 0.0);
+    position_out = vec4(v_position, 1.0);
+}
@@ -0,0 +1,21 @@
+#version 140
+
+uniform mat4 w2s;
+uniform mat4 cam;
+uniform mat4 model;
+
+in vec3 position;
+in vec3 normal;
+
+out vec3 v_dir;
+out vec3 v_normal;
+out vec3 v_position;
+
+void main() {
+    vec4 world = model * vec4(position, 1.0);
+    vec3 eye = inverse(cam)[3].xyz;
+    v_dir = world.xyz - eye;
+    v_normal = mat3(cam * model) * normal;
+    v_position = (cam * world).xyz;
+    gl_Position = w2s * world;
+}
@@ -0,0 +1,43 @@
+#version 140
+
+uniform sampler2D diffuse;
+uniform vec4 stage_color;
+uniform float vertex_color;
+uniform int alpha_func;
+uniform int gbuffer;
+
+in vec2 v_texcoords;
+in vec4 v_color;
+in vec3 v_normal;
+in vec3 v_position;
+
+out vec4 diffuse_out;
+out vec4 light_out;
+out vec4 normal_out;
+out vec4 position_out;
+
+void main() {
+    vec4 color = texture(diffuse, v_texcoords) * stage_color * mix(vec4(1.0), v_color, vertex_color);
+    if ((alpha_func == 1 && color.a <= 0.0) ||
+        (alpha_func == 2 && color.a >= 0.5) ||
+        (alpha_func == 3 && color.a < 0.5)) {
+        discard;
+    }
+    diffuse_out = color;
+
+    // The stages light themselves, with a lightmap stage if they want one, so the
+    // light buffer has to end up white. See `technique::PassUniforms::gbuffer`.
+    if (gbuffer == 1) {
+        light_out = vec4(0.0);
+        normal_out = vec4(0.0);
+        position_out = vec4(0.0);
+    } else if (gbuffer == 2) {
+        light_out = vec4(1.0);
+        normal_out = vec4(1.0);
+        position_out = vec4(1.0);
+    } else {
+        light_out = vec4(1.0);
+        normal_out = vec4(normalize(v_normal), 0.0);
+        position_out = vec4(v_position, 1.0);
+    }
+}
@@ -0,0 +1,28 @@
+#version 140
+
+uniform mat4 w2s;
+uniform mat4 cam;
+uniform mat4 model;
+uniform mat3 tc_transform;
+uniform int lightmap_stage;
+
+in vec3 position;
+in vec2 texcoords;
+in vec2 lightmaptexcoords;
+in vec3 normal;
+in vec4 color;
+
+out vec2 v_texcoords;
+out vec4 v_color;
+out vec3 v_normal;
+out vec3 v_position;
+
+void main() {
+    vec4 world = model * vec4(position, 1.0);
+    vec2 tc = lightmap_stage != 0 ? lightmaptexcoords : texcoords;
+    v_texcoords = (tc_transform * vec3(tc, 1.0)).xy;
+    v_color = color;
+    v_normal = mat3(cam * model) * normal;
+    v_position = (cam * world).xyz;
+    gl_Position = w2s * world;
+}
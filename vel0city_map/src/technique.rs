@@ -0,0 +1,345 @@
+//! Turns the stages of a material into passes the renderer can draw, and works out
+//! what each pass's uniforms should be at a given time.
+
+use std::f32::consts::PI;
+use shader::{
+    AlphaFunc,
+    BlendFactor,
+    Cull,
+    Material,
+    RgbGen,
+    StageMap,
+    TcMod,
+    Wave,
+    WaveFunc
+};
+
+/// What a pass samples.
+#[derive(Clone, Debug, PartialEq)]
+pub enum PassImage {
+    /// Indices into the map's stage images. With more than one, they're cycled
+    /// through `freq` times a second.
+    Images {
+        frames: Vec<u32>,
+        freq: f32,
+    },
+    Lightmap,
+    White,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Pass {
+    pub image: PassImage,
+    pub clamp: bool,
+    /// Source and destination factors, or `None` to replace what's there.
+    pub blend: Option<(BlendFactor, BlendFactor)>,
+    /// Blended passes go on top of the opaque one, so they leave depth alone.
+    pub depth_write: bool,
+    pub alpha_func: Option<AlphaFunc>,
+    pub tcmods: Vec<TcMod>,
+    pub rgbgen: RgbGen,
+}
+
+/// Everything needed to draw faces with a material, one pass per stage.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Technique {
+    pub cull: Cull,
+    pub passes: Vec<Pass>,
+}
+
+/// A pass's uniforms at some point in time.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct PassUniforms {
+    /// Applied to `MapVertex::texcoords`, as the columns of a GLSL `mat3`.
+    pub tc_transform: [[f32; 3]; 3],
+    /// Multiplies the sampled color.
+    pub color: [f32; 4],
+    /// 1 to multiply by the vertex colors as well, 0 not to.
+    pub vertex_color: f32,
+    /// 0 for no alpha test, then `Gt0`, `Lt128` and `Ge128`.
+    pub alpha_func: i32,
+    /// What to write to the light, normal and position buffers, so that blending
+    /// leaves them as the first pass wrote them: 0 for the real values, 1 for zeros
+    /// to add, 2 for ones to multiply by.
+    pub gbuffer: i32,
+}
+
+/// Drops the extension from an image path, since scripts use the one the original
+/// game's files had.
+pub fn strip_extension(path: &str) -> &str {
+    match path.rfind('.') {
+        Some(dot) if !path[dot..].contains('/') => &path[..dot],
+        _ => path,
+    }
+}
+
+fn image_index(images: &mut Vec<String>, path: &str) -> u32 {
+    let name = strip_extension(path);
+    match images.iter().position(|image| *image == name) {
+        Some(idx) => idx as u32,
+        None => {
+            images.push(name.to_string());
+            (images.len() - 1) as u32
+        }
+    }
+}
+
+/// Builds the passes for `material`. Images the stages use are added to `images`
+/// if they aren't already there, without extensions.
+pub fn compile(material: &Material, images: &mut Vec<String>) -> Technique {
+    let passes = material.stages.iter().filter_map(|stage| {
+        let image = match stage.map {
+            Some(StageMap::Texture(ref path)) => PassImage::Images { frames: vec![image_index(images, path)], freq: 0.0 },
+            Some(StageMap::Anim { ref freq, ref textures }) if !textures.is_empty() => PassImage::Images {
+                frames: textures.iter().map(|path| image_index(images, path)).collect(),
+                freq: *freq,
+            },
+            Some(StageMap::Lightmap) => PassImage::Lightmap,
+            Some(StageMap::WhiteImage) => PassImage::White,
+            // Nothing to draw.
+            _ => return None,
+        };
+        Some(Pass {
+            image: image,
+            clamp: stage.clamp,
+            blend: stage.blend,
+            depth_write: stage.blend.is_none(),
+            alpha_func: stage.alpha_func,
+            tcmods: stage.tcmods.clone(),
+            rgbgen: stage.rgbgen,
+        })
+    }).collect();
+
+    Technique {
+        cull: material.cull,
+        passes: passes,
+    }
+}
+
+/// Value of a Quake 3 waveform: `base + amp * func(phase + time * freq)`, where
+/// every function has a period of 1.
+pub fn eval_wave(wave: &Wave, time: f32) -> f32 {
+    let x = wave.phase + time * wave.freq;
+    let f = x - x.floor();
+    let y = match wave.func {
+        WaveFunc::Sin => (x * 2.0 * PI).sin(),
+        WaveFunc::Triangle => if f < 0.25 {
+            4.0 * f
+        } else if f < 0.75 {
+            2.0 - 4.0 * f
+        } else {
+            4.0 * f - 4.0
+        },
+        WaveFunc::Square => if f < 0.5 { 1.0 } else { -1.0 },
+        WaveFunc::Sawtooth => f,
+        WaveFunc::InverseSawtooth => 1.0 - f,
+    };
+    wave.base + wave.amp * y
+}
+
+/// A 2D affine transform: `s' = a[0] s + a[1] t + a[2]`, `t' = a[3] s + a[4] t + a[5]`.
+type Affine = [f32; 6];
+
+/// `second` applied after `first`.
+fn then(first: &Affine, second: &Affine) -> Affine {
+    let (f, s) = (first, second);
+    [
+        s[0] * f[0] + s[1] * f[3], s[0] * f[1] + s[1] * f[4], s[0] * f[2] + s[1] * f[5] + s[2],
+        s[3] * f[0] + s[4] * f[3], s[3] * f[1] + s[4] * f[4], s[3] * f[2] + s[4] * f[5] + s[5],
+    ]
+}
+
+/// The texture coordinate transform of `tcmods` at `time`, applied in order.
+pub fn tc_transform(tcmods: &[TcMod], time: f32) -> [[f32; 3]; 3] {
+    // Quake 3's texture coordinates are flipped both ways from ours, and that's
+    // what tcMods expect.
+    let flip = [-1.0, 0.0, 1.0, 0.0, -1.0, 1.0];
+    let mut m = flip;
+    for tcmod in tcmods {
+        let op = match *tcmod {
+            // Only the fractional part matters, and it keeps the numbers small.
+            TcMod::Scroll { s, t } => {
+                let (ds, dt) = (s * time, t * time);
+                [1.0, 0.0, ds - ds.floor(), 0.0, 1.0, dt - dt.floor()]
+            },
+            TcMod::Scale { s, t } => [s, 0.0, 0.0, 0.0, t, 0.0],
+            // Around the middle of the texture.
+            TcMod::Rotate(degrees) => {
+                let angle = -degrees * time * PI / 180.0;
+                let (sin, cos) = (angle.sin(), angle.cos());
+                [cos, -sin, 0.5 - 0.5 * cos + 0.5 * sin,
+                 sin, cos, 0.5 - 0.5 * sin - 0.5 * cos]
+            },
+        };
+        m = then(&m, &op);
+    }
+    let m = then(&m, &flip);
+    [[m[0], m[3], 0.0], [m[1], m[4], 0.0], [m[2], m[5], 1.0]]
+}
+
+impl Pass {
+    /// The stage image to sample at `time`, or `None` for the lightmap or white.
+    pub fn frame(&self, time: f32) -> Option<u32> {
+        match self.image {
+            PassImage::Images { ref frames, freq } => {
+                if frames.is_empty() {
+                    return None;
+                }
+                let n = (time * freq).floor() as i64;
+                let len = frames.len() as i64;
+                Some(frames[(((n % len) + len) % len) as usize])
+            },
+            _ => None,
+        }
+    }
+
+    pub fn uniforms(&self, time: f32) -> PassUniforms {
+        let (brightness, vertex_color) = match self.rgbgen {
+            RgbGen::Vertex | RgbGen::ExactVertex => (1.0, 1.0),
+            RgbGen::Wave(ref wave) => (eval_wave(wave, time).max(0.0).min(1.0), 0.0),
+            _ => (1.0, 0.0),
+        };
+        PassUniforms {
+            tc_transform: tc_transform(&self.tcmods, time),
+            color: [brightness, brightness, brightness, 1.0],
+            vertex_color: vertex_color,
+            alpha_func: match self.alpha_func {
+                None => 0,
+                Some(AlphaFunc::Gt0) => 1,
+                Some(AlphaFunc::Lt128) => 2,
+                Some(AlphaFunc::Ge128) => 3,
+            },
+            gbuffer: match self.blend {
+                Some((_, BlendFactor::One)) => 1,
+                Some((BlendFactor::DstColor, BlendFactor::Zero)) | Some((BlendFactor::Zero, BlendFactor::SrcColor)) => 2,
+                _ => 0,
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use na;
+    use shader::{self, BlendFactor, TcMod, Wave, WaveFunc};
+    use super::{
+        compile,
+        eval_wave,
+        strip_extension,
+        tc_transform,
+        PassImage
+    };
+
+    /// Where `tc_transform` puts the texture coordinate `(s, t)`.
+    fn apply(m: &[[f32; 3]; 3], s: f32, t: f32) -> (f32, f32) {
+        (m[0][0] * s + m[1][0] * t + m[2][0], m[0][1] * s + m[1][1] * t + m[2][1])
+    }
+
+    fn close(a: (f32, f32), b: (f32, f32)) -> bool {
+        na::approx_eq(&a.0, &b.0) && na::approx_eq(&a.1, &b.1)
+    }
+
+    #[test]
+    fn no_tcmods_is_identity() {
+        let m = tc_transform(&[], 12.5);
+        assert!(close(apply(&m, 0.3, 0.7), (0.3, 0.7)));
+    }
+
+    #[test]
+    fn scroll_wraps_and_flips() {
+        let m = tc_transform(&[TcMod::Scroll { s: 0.25, t: 0.0 }], 1.0);
+        // A quarter of the way along Quake's S is a quarter back along ours.
+        assert!(close(apply(&m, 0.5, 0.5), (0.25, 0.5)));
+        // Ten and a quarter scrolls lands in the same place.
+        let m = tc_transform(&[TcMod::Scroll { s: 0.25, t: 0.0 }], 41.0);
+        assert!(close(apply(&m, 0.5, 0.5), (0.25, 0.5)));
+    }
+
+    #[test]
+    fn tcmods_apply_in_order() {
+        let scale_then_scroll = tc_transform(&[TcMod::Scale { s: 2.0, t: 2.0 }, TcMod::Scroll { s: 0.25, t: 0.25 }], 1.0);
+        let scroll_then_scale = tc_transform(&[TcMod::Scroll { s: 0.25, t: 0.25 }, TcMod::Scale { s: 2.0, t: 2.0 }], 1.0);
+        // Ours (1, 1) is Quake's (0, 0).
+        assert!(close(apply(&scale_then_scroll, 1.0, 1.0), (0.75, 0.75)));
+        assert!(close(apply(&scroll_then_scale, 1.0, 1.0), (0.5, 0.5)));
+    }
+
+    #[test]
+    fn rotate_turns_around_the_middle() {
+        let m = tc_transform(&[TcMod::Rotate(90.0)], 1.0);
+        assert!(close(apply(&m, 0.5, 0.5), (0.5, 0.5)));
+        let (s, t) = apply(&m, 1.0, 0.5);
+        assert!(na::approx_eq(&s, &0.5));
+        assert!(na::approx_eq(&(t - 0.5).abs(), &0.5));
+    }
+
+    #[test]
+    fn waves() {
+        let wave = |func| Wave { func: func, base: 0.5, amp: 0.5, phase: 0.0, freq: 2.0 };
+        assert!(na::approx_eq(&eval_wave(&wave(WaveFunc::Sin), 0.125), &1.0));
+        assert!(na::approx_eq(&eval_wave(&wave(WaveFunc::Triangle), 0.375), &0.0));
+        assert!(na::approx_eq(&eval_wave(&wave(WaveFunc::Square), 0.3), &0.0));
+        assert!(na::approx_eq(&eval_wave(&wave(WaveFunc::Sawtooth), 0.125), &0.625));
+        assert!(na::approx_eq(&eval_wave(&wave(WaveFunc::InverseSawtooth), 0.125), &0.875));
+    }
+
+    #[test]
+    fn compiles_stages() {
+        let materials = shader::parse(r#"
+            textures/sfx/boost
+            {
+                cull none
+                {
+                    map textures/sfx/boost.tga
+                    rgbGen wave sin 0.5 0.5 0 1
+                }
+                {
+                    map $lightmap
+                    blendFunc filter
+                }
+                {
+                    animMap 4 textures/sfx/glow1.tga textures/sfx/glow2.tga textures/sfx/boost.jpg
+                    blendFunc add
+                }
+                {
+                    rgbGen vertex
+                }
+            }
+        "#);
+        let mut images = vec![];
+        let technique = compile(&materials[0], &mut images);
+
+        assert_eq!(images, vec!["textures/sfx/boost".to_string(), "textures/sfx/glow1".to_string(), "textures/sfx/glow2".to_string()]);
+        // The stage without a map has nothing to draw.
+        assert_eq!(technique.passes.len(), 3);
+        assert_eq!(technique.cull, shader::Cull::None);
+
+        let opaque = &technique.passes[0];
+        assert!(opaque.depth_write);
+        assert_eq!(opaque.frame(3.0), Some(0));
+        let uniforms = opaque.uniforms(0.25);
+        assert!(na::approx_eq(&uniforms.color[0], &1.0));
+        assert_eq!(uniforms.vertex_color, 0.0);
+        assert_eq!(uniforms.gbuffer, 0);
+
+        assert_eq!(technique.passes[1].image, PassImage::Lightmap);
+        assert_eq!(technique.passes[1].frame(0.0), None);
+        assert!(!technique.passes[1].depth_write);
+        assert_eq!(technique.passes[1].uniforms(0.0).gbuffer, 2);
+
+        let anim = &technique.passes[2];
+        assert_eq!(anim.blend, Some((BlendFactor::One, BlendFactor::One)));
+        assert_eq!(anim.uniforms(0.0).gbuffer, 1);
+        assert_eq!(anim.frame(0.0), Some(1));
+        assert_eq!(anim.frame(0.3), Some(2));
+        assert_eq!(anim.frame(0.5), Some(0));
+        assert_eq!(anim.frame(0.8), Some(1));
+    }
+
+    #[test]
+    fn strips_extensions() {
+        assert_eq!(strip_extension("textures/base/floor.tga"), "textures/base/floor");
+        assert_eq!(strip_extension("textures/v1.0/floor"), "textures/v1.0/floor");
+        assert_eq!(strip_extension("floor"), "floor");
+    }
+}
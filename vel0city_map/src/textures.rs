@@ -0,0 +1,191 @@
+//! Finding and decoding the images behind texture names.
+
+use glium;
+use image::{self, GenericImage};
+use std::fmt;
+use std::io::Cursor;
+use std::rc::Rc;
+use technique;
+use vel0city_base::assets;
+use vel0city_base::cache::Cache;
+
+/// Tried in order for names without an extension, like ioquake3 does.
+pub const EXTENSIONS: [&'static str; 4] = ["tga", "jpg", "jpeg", "png"];
+
+/// Shown in place of textures that can't be loaded.
+pub const PLACEHOLDER: &'static str = "textures/radiant/notex";
+
+/// Uploaded textures, by name without an extension.
+pub type TextureCache = Cache<glium::Texture2d>;
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum ImportWarning {
+    /// None of the files the texture could be in exist.
+    MissingTexture(String),
+    /// The file is there, but isn't an image we can decode.
+    CorruptTexture {
+        path: String,
+        error: String,
+    },
+}
+impl fmt::Display for ImportWarning {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            ImportWarning::MissingTexture(ref name) => write!(f, "Missing texture {}", name),
+            ImportWarning::CorruptTexture { ref path, ref error } => write!(f, "Couldn't decode {}: {}", path, error),
+        }
+    }
+}
+
+/// Everything that went wrong importing a map without stopping the import.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ImportReport {
+    pub warnings: Vec<ImportWarning>,
+    /// Every asset the import read or looked for, to know when to import again.
+    pub dependencies: Vec<String>,
+}
+impl ImportReport {
+    pub fn new() -> ImportReport {
+        ImportReport {
+            warnings: vec![],
+            dependencies: vec![],
+        }
+    }
+
+    pub fn depend_on(&mut self, name: &str) {
+        if !self.dependencies.iter().any(|dependency| dependency == name) {
+            self.dependencies.push(name.to_string());
+        }
+    }
+}
+
+/// The files `name` could be in, in the order they're tried. An extension that's
+/// already there goes first, since it's what the mapper asked for.
+pub fn candidates(name: &str) -> Vec<String> {
+    let base = technique::strip_extension(name);
+    let mut paths = vec![];
+    if base.len() != name.len() {
+        paths.push(name.to_string());
+    }
+    for ext in EXTENSIONS.iter() {
+        let path = format!("{}.{}", base, ext);
+        if !paths.contains(&path) {
+            paths.push(path);
+        }
+    }
+    paths
+}
+
+fn decode(path: &str, data: &[u8]) -> Result<image::DynamicImage, String> {
+    // TGAs don't start with anything that gives them away.
+    let is_tga = path.to_lowercase().ends_with(".tga");
+    let result = if is_tga {
+        image::load(Cursor::new(data), image::TGA)
+    } else {
+        image::load_from_memory(data)
+    };
+    result.map_err(|e| format!("{:?}", e))
+}
+
+/// Loads the first of `name`'s candidates that exists. Corrupt files are reported
+/// and skipped, so a good file further down the list still gets used.
+pub fn load_image(name: &str, report: &mut ImportReport) -> Option<image::DynamicImage> {
+    for path in candidates(name) {
+        report.depend_on(&path);
+        let data = match assets::load_bin_asset(&path) {
+            Ok(data) => data,
+            Err(_) => continue,
+        };
+        match decode(&path, &data) {
+            Ok(image) => return Some(image),
+            Err(error) => report.warnings.push(ImportWarning::CorruptTexture { path: path, error: error }),
+        }
+    }
+    None
+}
+
+/// Like `load_image`, but falls back to the placeholder texture, and then to a
+/// single magenta pixel, so there's always something to draw.
+pub fn load_image_or_placeholder(name: &str, report: &mut ImportReport) -> image::DynamicImage {
+    if let Some(image) = load_image(name, report) {
+        return image;
+    }
+    report.warnings.push(ImportWarning::MissingTexture(name.to_string()));
+    let mut ignored = ImportReport::new();
+    match load_image(PLACEHOLDER, &mut ignored) {
+        Some(image) => image,
+        None => image::ImageRgba8(image::ImageBuffer::from_pixel(1, 1, image::Rgba([255, 0, 255, 255]))),
+    }
+}
+
+/// Uploads the image for `name`, unless it's already in `cache`.
+pub fn load_texture(cache: &mut TextureCache, display: &glium::Display, name: &str, report: &mut ImportReport) -> Rc<glium::Texture2d> {
+    // Cached textures still depend on their files, so hot reloading notices them.
+    for path in candidates(name) {
+        report.depend_on(&path);
+    }
+    cache.get_or_insert_with(technique::strip_extension(name), || {
+        let image = load_image_or_placeholder(name, report);
+        let (width, height) = image.dimensions();
+        (glium::Texture2d::new(display, image), width as usize * height as usize * 4)
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use vel0city_base::testing::temp_mount;
+    use super::{
+        candidates,
+        load_image,
+        load_image_or_placeholder,
+        ImportReport,
+        ImportWarning
+    };
+
+    #[test]
+    fn lookup_order() {
+        assert_eq!(candidates("textures/base/floor"), vec![
+            "textures/base/floor.tga",
+            "textures/base/floor.jpg",
+            "textures/base/floor.jpeg",
+            "textures/base/floor.png",
+        ]);
+        assert_eq!(candidates("textures/base/floor.png"), vec![
+            "textures/base/floor.png",
+            "textures/base/floor.tga",
+            "textures/base/floor.jpg",
+            "textures/base/floor.jpeg",
+        ]);
+    }
+
+    #[test]
+    fn missing_textures_are_reported() {
+        let _assets = temp_mount("textures_missing", &[]);
+        let mut report = ImportReport::new();
+        assert!(load_image("textures/nothing", &mut report).is_none());
+        assert!(report.warnings.is_empty());
+        assert_eq!(report.dependencies, candidates("textures/nothing"));
+
+        let image = load_image_or_placeholder("textures/nothing", &mut report);
+        assert_eq!(report.warnings, vec![ImportWarning::MissingTexture("textures/nothing".to_string())]);
+        assert_eq!(image.raw_pixels(), vec![255, 0, 255, 255]);
+    }
+
+    #[test]
+    fn corrupt_textures_are_reported() {
+        let _assets = temp_mount("textures_corrupt", &[("textures/bad.tga", b"not a targa"), ("textures/bad.jpg", b"not a jpeg either")]);
+        let mut report = ImportReport::new();
+        load_image_or_placeholder("textures/bad", &mut report);
+
+        assert_eq!(report.warnings.len(), 3);
+        match report.warnings[0] {
+            ImportWarning::CorruptTexture { ref path, .. } => assert_eq!(path, "textures/bad.tga"),
+            ref other => panic!("{:?}", other),
+        }
+        match report.warnings[1] {
+            ImportWarning::CorruptTexture { ref path, .. } => assert_eq!(path, "textures/bad.jpg"),
+            ref other => panic!("{:?}", other),
+        }
+        assert_eq!(report.warnings[2], ImportWarning::MissingTexture("textures/bad".to_string()));
+    }
+}
@@ -0,0 +1,249 @@
+//! The potentially visible set: which clusters of leaves might be able to see each other.
+
+use na;
+use aabb::Aabb;
+use bsp::{
+    Plane,
+    Tree
+};
+
+/// Cluster-to-cluster visibility, as stored in the visdata lump.
+#[derive(Clone, Debug, PartialEq)]
+pub struct VisData {
+    pub n_clusters: usize,
+    /// Bytes per cluster. Bit `to` of cluster `from`'s bytes is set if `to` is visible from `from`.
+    pub cluster_size: usize,
+    pub bits: Vec<u8>,
+}
+impl VisData {
+    pub fn is_cluster_visible(&self, from: i32, to: i32) -> bool {
+        // Out of the map, we can't say what's visible so we draw everything.
+        // Leaves in solid space are never visible.
+        if from < 0 || from as usize >= self.n_clusters {
+            return true;
+        }
+        if to < 0 || to as usize >= self.n_clusters {
+            return false;
+        }
+        let byte = self.bits[from as usize * self.cluster_size + to as usize / 8];
+        byte & (1 << (to % 8)) != 0
+    }
+}
+
+impl Tree {
+    pub fn is_cluster_visible(&self, from: i32, to: i32) -> bool {
+        match self.visdata {
+            Some(ref visdata) => visdata.is_cluster_visible(from, to),
+            None => true,
+        }
+    }
+
+    /// Indices of every leaf that might be visible from `point`.
+    pub fn leaves_visible_from(&self, point: &na::Pnt3<f32>) -> Vec<usize> {
+        let from = self.leaves[self.find_leaf(point)].cluster;
+        self.leaves.iter()
+            .enumerate()
+            .filter(|&(_, leaf)| self.is_cluster_visible(from, leaf.cluster))
+            .map(|(leafidx, _)| leafidx)
+            .collect()
+    }
+}
+
+/// The volume a camera can see, as six planes facing into it.
+pub struct Frustum {
+    pub planes: [Plane; 6],
+}
+impl Frustum {
+    /// Pulls the planes out of a world-to-screen matrix that's applied as `w2s * point`.
+    /// The planes aren't normalized, so they're only good for telling sides apart.
+    pub fn from_matrix(w2s: &na::Mat4<f32>) -> Frustum {
+        let m = w2s;
+        let (r1, w1) = (na::Vec3::new(m.m11, m.m12, m.m13), m.m14);
+        let (r2, w2) = (na::Vec3::new(m.m21, m.m22, m.m23), m.m24);
+        let (r3, w3) = (na::Vec3::new(m.m31, m.m32, m.m33), m.m34);
+        let (r4, w4) = (na::Vec3::new(m.m41, m.m42, m.m43), m.m44);
+        // A point's inside when -w <= x, y, z <= w after the transform.
+        let plane = |norm: na::Vec3<f32>, w: f32| Plane { norm: norm, dist: -w };
+        Frustum {
+            planes: [
+                plane(r4 + r1, w4 + w1),
+                plane(r4 - r1, w4 - w1),
+                plane(r4 + r2, w4 + w2),
+                plane(r4 - r2, w4 - w2),
+                plane(r4 + r3, w4 + w3),
+                plane(r4 - r3, w4 - w3),
+            ]
+        }
+    }
+
+    /// Whether any of `aabb` might be inside. Boxes near the corners can get through
+    /// when they're really outside, but nothing inside is ever rejected.
+    pub fn intersects(&self, aabb: &Aabb) -> bool {
+        self.planes.iter().all(|plane| {
+            // The corner furthest along the plane's normal.
+            let corner = na::Pnt3::new(
+                if plane.norm.x >= 0.0 { aabb.maxs.x } else { aabb.mins.x },
+                if plane.norm.y >= 0.0 { aabb.maxs.y } else { aabb.mins.y },
+                if plane.norm.z >= 0.0 { aabb.maxs.z } else { aabb.mins.z },
+                );
+            plane.dist_to_point(&corner) >= 0.0
+        })
+    }
+}
+
+/// Indices of the faces that might be on screen when looking through `w2s` from `eye`:
+/// the faces of leaves that are both in the eye's PVS and in the view frustum.
+/// Maps without leaf faces can't be culled, so all `n_faces` of their faces are visible.
+pub fn visible_faces(tree: &Tree, n_faces: usize, eye: &na::Pnt3<f32>, w2s: &na::Mat4<f32>) -> Vec<u32> {
+    if tree.leaffaces.is_empty() {
+        return (0..n_faces as u32).collect();
+    }
+
+    let frustum = Frustum::from_matrix(w2s);
+    // Faces can be in more than one leaf.
+    let mut visible = vec![false; n_faces];
+    for leafidx in tree.leaves_visible_from(eye) {
+        let leaf = &tree.leaves[leafidx];
+        if !frustum.intersects(&leaf.bounds) {
+            continue;
+        }
+        for &face in &tree.leaffaces[leaf.leafface as usize..(leaf.leafface + leaf.n_leaffaces) as usize] {
+            if (face as usize) < n_faces {
+                visible[face as usize] = true;
+            }
+        }
+    }
+
+    visible.iter()
+        .enumerate()
+        .filter(|&(_, &v)| v)
+        .map(|(faceidx, _)| faceidx as u32)
+        .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use na;
+    use builder::MapBuilder;
+    use std::f32::consts::FRAC_PI_2;
+    use bsp::Tree;
+    use super::{
+        VisData,
+        visible_faces
+    };
+
+    /// Three rooms in a row along X, split up by walls. Each can see its neighbours, but not past them.
+    fn rooms() -> Tree {
+        let mut builder = MapBuilder::new();
+        for i in 0..4 {
+            let x = i as f32 * 256.0;
+            builder.add_box(na::Pnt3::new(x, -256.0, -128.0), na::Pnt3::new(x + 16.0, 0.0, 128.0));
+        }
+        let mut tree = builder.build().bsp;
+
+        // The last wall only has solid space past it.
+        let room = |x: f32| if x >= 768.0 { -1 } else { (x / 256.0) as i32 };
+        // Every leaf gets a face of its own.
+        for (leafidx, leaf) in tree.leaves.iter_mut().enumerate() {
+            leaf.cluster = room(leaf.bounds.center().x);
+            leaf.leafface = leafidx as i32;
+            leaf.n_leaffaces = 1;
+        }
+        tree.leaffaces = (0..tree.leaves.len() as u32).collect();
+        tree.visdata = Some(VisData {
+            n_clusters: 3,
+            cluster_size: 1,
+            bits: vec![0b011, 0b111, 0b110],
+        });
+        tree
+    }
+
+    #[test]
+    fn cluster_bits() {
+        let visdata = VisData {
+            n_clusters: 10,
+            cluster_size: 2,
+            bits: vec![0b0000_0001, 0b0000_0010, 0, 0],
+        };
+        assert!(visdata.is_cluster_visible(0, 0));
+        assert!(!visdata.is_cluster_visible(0, 1));
+        assert!(visdata.is_cluster_visible(0, 9));
+        assert!(!visdata.is_cluster_visible(1, 0));
+        assert!(!visdata.is_cluster_visible(0, -1));
+        assert!(!visdata.is_cluster_visible(0, 10));
+        assert!(visdata.is_cluster_visible(-1, 5));
+    }
+
+    #[test]
+    fn no_visdata_sees_everything() {
+        let mut tree = rooms();
+        tree.visdata = None;
+        assert_eq!(tree.leaves_visible_from(&na::Pnt3::new(100.0, -64.0, 0.0)).len(), tree.leaves.len());
+    }
+
+    #[test]
+    fn leaves_visible_from_room() {
+        let tree = rooms();
+        let point = na::Pnt3::new(100.0, -64.0, 0.0);
+        let from = tree.leaves[tree.find_leaf(&point)].cluster;
+        assert_eq!(from, 0);
+
+        let visible = tree.leaves_visible_from(&point);
+        assert!(visible.contains(&tree.find_leaf(&point)));
+        for (leafidx, leaf) in tree.leaves.iter().enumerate() {
+            let should_see = leaf.cluster == 0 || leaf.cluster == 1;
+            assert_eq!(visible.contains(&leafidx), should_see);
+        }
+    }
+
+    /// Looking down -Z from `eye`, with a 90 degree field of view.
+    fn looking_forward(eye: &na::Pnt3<f32>) -> na::Mat4<f32> {
+        let proj = na::Persp3::new(1.0, FRAC_PI_2, 1.0, 4096.0).to_mat();
+        let view = na::Iso3::new(-eye.to_vec(), na::zero()).to_homogeneous();
+        proj * view
+    }
+
+    /// Sees everything within 4096 units of the origin, in every direction.
+    fn everywhere() -> na::Mat4<f32> {
+        let s = 1.0 / 4096.0;
+        na::Mat4::new(s, 0.0, 0.0, 0.0,
+                      0.0, s, 0.0, 0.0,
+                      0.0, 0.0, s, 0.0,
+                      0.0, 0.0, 0.0, 1.0)
+    }
+
+    #[test]
+    fn pvs_culls_faces() {
+        let tree = rooms();
+        let eye = na::Pnt3::new(100.0, -64.0, 0.0);
+        let faces = visible_faces(&tree, tree.leaves.len(), &eye, &everywhere());
+        for (leafidx, leaf) in tree.leaves.iter().enumerate() {
+            let should_see = leaf.cluster == 0 || leaf.cluster == 1;
+            assert_eq!(faces.contains(&(leafidx as u32)), should_see);
+        }
+    }
+
+    #[test]
+    fn frustum_culls_faces() {
+        let mut tree = rooms();
+        tree.visdata = None;
+        let eye = na::Pnt3::new(100.0, -64.0, 0.0);
+        let faces = visible_faces(&tree, tree.leaves.len(), &eye, &looking_forward(&eye));
+
+        assert!(faces.contains(&(tree.find_leaf(&eye) as u32)));
+        for (leafidx, leaf) in tree.leaves.iter().enumerate() {
+            // Everything in the next room over is off to the side.
+            if leaf.bounds.mins.x >= 256.0 {
+                assert!(!faces.contains(&(leafidx as u32)));
+            }
+        }
+    }
+
+    #[test]
+    fn no_leaffaces_draws_everything() {
+        let mut tree = rooms();
+        tree.leaffaces = vec![];
+        let eye = na::Pnt3::new(100.0, -64.0, 0.0);
+        assert_eq!(visible_faces(&tree, 7, &eye, &looking_forward(&eye)), vec![0, 1, 2, 3, 4, 5, 6]);
+    }
+}
@@ -0,0 +1,174 @@
+use na;
+use bsp::Plane;
+
+/// Half the size of the square `Winding::from_plane` starts with.
+/// Has to be bigger than any map we'll ever load.
+const MAX_WORLD: f32 = 65536.0;
+
+/// A convex polygon. The points go counter-clockwise when looking at its front.
+#[derive(Clone, Debug)]
+pub struct Winding {
+    pub points: Vec<na::Pnt3<f32>>
+}
+
+impl Winding {
+    /// A huge square lying on `plane`, facing the same way as the plane.
+    pub fn from_plane(plane: &Plane) -> Winding {
+        let norm = plane.norm;
+
+        // Any vector that isn't parallel to the normal will do for "up".
+        let up = if na::abs(&norm.z) > na::abs(&norm.x) && na::abs(&norm.z) > na::abs(&norm.y) {
+            na::Vec3::new(1.0, 0.0, 0.0)
+        } else {
+            na::Vec3::new(0.0, 0.0, 1.0)
+        };
+        let up = na::normalize(&(up - norm * na::dot(&up, &norm))) * MAX_WORLD;
+        let right = na::cross(&norm, &up);
+
+        let org = plane.point_on().to_vec();
+        Winding {
+            points: vec![
+                (org - right + up).to_pnt(),
+                (org + right + up).to_pnt(),
+                (org + right - up).to_pnt(),
+                (org - right - up).to_pnt(),
+            ]
+        }
+    }
+
+    /// Cuts off everything in front of `plane`, keeping what's behind it.
+    /// Points within `eps` of the plane count as being on it.
+    /// Returns `None` if nothing is left.
+    pub fn clip(&self, plane: &Plane, eps: f32) -> Option<Winding> {
+        let dists: Vec<f32> = self.points.iter().map(|p| plane.dist_to_point(p)).collect();
+
+        if dists.iter().all(|&d| d <= eps) {
+            return Some(self.clone());
+        }
+        if dists.iter().all(|&d| d >= -eps) {
+            return None;
+        }
+
+        let mut points = vec![];
+        for i in 0..self.points.len() {
+            let j = (i + 1) % self.points.len();
+            let (p1, d1) = (self.points[i], dists[i]);
+            let (p2, d2) = (self.points[j], dists[j]);
+
+            if d1 <= eps {
+                points.push(p1);
+            }
+            if na::abs(&d1) <= eps || na::abs(&d2) <= eps || (d1 > 0.0) == (d2 > 0.0) {
+                continue;
+            }
+
+            // The edge crosses the plane, so split it there.
+            let t = d1 / (d1 - d2);
+            points.push((p1.to_vec() + (p2.to_vec() - p1.to_vec()) * t).to_pnt());
+        }
+
+        if points.len() < 3 {
+            None
+        } else {
+            Some(Winding { points: points })
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use na;
+    use bsp::{
+        Brush,
+        BrushSide,
+        Plane
+    };
+
+    fn brush(planes: &[(na::Vec3<f32>, f32)]) -> Brush {
+        Brush {
+            sides: planes.iter().map(|&(norm, dist)| BrushSide {
+                plane: Plane { norm: na::normalize(&norm), dist: dist },
+                flags: 0,
+                contents: 1,
+            }).collect()
+        }
+    }
+
+    fn cube(halfsize: f32) -> Vec<(na::Vec3<f32>, f32)> {
+        vec![
+            (na::Vec3::new(1.0, 0.0, 0.0), halfsize),
+            (na::Vec3::new(-1.0, 0.0, 0.0), halfsize),
+            (na::Vec3::new(0.0, 1.0, 0.0), halfsize),
+            (na::Vec3::new(0.0, -1.0, 0.0), halfsize),
+            (na::Vec3::new(0.0, 0.0, 1.0), halfsize),
+            (na::Vec3::new(0.0, 0.0, -1.0), halfsize),
+        ]
+    }
+
+    /// Checks that every point of every polygon lies on its side's plane and
+    /// that the points wind counter-clockwise around the plane normal.
+    fn assert_polygons_on_planes(brush: &Brush) {
+        for polygon in brush.polygons() {
+            let side = brush.sides.iter()
+                .find(|side| polygon.points.iter().all(|p| na::abs(&side.plane.dist_to_point(p)) < 0.01))
+                .expect("Polygon doesn't lie on any side");
+            let p = &polygon.points;
+            let winding_norm = na::cross(&(p[1].to_vec() - p[0].to_vec()), &(p[2].to_vec() - p[1].to_vec()));
+            assert!(na::dot(&winding_norm, &side.plane.norm) > 0.0);
+        }
+    }
+
+    #[test]
+    fn cube_polygons() {
+        let cube = brush(&cube(16.0));
+        let polygons = cube.polygons();
+        assert_eq!(polygons.len(), 6);
+        for polygon in &polygons {
+            assert_eq!(polygon.points.len(), 4);
+        }
+        assert_polygons_on_planes(&cube);
+
+        let bounds = cube.bounds();
+        assert_approx_eq!(bounds.mins.to_vec(), na::Vec3::new(-16.0, -16.0, -16.0));
+        assert_approx_eq!(bounds.maxs.to_vec(), na::Vec3::new(16.0, 16.0, 16.0));
+    }
+
+    #[test]
+    fn wedge_polygons() {
+        let wedge = brush(&[
+            (na::Vec3::new(-1.0, 0.0, 0.0), 0.0),
+            (na::Vec3::new(0.0, -1.0, 0.0), 0.0),
+            (na::Vec3::new(0.0, 0.0, 1.0), 16.0),
+            (na::Vec3::new(0.0, 0.0, -1.0), 16.0),
+            (na::Vec3::new(1.0, 1.0, 0.0), 32.0 / 2.0f32.sqrt()),
+        ]);
+        let polygons = wedge.polygons();
+        assert_eq!(polygons.len(), 5);
+        assert_eq!(polygons.iter().filter(|p| p.points.len() == 3).count(), 2);
+        assert_eq!(polygons.iter().filter(|p| p.points.len() == 4).count(), 3);
+        assert_polygons_on_planes(&wedge);
+
+        let bounds = wedge.bounds();
+        assert_approx_eq!(bounds.mins.to_vec(), na::Vec3::new(0.0, 0.0, -16.0));
+        assert_approx_eq!(bounds.maxs.to_vec(), na::Vec3::new(32.0, 32.0, 16.0));
+    }
+
+    #[test]
+    fn redundant_side_has_no_polygon() {
+        let mut planes = cube(16.0);
+        planes.push((na::Vec3::new(1.0, 0.0, 0.0), 100.0));
+        let cube = brush(&planes);
+        assert_eq!(cube.polygons().len(), 6);
+    }
+
+    #[test]
+    fn open_brush_is_empty() {
+        // Two parallel planes facing away from each other enclose nothing.
+        let brush = brush(&[
+            (na::Vec3::new(1.0, 0.0, 0.0), -16.0),
+            (na::Vec3::new(-1.0, 0.0, 0.0), -16.0),
+        ]);
+        assert!(brush.polygons().is_empty());
+        assert!(brush.bounds().is_empty());
+    }
+}
@@ -0,0 +1,190 @@
+//! Feeds mangled BSPs to the importer. Nothing here checks what comes out,
+//! only that bad input gets an error instead of a panic.
+//!
+//! Set VEL0CITY_FUZZ_ITERS to run for longer, and VEL0CITY_FUZZ_SEED to try
+//! a different sequence of inputs.
+
+extern crate nalgebra as na;
+extern crate vel0city_base;
+extern crate vel0city_map;
+
+use vel0city_base::rng::Rng;
+
+use vel0city_map::{
+    EntityKind,
+    GraphicsMapData,
+    Lightmap,
+    MapFace,
+    MapVertex
+};
+use vel0city_map::builder::{
+    MapBuilder,
+    box_brush,
+    CONTENTS_SOLID
+};
+use vel0city_map::cast::{
+    Ray,
+    Shape
+};
+use vel0city_map::q3_export;
+use vel0city_map::q3_import;
+
+const N_LUMPS: usize = 17;
+
+fn env_or(name: &str, default: u64) -> u64 {
+    std::env::var(name).ok()
+        .and_then(|s| s.parse().ok())
+        .unwrap_or(default)
+}
+
+fn base_bsp() -> Vec<u8> {
+    let mut builder = MapBuilder::new();
+    builder.add_box(na::Pnt3::new(-256.0, 0.0, -256.0), na::Pnt3::new(256.0, 16.0, 256.0));
+    builder.add_box(na::Pnt3::new(64.0, -128.0, -256.0), na::Pnt3::new(80.0, 0.0, 256.0));
+    builder.add_entity(EntityKind::OutOfBounds, vec![
+        box_brush(na::Pnt3::new(-1024.0, 512.0, -1024.0), na::Pnt3::new(1024.0, 600.0, 1024.0), CONTENTS_SOLID)
+    ]);
+    builder.add_entity(EntityKind::Goal, vec![
+        box_brush(na::Pnt3::new(200.0, -64.0, 200.0), na::Pnt3::new(250.0, 0.0, 250.0), CONTENTS_SOLID)
+    ]);
+
+    let vert = |x: f32, z: f32| MapVertex {
+        position: [x, 0.0, z],
+        texcoords: [0.0, 0.0],
+        lightmaptexcoords: [0.0, 0.0],
+        normal: [0.0, -1.0, 0.0],
+        color: [1.0, 1.0, 1.0, 1.0],
+    };
+    let graphics = GraphicsMapData {
+        vertices: vec![vert(-64.0, -64.0), vert(64.0, -64.0), vert(64.0, 64.0), vert(-64.0, 64.0)],
+        indices: vec![0, 1, 2, 0, 2, 3],
+        faces: vec![
+            MapFace { texture: 0, lightmap: 0, index_start: 0, index_count: 3, material: None },
+            MapFace { texture: 0, lightmap: -1, index_start: 3, index_count: 3, material: None },
+        ],
+        textures: vec!["textures/base/floor".to_string()],
+        texture_flags: vec![0],
+        materials: vec![],
+        lightmaps: vec![Lightmap { width: 128, height: 128, data: vec![255; 128 * 128 * 3] }],
+        batches: vec![],
+        lightgrid: None,
+    };
+
+    q3_export::export(&builder.build(), &[], Some(&graphics))
+}
+
+fn read_u32(data: &[u8], at: usize) -> u32 {
+    data[at] as u32 | (data[at + 1] as u32) << 8 | (data[at + 2] as u32) << 16 | (data[at + 3] as u32) << 24
+}
+
+fn write_u32(data: &mut [u8], at: usize, value: u32) {
+    for i in 0..4 {
+        data[at + i] = (value >> (i * 8)) as u8;
+    }
+}
+
+/// Values that tend to land on edge cases when they show up as an index, count or length.
+fn interesting(rng: &mut Rng, len: usize) -> u32 {
+    match rng.below(8) {
+        0 => 0,
+        1 => 1,
+        2 => !0,
+        3 => 0x7fffffff,
+        4 => 0x80000000,
+        5 => len as u32,
+        6 => rng.below(64) as u32,
+        _ => rng.next_u64() as u32,
+    }
+}
+
+/// Changes a few things about `data`, mostly inside the lumps themselves so that
+/// the big lightmap lump doesn't soak up all the mutations.
+fn mutate(rng: &mut Rng, data: &mut Vec<u8>) {
+    let n_mutations = 1 + rng.below(8);
+    for _ in 0..n_mutations {
+        let len = data.len();
+        match rng.below(6) {
+            // Something in the directory.
+            0 if len >= 8 + N_LUMPS * 8 => {
+                let at = 8 + rng.below(N_LUMPS * 2) * 4;
+                let value = interesting(rng, len);
+                write_u32(data, at, value);
+            },
+            // Chop the file off somewhere.
+            1 => {
+                let new_len = rng.below(len + 1);
+                data.truncate(new_len);
+            },
+            // Scribble over a random byte.
+            2 if len > 0 => {
+                let at = rng.below(len);
+                data[at] = rng.next_u64() as u8;
+            },
+            // Scribble over a field in a lump.
+            _ if len >= 8 + N_LUMPS * 8 => {
+                let lump = rng.below(N_LUMPS);
+                let offset = read_u32(data, 8 + lump * 8) as usize;
+                let lump_len = read_u32(data, 8 + lump * 8 + 4) as usize;
+                if lump_len >= 4 && offset.checked_add(lump_len).map_or(false, |end| end <= len) {
+                    let at = offset + rng.below(lump_len / 4) * 4;
+                    let value = interesting(rng, lump_len);
+                    write_u32(data, at, value);
+                }
+            },
+            _ => ()
+        }
+    }
+}
+
+/// Runs `data` through every entry point, and traces against whatever comes out.
+fn exercise(data: &[u8]) {
+    let _ = q3_import::import_entities(data);
+    let _ = q3_import::import_graphics_data(data);
+    if let Ok(map) = q3_import::import(data) {
+        let rays = [
+            Ray { orig: na::Pnt3::new(0.0, -64.0, 0.0), dir: na::Vec3::new(0.0, 128.0, 0.0), shape: Shape::Box(na::zero()) },
+            Ray { orig: na::Pnt3::new(-300.0, -16.0, 0.0), dir: na::Vec3::new(600.0, 0.0, 0.0), shape: Shape::Box(na::Vec3::new(16.0, 32.0, 16.0)) },
+            Ray { orig: na::Pnt3::new(0.0, -64.0, 0.0), dir: na::Vec3::new(300.0, 700.0, 300.0), shape: Shape::Capsule { radius: 16.0, halfheight: 16.0 } },
+        ];
+        for ray in rays.iter() {
+            let _ = map.cast_ray(ray);
+            let _ = map.cast_ray_brute_force(ray);
+        }
+    }
+}
+
+#[test]
+fn base_map_is_valid() {
+    let data = base_bsp();
+    assert!(q3_import::import(&data).is_ok());
+    assert!(q3_import::import_graphics_data(&data).is_ok());
+    assert!(q3_import::import_entities(&data).is_ok());
+}
+
+#[test]
+fn fuzz_mutated_maps() {
+    let base = base_bsp();
+    let mut rng = Rng::new(env_or("VEL0CITY_FUZZ_SEED", 0));
+    for _ in 0..env_or("VEL0CITY_FUZZ_ITERS", 2000) {
+        let mut data = base.clone();
+        mutate(&mut rng, &mut data);
+        exercise(&data);
+    }
+}
+
+#[test]
+fn fuzz_random_bytes() {
+    let mut rng = Rng::new(env_or("VEL0CITY_FUZZ_SEED", 1));
+    for _ in 0..env_or("VEL0CITY_FUZZ_ITERS", 2000) {
+        let len = rng.below(512);
+        let mut data: Vec<u8> = (0..len).map(|_| rng.next_u64() as u8).collect();
+        // Give most of them a real header so they get past the magic check.
+        if len >= 8 && rng.below(4) != 0 {
+            for (d, &c) in data.iter_mut().zip(b"IBSP".iter()) {
+                *d = c;
+            }
+            write_u32(&mut data, 4, 46);
+        }
+        exercise(&data);
+    }
+}
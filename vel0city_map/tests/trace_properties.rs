@@ -0,0 +1,181 @@
+//! Checks things that should hold for every trace, on randomly built maps.
+
+extern crate nalgebra as na;
+extern crate vel0city_base;
+extern crate vel0city_map;
+
+use vel0city_base::rng::Rng;
+
+use vel0city_map::Map;
+use vel0city_map::bsp::{
+    Brush,
+    Plane
+};
+use vel0city_map::builder::{
+    MapBuilder,
+    box_brush,
+    brush_from_planes,
+    CONTENTS_SOLID
+};
+use vel0city_map::cast::{
+    self,
+    CastResult,
+    Ray,
+    Shape
+};
+use vel0city_map::EntityKind;
+
+const N_MAPS: usize = 20;
+const N_RAYS: usize = 200;
+
+/// How far inside a brush a point has to be before it counts as penetrating.
+/// Grazing an edge can leave the end of a trace a hair inside the padded planes.
+const PENETRATION_TOLERANCE: f32 = 1.0 / 16.0;
+
+fn random_pnt(rng: &mut Rng, lo: f32, hi: f32) -> na::Pnt3<f32> {
+    na::Pnt3::new(rng.range(lo, hi), rng.range(lo, hi), rng.range(lo, hi))
+}
+
+fn random_vec(rng: &mut Rng, lo: f32, hi: f32) -> na::Vec3<f32> {
+    na::Vec3::new(rng.range(lo, hi), rng.range(lo, hi), rng.range(lo, hi))
+}
+
+fn random_box(rng: &mut Rng) -> (na::Pnt3<f32>, na::Pnt3<f32>) {
+    let mins = random_pnt(rng, -512.0, 512.0);
+    let size = random_vec(rng, 16.0, 256.0);
+    (mins, (mins.to_vec() + size).to_pnt())
+}
+
+/// Half of a random box, cut along a random plane through its middle.
+fn random_wedge(rng: &mut Rng) -> Brush {
+    let (mins, maxs) = random_box(rng);
+    let center = (mins.to_vec() + maxs.to_vec()) * 0.5;
+    let norm = na::normalize(&random_vec(rng, -1.0, 1.0));
+    brush_from_planes(&[
+        Plane { norm: na::Vec3::new(1.0, 0.0, 0.0), dist: maxs.x },
+        Plane { norm: na::Vec3::new(-1.0, 0.0, 0.0), dist: -mins.x },
+        Plane { norm: na::Vec3::new(0.0, 1.0, 0.0), dist: maxs.y },
+        Plane { norm: na::Vec3::new(0.0, -1.0, 0.0), dist: -mins.y },
+        Plane { norm: na::Vec3::new(0.0, 0.0, 1.0), dist: maxs.z },
+        Plane { norm: na::Vec3::new(0.0, 0.0, -1.0), dist: -mins.z },
+        Plane { norm: norm, dist: na::dot(&norm, &center) },
+    ], CONTENTS_SOLID)
+}
+
+fn random_map(rng: &mut Rng) -> Map {
+    let mut builder = MapBuilder::new();
+    for _ in 0..8 {
+        let (mins, maxs) = random_box(rng);
+        builder.add_box(mins, maxs);
+    }
+    for _ in 0..4 {
+        let wedge = random_wedge(rng);
+        builder.add_brush(wedge);
+    }
+    let (mins, maxs) = random_box(rng);
+    builder.add_entity(EntityKind::Goal, vec![box_brush(mins, maxs, CONTENTS_SOLID)]);
+    builder.build()
+}
+
+fn random_shape(rng: &mut Rng) -> Shape {
+    match rng.next_u64() % 4 {
+        0 => Shape::Box(na::zero()),
+        1 => Shape::Box(random_vec(rng, 1.0, 32.0)),
+        2 => Shape::Sphere(rng.range(1.0, 32.0)),
+        _ => Shape::Capsule { radius: rng.range(1.0, 16.0), halfheight: rng.range(1.0, 32.0) },
+    }
+}
+
+/// How far `point` is inside `brush` once the brush is grown to fit `shape`.
+/// Negative means outside.
+fn depth(brush: &Brush, shape: &Shape, point: &na::Pnt3<f32>) -> f32 {
+    brush.sides.iter()
+        .map(|side| shape.plane_offset(&side.plane.norm) - side.plane.dist_to_point(point))
+        .fold(std::f32::INFINITY, |acc, d| if d < acc { d } else { acc })
+}
+
+/// Casts against every brush in the map, with no tree or broadphase involved.
+fn brute_force(map: &Map, ray: &Ray) -> Option<CastResult> {
+    map.bsp.brushes.iter().fold(None, |best, brush| cast::combine_results(best, brush.cast_ray(ray, (0.0, 1.0))))
+}
+
+/// Runs `check` on a lot of rays that start outside of everything, over a lot of maps.
+fn for_each_ray<F: FnMut(&Map, &Ray)>(seed: u64, mut check: F) {
+    let mut rng = Rng::new(seed);
+    for _ in 0..N_MAPS {
+        let map = random_map(&mut rng);
+        let mut n_rays = 0;
+        while n_rays < N_RAYS {
+            let ray = Ray {
+                orig: random_pnt(&mut rng, -600.0, 600.0),
+                dir: random_vec(&mut rng, -800.0, 800.0),
+                shape: random_shape(&mut rng),
+            };
+            if map.bsp.brushes.iter().any(|brush| depth(brush, &ray.shape, &ray.orig) >= 0.0) {
+                continue;
+            }
+            check(&map, &ray);
+            n_rays += 1;
+        }
+    }
+}
+
+#[test]
+fn toi_in_range() {
+    for_each_ray(0x853c49e6748fea9b, |map, ray| {
+        if let Some(result) = map.cast_ray(ray) {
+            assert!(result.toi >= 0.0 && result.toi <= 1.0, "toi {} out of range", result.toi);
+        }
+    });
+}
+
+#[test]
+fn hits_never_penetrate() {
+    for_each_ray(0xda3e39cb94b95bdb, |map, ray| {
+        let toi = map.cast_ray(ray).map_or(1.0, |result| result.toi);
+        let stop = (ray.orig.to_vec() + ray.dir * toi).to_pnt();
+        for (brushidx, brush) in map.bsp.brushes.iter().enumerate() {
+            let d = depth(brush, &ray.shape, &stop);
+            assert!(d < PENETRATION_TOLERANCE,
+                    "Ray from {:?} along {:?} stopped at {} ({:?}), {} inside brush {}",
+                    ray.orig, ray.dir, toi, stop, d, brushidx);
+        }
+    });
+}
+
+#[test]
+fn tree_matches_brute_force() {
+    for_each_ray(0x2545f4914f6cdd1d, |map, ray| {
+        let tree = map.cast_ray(ray);
+        let brute = brute_force(map, ray);
+        match (tree, brute) {
+            (None, None) => (),
+            (Some(a), Some(b)) => assert!(na::approx_eq(&a.toi, &b.toi),
+                                          "Ray from {:?} along {:?}: tree says {}, brute force says {}",
+                                          ray.orig, ray.dir, a.toi, b.toi),
+            (a, b) => panic!("Ray from {:?} along {:?}: tree says {:?}, brute force says {:?}",
+                             ray.orig, ray.dir, a, b),
+        }
+    });
+}
+
+
+#[test]
+fn parallel_matches_serial() {
+    let mut rng = Rng::new(0x9fb21c651e98df25);
+    for _ in 0..4 {
+        let map = random_map(&mut rng);
+        // An odd count, so the chunks don't split evenly between the threads.
+        let rays: Vec<Ray> = (0..103).map(|_| Ray {
+            orig: random_pnt(&mut rng, -600.0, 600.0),
+            dir: random_vec(&mut rng, -800.0, 800.0),
+            shape: random_shape(&mut rng),
+        }).collect();
+        let serial = map.cast_rays(&rays);
+        for &n_threads in &[0, 1, 2, 3, 4, 7, 200] {
+            assert_eq!(map.cast_rays_parallel(&rays, n_threads), serial, "{} threads", n_threads);
+        }
+        assert_eq!(map.cast_rays_parallel(&rays[..5], 8), map.cast_rays(&rays[..5]));
+        assert_eq!(map.cast_rays_parallel(&[], 4), vec![]);
+    }
+}